@@ -0,0 +1,60 @@
+//! Compares the throughput of the codecs for file events
+//!
+//! Run with `cargo bench -p common`.
+
+use std::{
+    hint::black_box,
+    time::{Duration, Instant},
+};
+
+use common::{encode_file, Codec, MessageId, RoomEvent, RoomName, ServerEvent, Username};
+
+const ITERATIONS: u32 = 200;
+
+fn main() {
+    let room_name = RoomName::lobby();
+    let username = Username::from("alice");
+    let contents = encode_file(&vec![0x5a; 1024 * 1024]);
+    let events = [
+        (
+            "1 MiB file",
+            RoomEvent::file("file.bin", &contents, contents.len()),
+        ),
+        (
+            "64 KiB chunk",
+            RoomEvent::file_chunk(1, 0, 16, &contents[..65536]),
+        ),
+        (
+            "message",
+            RoomEvent::message(MessageId::new(1), "hello there", Vec::new()),
+        ),
+    ];
+    for (name, event) in events {
+        let event = ServerEvent::room_event(&room_name, &username, event);
+        for codec in [Codec::Json, Codec::MessagePack] {
+            let encoded = codec.encode(&event);
+            let encoding = measure(|| codec.encode(black_box(&event)));
+            let decoding = measure(|| codec.decode(black_box(&encoded)).unwrap());
+            println!(
+                "{name:>12} {codec:>11}: {size:>8} bytes, encode {encode:>9.1} MiB/s, decode {decode:>9.1} MiB/s",
+                codec = format!("{codec:?}"),
+                size = encoded.len(),
+                encode = throughput(encoded.len(), encoding),
+                decode = throughput(encoded.len(), decoding),
+            );
+        }
+    }
+}
+
+/// Returns the average time that the given function takes
+fn measure<T>(mut f: impl FnMut() -> T) -> Duration {
+    let start = Instant::now();
+    for _ in 0..ITERATIONS {
+        black_box(f());
+    }
+    start.elapsed() / ITERATIONS
+}
+
+fn throughput(bytes: usize, elapsed: Duration) -> f64 {
+    bytes as f64 / (1024.0 * 1024.0) / elapsed.as_secs_f64()
+}
@@ -0,0 +1,108 @@
+use std::collections::BTreeMap;
+
+use crate::{decode_file, FileDecodeError};
+
+/// Reassembles a file from the chunks of a transfer (see
+/// [`RoomEvent::FileChunk`](crate::RoomEvent::FileChunk))
+///
+/// The chunks can arrive in any order and duplicated chunks are ignored, so a retransmitted chunk
+/// is never appended twice.
+#[derive(Debug, Clone, Default)]
+pub struct FileAssembler {
+    /// The number of chunks, known from the first chunk
+    total: Option<u32>,
+    /// The decoded chunks by their index
+    chunks: BTreeMap<u32, Vec<u8>>,
+}
+
+impl FileAssembler {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Adds an encoded chunk, returning whether it was new
+    pub fn add(&mut self, index: u32, total: u32, contents: &str) -> Result<bool, FileDecodeError> {
+        if index >= total || *self.total.get_or_insert(total) != total {
+            return Err(FileDecodeError::InvalidChunk { index, total });
+        }
+        if self.chunks.contains_key(&index) {
+            return Ok(false);
+        }
+        self.chunks.insert(index, decode_file(contents)?);
+        Ok(true)
+    }
+
+    /// Returns the fraction of the chunks that have arrived, from 0 to 1
+    pub fn progress(&self) -> f64 {
+        match self.total {
+            Some(total) => self.chunks.len() as f64 / f64::from(total),
+            None => 0.0,
+        }
+    }
+
+    /// Returns whether every chunk has arrived
+    pub fn is_complete(&self) -> bool {
+        self.total
+            .is_some_and(|total| self.chunks.len() == total as usize)
+    }
+
+    /// Returns the contents of the file if every chunk has arrived
+    pub fn finish(self) -> Option<Vec<u8>> {
+        self.is_complete()
+            .then(|| self.chunks.into_values().flatten().collect())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::encode_file;
+
+    #[test]
+    fn duplicated_and_reordered_chunks_reassemble() {
+        let chunks = [&b"first "[..], b"second ", b"third"].map(encode_file);
+        let mut assembler = FileAssembler::new();
+        assert_eq!(assembler.add(2, 3, &chunks[2]), Ok(true));
+        assert_eq!(assembler.add(0, 3, &chunks[0]), Ok(true));
+        assert_eq!(assembler.add(0, 3, &chunks[0]), Ok(false));
+        assert!(!assembler.is_complete());
+        assert_eq!(assembler.add(1, 3, &chunks[1]), Ok(true));
+        assert_eq!(assembler.add(2, 3, &chunks[2]), Ok(false));
+        assert_eq!(assembler.finish().unwrap(), b"first second third");
+    }
+
+    #[test]
+    fn progress_counts_the_chunks() {
+        let mut assembler = FileAssembler::new();
+        assert_eq!(assembler.progress(), 0.0);
+        assembler.add(3, 4, &encode_file(b"last")).unwrap();
+        assert_eq!(assembler.progress(), 0.25);
+        assembler.add(3, 4, &encode_file(b"last")).unwrap();
+        assert_eq!(assembler.progress(), 0.25);
+        for index in 0..3 {
+            assembler.add(index, 4, &encode_file(b"data")).unwrap();
+        }
+        assert_eq!(assembler.progress(), 1.0);
+    }
+
+    #[test]
+    fn incomplete_files_are_not_finished() {
+        let mut assembler = FileAssembler::new();
+        assembler.add(1, 2, &encode_file(b"end")).unwrap();
+        assert_eq!(assembler.finish(), None);
+    }
+
+    #[test]
+    fn chunks_must_fit_the_count() {
+        let mut assembler = FileAssembler::new();
+        assert_eq!(
+            assembler.add(2, 2, &encode_file(b"data")),
+            Err(FileDecodeError::InvalidChunk { index: 2, total: 2 })
+        );
+        assembler.add(0, 2, &encode_file(b"data")).unwrap();
+        assert_eq!(
+            assembler.add(1, 3, &encode_file(b"data")),
+            Err(FileDecodeError::InvalidChunk { index: 1, total: 3 })
+        );
+    }
+}
@@ -0,0 +1,113 @@
+use serde::{Deserialize, Serialize};
+
+/// The version of the protocol, bumped on incompatible changes
+pub const PROTOCOL_VERSION: u32 = 1;
+
+/// Optional protocol features that a server supports
+///
+/// Clients can announce the features that they support with `/hello`, after which the server
+/// only uses the features that both sides support.
+#[derive(Debug, Clone, Copy, Serialize, Deserialize, PartialEq, Eq, Hash, Default)]
+pub struct Capabilities {
+    /// Events can be encoded in a binary format instead of JSON
+    pub binary_codec: bool,
+    /// Events can be compressed
+    pub compression: bool,
+    /// Connections can be encrypted with TLS
+    pub tls: bool,
+    /// Files can be sent in multiple chunks
+    pub file_chunking: bool,
+    /// Messages can be reacted to with emojis
+    #[serde(default)]
+    pub reactions: bool,
+}
+
+impl Capabilities {
+    pub const BINARY_CODEC: &'static str = "binary_codec";
+    pub const COMPRESSION: &'static str = "compression";
+    pub const TLS: &'static str = "tls";
+    pub const FILE_CHUNKING: &'static str = "file_chunking";
+    pub const REACTIONS: &'static str = "reactions";
+
+    /// Creates the capabilities with the given names, ignoring the unknown ones
+    pub fn from_names<'a>(names: impl IntoIterator<Item = &'a str>) -> Self {
+        let mut capabilities = Self::default();
+        for name in names {
+            match name {
+                Self::BINARY_CODEC => capabilities.binary_codec = true,
+                Self::COMPRESSION => capabilities.compression = true,
+                Self::TLS => capabilities.tls = true,
+                Self::FILE_CHUNKING => capabilities.file_chunking = true,
+                Self::REACTIONS => capabilities.reactions = true,
+                _ => {}
+            }
+        }
+        capabilities
+    }
+
+    /// Returns the names of the supported features
+    pub fn names(&self) -> Vec<&'static str> {
+        [
+            (self.binary_codec, Self::BINARY_CODEC),
+            (self.compression, Self::COMPRESSION),
+            (self.tls, Self::TLS),
+            (self.file_chunking, Self::FILE_CHUNKING),
+            (self.reactions, Self::REACTIONS),
+        ]
+        .into_iter()
+        .filter_map(|(supported, name)| supported.then_some(name))
+        .collect()
+    }
+
+    /// Returns the features that are supported by both sides
+    pub fn intersection(&self, other: &Self) -> Self {
+        Self {
+            binary_codec: self.binary_codec && other.binary_codec,
+            compression: self.compression && other.compression,
+            tls: self.tls && other.tls,
+            file_chunking: self.file_chunking && other.file_chunking,
+            reactions: self.reactions && other.reactions,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn names_round_trip() {
+        let capabilities = Capabilities {
+            binary_codec: true,
+            reactions: true,
+            ..Capabilities::default()
+        };
+        assert_eq!(capabilities.names(), ["binary_codec", "reactions"]);
+        assert_eq!(Capabilities::from_names(capabilities.names()), capabilities);
+        assert_eq!(
+            Capabilities::from_names(["reactions", "telepathy"]),
+            Capabilities {
+                reactions: true,
+                ..Capabilities::default()
+            }
+        );
+    }
+
+    #[test]
+    fn only_shared_features_are_used() {
+        let server = Capabilities::from_names(["binary_codec", "file_chunking", "reactions"]);
+        let client = Capabilities::from_names(["file_chunking", "tls", "reactions"]);
+        assert_eq!(
+            server.intersection(&client).names(),
+            ["file_chunking", "reactions"]
+        );
+    }
+
+    #[test]
+    fn older_servers_have_no_reactions() {
+        let json = r#"{"binary_codec":true,"compression":false,"tls":false,"file_chunking":true}"#;
+        let capabilities: Capabilities = serde_json::from_str(json).unwrap();
+        assert!(!capabilities.reactions);
+        assert!(capabilities.file_chunking);
+    }
+}
@@ -0,0 +1,113 @@
+use strum_macros::Display;
+
+use crate::{Capabilities, ServerEvent};
+
+/// The format that the server events are encoded in
+///
+/// Events are JSON until the `binary_codec` capability is negotiated with `/hello`, after which
+/// every event that follows the [`Capabilities`](ServerEvent::Capabilities) reply is MessagePack.
+/// Over TCP, JSON events are terminated by a newline and MessagePack events are prefixed with
+/// their length as a 32-bit big-endian integer. Over WebSocket, they are sent as text and binary
+/// messages respectively. Commands are always sent as lines of text.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Default)]
+pub enum Codec {
+    #[default]
+    Json,
+    MessagePack,
+}
+
+impl Codec {
+    /// Returns the codec that is used with the given negotiated capabilities
+    pub fn negotiated(capabilities: &Capabilities) -> Self {
+        if capabilities.binary_codec {
+            Self::MessagePack
+        } else {
+            Self::Json
+        }
+    }
+
+    /// Returns whether the encoded events are text
+    pub fn is_text(&self) -> bool {
+        *self == Self::Json
+    }
+
+    /// Encodes the given event, without any framing
+    pub fn encode(&self, event: &ServerEvent) -> Vec<u8> {
+        match self {
+            Self::Json => serde_json::to_vec(event).unwrap(),
+            Self::MessagePack => rmp_serde::to_vec_named(event).unwrap(),
+        }
+    }
+
+    /// Decodes an event that was encoded with [`encode`](Self::encode)
+    pub fn decode(&self, bytes: &[u8]) -> Result<ServerEvent, CodecError> {
+        match self {
+            Self::Json => serde_json::from_slice(bytes).map_err(CodecError::Json),
+            Self::MessagePack => rmp_serde::from_slice(bytes).map_err(CodecError::MessagePack),
+        }
+    }
+}
+
+/// An error that occurred while decoding a [`ServerEvent`]
+#[derive(Debug, Display)]
+pub enum CodecError {
+    #[strum(to_string = "Invalid JSON event: {0}")]
+    Json(serde_json::Error),
+    #[strum(to_string = "Invalid MessagePack event: {0}")]
+    MessagePack(rmp_serde::decode::Error),
+}
+
+impl std::error::Error for CodecError {}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{RoomEvent, Username};
+
+    fn event() -> ServerEvent {
+        ServerEvent::room_event(
+            &"den".into(),
+            &Username::from("alice"),
+            RoomEvent::Message {
+                id: 1.into(),
+                text: "hello @bob".to_string(),
+                mentions: vec!["bob".into()],
+            },
+        )
+    }
+
+    #[test]
+    fn events_round_trip() {
+        let event = event();
+        let json = event.as_json_str();
+        for codec in [Codec::Json, Codec::MessagePack] {
+            let decoded = codec.decode(&codec.encode(&event)).unwrap();
+            assert_eq!(decoded.as_json_str(), json, "{codec:?}");
+        }
+    }
+
+    #[test]
+    fn binary_codec_is_negotiated() {
+        let capabilities = Capabilities {
+            binary_codec: true,
+            ..Capabilities::default()
+        };
+        assert_eq!(Codec::negotiated(&capabilities), Codec::MessagePack);
+        assert!(!Codec::negotiated(&capabilities).is_text());
+        assert_eq!(Codec::negotiated(&Capabilities::default()), Codec::Json);
+    }
+
+    #[test]
+    fn codecs_are_not_mixed() {
+        let json = Codec::Json.encode(&event());
+        assert!(matches!(
+            Codec::MessagePack.decode(&json),
+            Err(CodecError::MessagePack(_))
+        ));
+        let message_pack = Codec::MessagePack.encode(&event());
+        assert!(matches!(
+            Codec::Json.decode(&message_pack),
+            Err(CodecError::Json(_))
+        ));
+    }
+}
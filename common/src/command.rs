@@ -1,63 +1,985 @@
 use std::fmt;
 
-use crate::{RoomName, Username};
+use strum_macros::Display;
 
-#[derive(Debug)]
+use crate::{Capabilities, MessageId, RoomName, UserStatus, Username};
+
+#[derive(Debug, Clone, PartialEq, Eq)]
 pub enum Command {
+    /// Announces the protocol version and the capabilities of the client
+    Hello(u32, Capabilities),
     Help,
     ChangeUsername(Username),
+    NameHistory,
     ListRooms,
-    Join(RoomName),
+    ListMemberships,
+    Join(RoomName, Option<String>),
+    Leave(RoomName),
+    RenameRoom(RoomName),
+    ClearRoom,
+    Op(Username),
+    Kick(Username),
+    Ban(Username),
+    Unban(Username),
+    Mute(Username),
+    Unmute(Username),
     ListUsers,
+    CountUsers,
     SendFile(String, String),
+    Code(String, String),
+    /// Starts sending a file in chunks: the transfer id, the size in bytes and the file name
+    FileStart(u64, usize, String),
+    /// A chunk of a file: the transfer id, the index of the chunk, the number of chunks and the
+    /// contents that are encoded with [`encode_file`](crate::encode_file)
+    FileChunk(u64, u32, u32, String),
+    FileEnd(u64),
+    DirectMessage(Username, String),
+    /// Marks a direct message as read, which lets its sender know
+    MarkRead(MessageId),
     Nudge(Username),
-    Quit,
+    Oper(String),
+    SystemMessage(RoomName, String),
+    Drain,
+    Undo,
+    Edit(MessageId, String),
+    React(MessageId, String),
+    InitiativeAdd(String, i32),
+    InitiativeNext,
+    InitiativeClear,
+    Ping(Option<String>),
+    Pong(u64),
+    Away(Option<String>),
+    Status(UserStatus, Option<String>),
+    Summary,
+    Search(String),
+    Translate(String, String),
+    Quit(Option<String>),
+}
+
+impl Command {
+    pub const HELLO: &'static str = "hello";
+    pub const HELP: &'static str = "help";
+    pub const NAME: &'static str = "name";
+    pub const NICK_HISTORY: &'static str = "nick-history";
+    pub const ROOMS: &'static str = "rooms";
+    pub const MY_ROOMS: &'static str = "myrooms";
+    pub const JOIN: &'static str = "join";
+    pub const LEAVE: &'static str = "leave";
+    pub const RENAME_ROOM: &'static str = "renameroom";
+    pub const CLEAR_ROOM: &'static str = "clearroom";
+    pub const OP: &'static str = "op";
+    pub const KICK: &'static str = "kick";
+    pub const BAN: &'static str = "ban";
+    pub const UNBAN: &'static str = "unban";
+    pub const MUTE: &'static str = "mute";
+    pub const UNMUTE: &'static str = "unmute";
+    pub const USERS: &'static str = "users";
+    pub const FILE: &'static str = "file";
+    pub const CODE: &'static str = "code";
+    pub const FILE_START: &'static str = "filestart";
+    pub const FILE_CHUNK: &'static str = "filechunk";
+    pub const FILE_END: &'static str = "fileend";
+    pub const MSG: &'static str = "msg";
+    pub const READ: &'static str = "read";
+    pub const NUDGE: &'static str = "nudge";
+    pub const OPER: &'static str = "oper";
+    pub const SYSMSG: &'static str = "sysmsg";
+    pub const DRAIN: &'static str = "drain";
+    pub const UNDO: &'static str = "undo";
+    pub const EDIT: &'static str = "edit";
+    pub const REACT: &'static str = "react";
+    pub const INITIATIVE: &'static str = "initiative";
+    pub const PING: &'static str = "ping";
+    pub const PONG: &'static str = "pong";
+    pub const AWAY: &'static str = "away";
+    pub const STATUS: &'static str = "status";
+    pub const SUMMARY: &'static str = "summary";
+    pub const SEARCH: &'static str = "search";
+    pub const TRANSLATE: &'static str = "translate";
+    pub const QUIT: &'static str = "quit";
+
+    /// The names and arguments of the commands that are listed in `/help`, in order
+    ///
+    /// `/hello`, `/pong`, `/read`, `/code` and the `/file` commands are sent by clients rather than
+    /// typed, and the operator commands are not listed.
+    pub const USAGE: &'static [(&'static str, &'static str)] = &[
+        (Self::HELP, ""),
+        (Self::NAME, "{name}"),
+        (Self::NICK_HISTORY, ""),
+        (Self::ROOMS, ""),
+        (Self::MY_ROOMS, ""),
+        (Self::JOIN, "{room} [password]"),
+        (Self::LEAVE, "{room}"),
+        (Self::RENAME_ROOM, "{room}"),
+        (Self::CLEAR_ROOM, ""),
+        (Self::OP, "{name}"),
+        (Self::KICK, "{name}"),
+        (Self::BAN, "{name}"),
+        (Self::UNBAN, "{name}"),
+        (Self::MUTE, "{name}"),
+        (Self::UNMUTE, "{name}"),
+        (Self::USERS, "[count]"),
+        (Self::MSG, "{name} {message}"),
+        (Self::NUDGE, "{name}"),
+        (Self::UNDO, ""),
+        (Self::EDIT, "{id} {message}"),
+        (Self::REACT, "{id} {emoji}"),
+        (Self::INITIATIVE, "{add|next|clear} [name] [initiative]"),
+        (Self::PING, "[token]"),
+        (Self::AWAY, "[message]"),
+        (Self::STATUS, "{online|away|busy} [message]"),
+        (Self::SUMMARY, ""),
+        (Self::SEARCH, "{query}"),
+        (Self::TRANSLATE, "{language} {message}"),
+        (Self::QUIT, "[message]"),
+    ];
+
+    /// Returns the name of the command without the leading slash
+    pub fn name(&self) -> &'static str {
+        match self {
+            Command::Hello(_, _) => Self::HELLO,
+            Command::Help => Self::HELP,
+            Command::ChangeUsername(_) => Self::NAME,
+            Command::NameHistory => Self::NICK_HISTORY,
+            Command::ListRooms => Self::ROOMS,
+            Command::ListMemberships => Self::MY_ROOMS,
+            Command::Join(_, _) => Self::JOIN,
+            Command::Leave(_) => Self::LEAVE,
+            Command::RenameRoom(_) => Self::RENAME_ROOM,
+            Command::ClearRoom => Self::CLEAR_ROOM,
+            Command::Op(_) => Self::OP,
+            Command::Kick(_) => Self::KICK,
+            Command::Ban(_) => Self::BAN,
+            Command::Unban(_) => Self::UNBAN,
+            Command::Mute(_) => Self::MUTE,
+            Command::Unmute(_) => Self::UNMUTE,
+            Command::ListUsers | Command::CountUsers => Self::USERS,
+            Command::SendFile(_, _) => Self::FILE,
+            Command::Code(_, _) => Self::CODE,
+            Command::FileStart(_, _, _) => Self::FILE_START,
+            Command::FileChunk(_, _, _, _) => Self::FILE_CHUNK,
+            Command::FileEnd(_) => Self::FILE_END,
+            Command::DirectMessage(_, _) => Self::MSG,
+            Command::MarkRead(_) => Self::READ,
+            Command::Nudge(_) => Self::NUDGE,
+            Command::Oper(_) => Self::OPER,
+            Command::SystemMessage(_, _) => Self::SYSMSG,
+            Command::Drain => Self::DRAIN,
+            Command::Undo => Self::UNDO,
+            Command::Edit(_, _) => Self::EDIT,
+            Command::React(_, _) => Self::REACT,
+            Command::InitiativeAdd(_, _) | Command::InitiativeNext | Command::InitiativeClear => {
+                Self::INITIATIVE
+            }
+            Command::Ping(_) => Self::PING,
+            Command::Pong(_) => Self::PONG,
+            Command::Away(_) => Self::AWAY,
+            Command::Status(_, _) => Self::STATUS,
+            Command::Summary => Self::SUMMARY,
+            Command::Search(_) => Self::SEARCH,
+            Command::Translate(_, _) => Self::TRANSLATE,
+            Command::Quit(_) => Self::QUIT,
+        }
+    }
 }
 
 impl fmt::Display for Command {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "/{}", self.name())?;
         match self {
-            Command::Help => write!(f, "/help"),
-            Command::ChangeUsername(name) => write!(f, "/name {}", name),
-            Command::ListRooms => write!(f, "/rooms"),
-            Command::Join(room) => write!(f, "/join {}", room),
-            Command::ListUsers => write!(f, "/users"),
-            Command::SendFile(filename, encoded) => {
-                write!(f, "/file {} {}", filename, encoded)
+            Command::Hello(version, capabilities) => {
+                write!(f, " {}", version)?;
+                capabilities
+                    .names()
+                    .into_iter()
+                    .try_for_each(|name| write!(f, " {}", name))
+            }
+            Command::ChangeUsername(name) => write!(f, " {}", name),
+            Command::Join(room, None) | Command::Leave(room) | Command::RenameRoom(room) => {
+                write!(f, " {}", room)
+            }
+            Command::Join(room, Some(password)) => write!(f, " {} {}", room, password),
+            Command::CountUsers => write!(f, " count"),
+            Command::SendFile(filename, encoded) => write!(f, " {} {}", filename, encoded),
+            Command::Code(language, encoded) => write!(f, " {} {}", language, encoded),
+            Command::FileStart(id, size, filename) => write!(f, " {} {} {}", id, size, filename),
+            Command::FileChunk(id, index, total, encoded) => {
+                write!(f, " {} {} {} {}", id, index, total, encoded)
             }
-            Command::Nudge(username) => write!(f, "/nudge {}", username),
-            Command::Quit => write!(f, "/quit"),
+            Command::FileEnd(id) | Command::Pong(id) => write!(f, " {}", id),
+            Command::DirectMessage(username, text) => write!(f, " {} {}", username, text),
+            Command::MarkRead(id) => write!(f, " {}", id),
+            Command::Nudge(username)
+            | Command::Op(username)
+            | Command::Kick(username)
+            | Command::Ban(username)
+            | Command::Unban(username)
+            | Command::Mute(username)
+            | Command::Unmute(username) => write!(f, " {}", username),
+            Command::Oper(password) => write!(f, " {}", password),
+            Command::SystemMessage(room, message) => write!(f, " {} {}", room, message),
+            Command::Edit(id, text) => write!(f, " {} {}", id, text),
+            Command::React(id, emoji) => write!(f, " {} {}", id, emoji),
+            Command::InitiativeAdd(name, value) => write!(f, " add {} {}", name, value),
+            Command::InitiativeNext => write!(f, " next"),
+            Command::InitiativeClear => write!(f, " clear"),
+            Command::Ping(Some(text)) | Command::Away(Some(text)) | Command::Quit(Some(text)) => {
+                write!(f, " {}", text)
+            }
+            Command::Status(status, None) => write!(f, " {}", status),
+            Command::Status(status, Some(text)) => write!(f, " {} {}", status, text),
+            Command::Translate(language, text) => write!(f, " {} {}", language, text),
+            Command::Search(query) => write!(f, " {}", query),
+            Command::Help
+            | Command::NameHistory
+            | Command::ListRooms
+            | Command::ListMemberships
+            | Command::ClearRoom
+            | Command::ListUsers
+            | Command::Drain
+            | Command::Undo
+            | Command::Ping(None)
+            | Command::Away(None)
+            | Command::Summary
+            | Command::Quit(None) => Ok(()),
         }
     }
 }
 
+/// Parses a command from a line of user input
+///
+/// Parsing the [`Display`](fmt::Display) output of a command yields the same command, with the
+/// following inputs being intentionally rejected or altered:
+///
+/// - Names, room names, passwords, tokens and emojis end at the first whitespace, the rest is
+///   ignored.
+/// - File names may contain spaces but the file content (base64) may not.
+/// - The source of `/code` is encoded with [`encode_file`](crate::encode_file) so that
+///   it can span multiple lines.
+/// - Arguments are separated by any amount of whitespace.
+/// - Messages of `/msg`, `/sysmsg`, `/edit` and `/translate` are taken verbatim (apart from the
+///   whitespace before them) but can't be empty.
+/// - The query of `/search` is taken verbatim (apart from surrounding whitespace) but can't be
+///   empty.
+/// - An empty `/away`, `/status` or `/quit` message is the same as no message.
+/// - Unknown capabilities of `/hello` are ignored.
 impl TryFrom<String> for Command {
-    type Error = String;
+    type Error = CommandParseError;
     fn try_from(value: String) -> Result<Self, Self::Error> {
+        use CommandParseError::{InvalidArg, MissingArg, UnknownCommand};
         let mut parts = value.split_whitespace();
-        match parts.next() {
-            Some("/help") => Ok(Command::Help),
-            Some("/name") => {
-                let name = parts.next().ok_or("Name is required")?.into();
-                Ok(Command::ChangeUsername(name))
-            }
-            Some("/rooms") => Ok(Command::ListRooms),
-            Some("/join" | "/j") => {
-                let room = parts.next().ok_or("Room name is required")?.into();
-                Ok(Command::Join(room))
-            }
-            Some("/users") => Ok(Command::ListUsers),
-            Some("/file") => {
-                let filename = parts.next().ok_or("File name is required")?.to_string();
-                let encoded = parts.next().ok_or("File content is required")?.to_string();
-                Ok(Command::SendFile(filename, encoded))
-            }
-            Some("/nudge") => {
-                let username = parts.next().ok_or("Username is required")?.into();
-                Ok(Command::Nudge(username))
-            }
-            Some("/quit") => Ok(Command::Quit),
-            _ => Err(format!("Invalid command: {}", value)),
+        // the arguments after the command name, for commands that take free-form text
+        let rest = value
+            .trim()
+            .split_once(char::is_whitespace)
+            .map(|(_, rest)| rest.trim_start())
+            .unwrap_or_default();
+        let missing = |command, arg_name, position| MissingArg {
+            command,
+            arg_name,
+            position,
+        };
+        let invalid = |command, arg_name, position, value: &str| InvalidArg {
+            command,
+            arg_name,
+            position,
+            value: value.to_string(),
+        };
+        // the free-form text after the given number of arguments
+        let after = |count| {
+            (0..count).fold(rest, |text, _| {
+                text.split_once(char::is_whitespace)
+                    .map(|(_, text)| text.trim_start())
+                    .unwrap_or_default()
+            })
+        };
+        // the free-form text at the given position, which can't be empty
+        let text = |command, position| {
+            Some(after(position))
+                .filter(|text| !text.is_empty())
+                .ok_or_else(|| missing(command, "message", position))
+        };
+        match parts.next().and_then(|part| part.strip_prefix('/')) {
+            Some(Command::HELLO) => {
+                let version = parts
+                    .next()
+                    .ok_or_else(|| missing(Command::HELLO, "protocol version", 0))?;
+                let version = version
+                    .parse()
+                    .map_err(|_| invalid(Command::HELLO, "protocol version", 0, version))?;
+                Ok(Command::Hello(version, Capabilities::from_names(parts)))
+            }
+            Some(Command::HELP) => Ok(Command::Help),
+            Some(Command::NAME) => {
+                let name = parts
+                    .next()
+                    .ok_or_else(|| missing(Command::NAME, "name", 0))?;
+                Ok(Command::ChangeUsername(name.into()))
+            }
+            Some(Command::NICK_HISTORY) => Ok(Command::NameHistory),
+            Some(Command::ROOMS) => Ok(Command::ListRooms),
+            Some(Command::MY_ROOMS) => Ok(Command::ListMemberships),
+            Some(Command::JOIN | "j") => {
+                let room = parts
+                    .next()
+                    .ok_or_else(|| missing(Command::JOIN, "room name", 0))?;
+                let password = parts.next().map(String::from);
+                Ok(Command::Join(room.into(), password))
+            }
+            Some(Command::LEAVE) => {
+                let room = parts
+                    .next()
+                    .ok_or_else(|| missing(Command::LEAVE, "room name", 0))?;
+                Ok(Command::Leave(room.into()))
+            }
+            Some(Command::RENAME_ROOM) => {
+                let room = parts
+                    .next()
+                    .ok_or_else(|| missing(Command::RENAME_ROOM, "room name", 0))?;
+                Ok(Command::RenameRoom(room.into()))
+            }
+            Some(Command::CLEAR_ROOM) => Ok(Command::ClearRoom),
+            Some(Command::OP) => {
+                let username = parts
+                    .next()
+                    .ok_or_else(|| missing(Command::OP, "username", 0))?;
+                Ok(Command::Op(username.into()))
+            }
+            Some(Command::KICK) => {
+                let username = parts
+                    .next()
+                    .ok_or_else(|| missing(Command::KICK, "username", 0))?;
+                Ok(Command::Kick(username.into()))
+            }
+            Some(Command::BAN) => {
+                let username = parts
+                    .next()
+                    .ok_or_else(|| missing(Command::BAN, "username", 0))?;
+                Ok(Command::Ban(username.into()))
+            }
+            Some(Command::UNBAN) => {
+                let username = parts
+                    .next()
+                    .ok_or_else(|| missing(Command::UNBAN, "username", 0))?;
+                Ok(Command::Unban(username.into()))
+            }
+            Some(Command::MUTE) => {
+                let username = parts
+                    .next()
+                    .ok_or_else(|| missing(Command::MUTE, "username", 0))?;
+                Ok(Command::Mute(username.into()))
+            }
+            Some(Command::UNMUTE) => {
+                let username = parts
+                    .next()
+                    .ok_or_else(|| missing(Command::UNMUTE, "username", 0))?;
+                Ok(Command::Unmute(username.into()))
+            }
+            Some(Command::USERS) => match parts.next() {
+                Some("count") => Ok(Command::CountUsers),
+                _ => Ok(Command::ListUsers),
+            },
+            Some(Command::FILE) => {
+                if rest.is_empty() {
+                    return Err(missing(Command::FILE, "file name", 0));
+                }
+                let (filename, encoded) = rest
+                    .rsplit_once(char::is_whitespace)
+                    .ok_or_else(|| missing(Command::FILE, "file content", 1))?;
+                Ok(Command::SendFile(
+                    filename.trim_end().to_string(),
+                    encoded.to_string(),
+                ))
+            }
+            Some(Command::CODE) => {
+                let language = parts
+                    .next()
+                    .ok_or_else(|| missing(Command::CODE, "language", 0))?;
+                let encoded = parts
+                    .next()
+                    .ok_or_else(|| missing(Command::CODE, "source", 1))?;
+                Ok(Command::Code(language.to_string(), encoded.to_string()))
+            }
+            Some(Command::FILE_START) => {
+                let id = parts
+                    .next()
+                    .ok_or_else(|| missing(Command::FILE_START, "transfer id", 0))?;
+                let id = id
+                    .parse()
+                    .map_err(|_| invalid(Command::FILE_START, "transfer id", 0, id))?;
+                let size = parts
+                    .next()
+                    .ok_or_else(|| missing(Command::FILE_START, "file size", 1))?;
+                let size = size
+                    .parse()
+                    .map_err(|_| invalid(Command::FILE_START, "file size", 1, size))?;
+                let filename = Some(after(2).trim_end())
+                    .filter(|filename| !filename.is_empty())
+                    .ok_or_else(|| missing(Command::FILE_START, "file name", 2))?;
+                Ok(Command::FileStart(id, size, filename.to_string()))
+            }
+            Some(Command::FILE_CHUNK) => {
+                let id = parts
+                    .next()
+                    .ok_or_else(|| missing(Command::FILE_CHUNK, "transfer id", 0))?;
+                let id = id
+                    .parse()
+                    .map_err(|_| invalid(Command::FILE_CHUNK, "transfer id", 0, id))?;
+                let index = parts
+                    .next()
+                    .ok_or_else(|| missing(Command::FILE_CHUNK, "chunk index", 1))?;
+                let index = index
+                    .parse()
+                    .map_err(|_| invalid(Command::FILE_CHUNK, "chunk index", 1, index))?;
+                let total = parts
+                    .next()
+                    .ok_or_else(|| missing(Command::FILE_CHUNK, "chunk count", 2))?;
+                let total = total
+                    .parse()
+                    .map_err(|_| invalid(Command::FILE_CHUNK, "chunk count", 2, total))?;
+                let encoded = parts
+                    .next()
+                    .ok_or_else(|| missing(Command::FILE_CHUNK, "file content", 3))?;
+                Ok(Command::FileChunk(id, index, total, encoded.to_string()))
+            }
+            Some(Command::FILE_END) => {
+                let id = parts
+                    .next()
+                    .ok_or_else(|| missing(Command::FILE_END, "transfer id", 0))?;
+                let id = id
+                    .parse()
+                    .map_err(|_| invalid(Command::FILE_END, "transfer id", 0, id))?;
+                Ok(Command::FileEnd(id))
+            }
+            Some(Command::MSG) => {
+                let username = parts
+                    .next()
+                    .ok_or_else(|| missing(Command::MSG, "username", 0))?;
+                let message = text(Command::MSG, 1)?;
+                Ok(Command::DirectMessage(username.into(), message.to_string()))
+            }
+            Some(Command::READ) => {
+                let id = parts
+                    .next()
+                    .ok_or_else(|| missing(Command::READ, "message id", 0))?;
+                let id = id
+                    .parse()
+                    .map_err(|_| invalid(Command::READ, "message id", 0, id))?;
+                Ok(Command::MarkRead(id))
+            }
+            Some(Command::NUDGE) => {
+                let username = parts
+                    .next()
+                    .ok_or_else(|| missing(Command::NUDGE, "username", 0))?;
+                Ok(Command::Nudge(username.into()))
+            }
+            Some(Command::OPER) => {
+                let password = parts
+                    .next()
+                    .ok_or_else(|| missing(Command::OPER, "password", 0))?;
+                Ok(Command::Oper(password.to_string()))
+            }
+            Some(Command::SYSMSG) => {
+                let room = parts
+                    .next()
+                    .ok_or_else(|| missing(Command::SYSMSG, "room name", 0))?;
+                let message = text(Command::SYSMSG, 1)?;
+                Ok(Command::SystemMessage(room.into(), message.to_string()))
+            }
+            Some(Command::DRAIN) => Ok(Command::Drain),
+            Some(Command::UNDO) => Ok(Command::Undo),
+            Some(Command::EDIT) => {
+                let id = parts
+                    .next()
+                    .ok_or_else(|| missing(Command::EDIT, "message id", 0))?;
+                let id = id
+                    .parse()
+                    .map_err(|_| invalid(Command::EDIT, "message id", 0, id))?;
+                let message = text(Command::EDIT, 1)?;
+                Ok(Command::Edit(id, message.to_string()))
+            }
+            Some(Command::REACT) => {
+                let id = parts
+                    .next()
+                    .ok_or_else(|| missing(Command::REACT, "message id", 0))?;
+                let id = id
+                    .parse()
+                    .map_err(|_| invalid(Command::REACT, "message id", 0, id))?;
+                let emoji = parts
+                    .next()
+                    .ok_or_else(|| missing(Command::REACT, "emoji", 1))?;
+                Ok(Command::React(id, emoji.to_string()))
+            }
+            Some(Command::INITIATIVE) => match parts.next() {
+                Some("add") => {
+                    let name = parts
+                        .next()
+                        .ok_or_else(|| missing(Command::INITIATIVE, "name", 1))?;
+                    let value = parts
+                        .next()
+                        .ok_or_else(|| missing(Command::INITIATIVE, "initiative value", 2))?;
+                    let value = value
+                        .parse()
+                        .map_err(|_| invalid(Command::INITIATIVE, "initiative value", 2, value))?;
+                    Ok(Command::InitiativeAdd(name.to_string(), value))
+                }
+                Some("next") => Ok(Command::InitiativeNext),
+                Some("clear") => Ok(Command::InitiativeClear),
+                Some(subcommand) => Err(invalid(Command::INITIATIVE, "subcommand", 0, subcommand)),
+                None => Err(missing(Command::INITIATIVE, "subcommand", 0)),
+            },
+            Some(Command::PING) => Ok(Command::Ping(parts.next().map(String::from))),
+            Some(Command::PONG) => {
+                let id = parts
+                    .next()
+                    .ok_or_else(|| missing(Command::PONG, "ping id", 0))?;
+                let id = id
+                    .parse()
+                    .map_err(|_| invalid(Command::PONG, "ping id", 0, id))?;
+                Ok(Command::Pong(id))
+            }
+            Some(Command::AWAY) => Ok(Command::Away(
+                Some(rest.to_string()).filter(|s| !s.is_empty()),
+            )),
+            Some(Command::STATUS) => {
+                let status = parts
+                    .next()
+                    .ok_or_else(|| missing(Command::STATUS, "status", 0))?;
+                let status = status
+                    .parse()
+                    .map_err(|_| invalid(Command::STATUS, "status", 0, status))?;
+                let message = Some(after(1).to_string()).filter(|message| !message.is_empty());
+                Ok(Command::Status(status, message))
+            }
+            Some(Command::SUMMARY) => Ok(Command::Summary),
+            Some(Command::SEARCH) if rest.is_empty() => Err(missing(Command::SEARCH, "query", 0)),
+            Some(Command::SEARCH) => Ok(Command::Search(rest.trim_end().to_string())),
+            Some(Command::TRANSLATE) => {
+                let language = parts
+                    .next()
+                    .ok_or_else(|| missing(Command::TRANSLATE, "language", 0))?;
+                let message = text(Command::TRANSLATE, 1)?;
+                Ok(Command::Translate(
+                    language.to_string(),
+                    message.to_string(),
+                ))
+            }
+            Some(Command::QUIT) => Ok(Command::Quit(
+                Some(rest.to_string()).filter(|s| !s.is_empty()),
+            )),
+            _ => Err(UnknownCommand(value)),
+        }
+    }
+}
+
+/// An error that occurred while parsing a [`Command`]
+///
+/// The positions of the arguments start from 0 after the command name.
+#[derive(Debug, Clone, PartialEq, Eq, Display)]
+pub enum CommandParseError {
+    #[strum(to_string = "Invalid command: {0}")]
+    UnknownCommand(String),
+    #[strum(to_string = "The {arg_name} is required for /{command}")]
+    MissingArg {
+        command: &'static str,
+        arg_name: &'static str,
+        position: usize,
+    },
+    #[strum(to_string = "Invalid {arg_name} for /{command}: {value}")]
+    InvalidArg {
+        command: &'static str,
+        arg_name: &'static str,
+        position: usize,
+        value: String,
+    },
+}
+
+impl std::error::Error for CommandParseError {}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn parse(line: &str) -> Result<Command, CommandParseError> {
+        Command::try_from(line.to_string())
+    }
+
+    /// One command of every variant, with the arguments that survive a round trip
+    fn examples() -> Vec<Command> {
+        let capabilities = Capabilities {
+            binary_codec: true,
+            reactions: true,
+            ..Capabilities::default()
+        };
+        let commands = vec![
+            Command::Hello(1, capabilities),
+            Command::Help,
+            Command::ChangeUsername("alice".into()),
+            Command::NameHistory,
+            Command::ListRooms,
+            Command::ListMemberships,
+            Command::Join("den".into(), None),
+            Command::Join("den".into(), Some("hunter2".to_string())),
+            Command::Leave("den".into()),
+            Command::RenameRoom("hall".into()),
+            Command::ClearRoom,
+            Command::Op("bob".into()),
+            Command::Kick("bob".into()),
+            Command::Ban("bob".into()),
+            Command::Unban("bob".into()),
+            Command::Mute("bob".into()),
+            Command::Unmute("bob".into()),
+            Command::ListUsers,
+            Command::CountUsers,
+            Command::SendFile("my notes.txt".to_string(), "aGk=".to_string()),
+            Command::Code("rust".to_string(), "Zm4gbWFpbigpIHt9".to_string()),
+            Command::FileStart(1, 1024, "my notes.txt".to_string()),
+            Command::FileChunk(1, 0, 2, "aGk=".to_string()),
+            Command::FileEnd(1),
+            Command::DirectMessage("bob".into(), "hi  there".to_string()),
+            Command::MarkRead(MessageId::new(3)),
+            Command::Nudge("bob".into()),
+            Command::Oper("hunter2".to_string()),
+            Command::SystemMessage("den".into(), "be nice".to_string()),
+            Command::Drain,
+            Command::Undo,
+            Command::Edit(MessageId::new(3), "fixed typo".to_string()),
+            Command::React(MessageId::new(3), "🎉".to_string()),
+            Command::InitiativeAdd("goblin".to_string(), -2),
+            Command::InitiativeNext,
+            Command::InitiativeClear,
+            Command::Ping(None),
+            Command::Ping(Some("token".to_string())),
+            Command::Pong(7),
+            Command::Away(None),
+            Command::Away(Some("lunch break".to_string())),
+            Command::Status(UserStatus::Busy, None),
+            Command::Status(UserStatus::Away, Some("in a meeting".to_string())),
+            Command::Summary,
+            Command::Search("release date".to_string()),
+            Command::Translate("de".to_string(), "good morning".to_string()),
+            Command::Quit(None),
+            Command::Quit(Some("bye all".to_string())),
+        ];
+        // fails to compile when a variant is added, as a reminder to add it above
+        for command in &commands {
+            match command {
+                Command::Hello(..)
+                | Command::Help
+                | Command::ChangeUsername(_)
+                | Command::NameHistory
+                | Command::ListRooms
+                | Command::ListMemberships
+                | Command::Join(..)
+                | Command::Leave(_)
+                | Command::RenameRoom(_)
+                | Command::ClearRoom
+                | Command::Op(_)
+                | Command::Kick(_)
+                | Command::Ban(_)
+                | Command::Unban(_)
+                | Command::Mute(_)
+                | Command::Unmute(_)
+                | Command::ListUsers
+                | Command::CountUsers
+                | Command::SendFile(..)
+                | Command::Code(..)
+                | Command::FileStart(..)
+                | Command::FileChunk(..)
+                | Command::FileEnd(_)
+                | Command::DirectMessage(..)
+                | Command::MarkRead(_)
+                | Command::Nudge(_)
+                | Command::Oper(_)
+                | Command::SystemMessage(..)
+                | Command::Drain
+                | Command::Undo
+                | Command::Edit(..)
+                | Command::React(..)
+                | Command::InitiativeAdd(..)
+                | Command::InitiativeNext
+                | Command::InitiativeClear
+                | Command::Ping(_)
+                | Command::Pong(_)
+                | Command::Away(_)
+                | Command::Status(..)
+                | Command::Summary
+                | Command::Search(_)
+                | Command::Translate(..)
+                | Command::Quit(_) => {}
+            }
+        }
+        commands
+    }
+
+    #[test]
+    fn every_command_round_trips() {
+        for command in examples() {
+            assert_eq!(
+                parse(&command.to_string()),
+                Ok(command.clone()),
+                "{command}"
+            );
+        }
+    }
+
+    /// Fills in the arguments of a `USAGE` entry, once for every alternative (e.g. `{a|b}`)
+    fn usage_examples(name: &str, arguments: &str) -> Vec<String> {
+        let sample = |argument: &str| match argument {
+            "name" => "bob",
+            "room" => "den",
+            "password" => "hunter2",
+            "count" => "count",
+            "message" => "hi there",
+            "id" => "3",
+            "emoji" => "🎉",
+            "initiative" => "12",
+            "token" => "abc",
+            "query" => "release date",
+            "language" => "de",
+            _ => panic!("no sample for the {argument} argument of /{name}"),
+        };
+        let mut lines = vec![format!("/{name}")];
+        for argument in arguments.split_whitespace() {
+            let argument = argument.trim_matches(['{', '}', '[', ']']);
+            let alternatives = match argument.split('|').collect::<Vec<_>>() {
+                alternatives if alternatives.len() > 1 => alternatives,
+                _ => vec![sample(argument)],
+            };
+            lines = lines
+                .iter()
+                .flat_map(|line| {
+                    alternatives
+                        .iter()
+                        .map(move |value| format!("{line} {value}"))
+                })
+                .collect();
+        }
+        lines
+    }
+
+    #[test]
+    fn usage_examples_parse() {
+        for (name, arguments) in Command::USAGE {
+            for line in usage_examples(name, arguments) {
+                let command = parse(&line).unwrap_or_else(|err| panic!("{line}: {err}"));
+                assert_eq!(command.name(), *name, "{line}");
+            }
+        }
+    }
+
+    #[test]
+    fn single_word_arguments_end_at_whitespace() {
+        assert_eq!(
+            parse("/name alice smith"),
+            Ok(Command::ChangeUsername("alice".into()))
+        );
+        assert_eq!(
+            parse("/join den hunter 2"),
+            Ok(Command::Join("den".into(), Some("hunter".to_string())))
+        );
+        assert_eq!(
+            parse("/react 3 🎉 🎊"),
+            Ok(Command::React(MessageId::new(3), "🎉".to_string()))
+        );
+        let command = Command::Oper("hunter 2".to_string());
+        assert_eq!(
+            parse(&command.to_string()),
+            Ok(Command::Oper("hunter".to_string()))
+        );
+    }
+
+    #[test]
+    fn carriage_returns_are_ignored() {
+        assert_eq!(
+            parse("/join room\r"),
+            Ok(Command::Join("room".into(), None))
+        );
+        assert_eq!(
+            parse("/join room hunter2\r"),
+            Ok(Command::Join("room".into(), Some("hunter2".to_string())))
+        );
+        assert_eq!(parse("/rooms\r"), Ok(Command::ListRooms));
+    }
+
+    #[test]
+    fn file_content_ends_at_whitespace() {
+        let command = Command::SendFile("notes.txt".to_string(), "aGk= aGk=".to_string());
+        assert_eq!(
+            parse(&command.to_string()),
+            Ok(Command::SendFile(
+                "notes.txt aGk=".to_string(),
+                "aGk=".to_string()
+            ))
+        );
+        assert_eq!(
+            parse("/code rust fn main() {}"),
+            Ok(Command::Code("rust".to_string(), "fn".to_string()))
+        );
+    }
+
+    #[test]
+    fn messages_cannot_be_empty() {
+        for line in [
+            "/msg bob",
+            "/msg bob ",
+            "/sysmsg den",
+            "/edit 3",
+            "/translate de",
+        ] {
+            assert!(
+                matches!(parse(line), Err(CommandParseError::MissingArg { .. })),
+                "{line}"
+            );
+        }
+        let command = Command::DirectMessage("bob".into(), String::new());
+        assert!(parse(&command.to_string()).is_err());
+    }
+
+    #[test]
+    fn missing_arguments_have_their_positions() {
+        for (line, command, arg_name, position) in [
+            ("/hello", Command::HELLO, "protocol version", 0),
+            ("/name", Command::NAME, "name", 0),
+            ("/join", Command::JOIN, "room name", 0),
+            ("/leave", Command::LEAVE, "room name", 0),
+            ("/renameroom", Command::RENAME_ROOM, "room name", 0),
+            ("/op", Command::OP, "username", 0),
+            ("/kick", Command::KICK, "username", 0),
+            ("/ban", Command::BAN, "username", 0),
+            ("/unban", Command::UNBAN, "username", 0),
+            ("/mute", Command::MUTE, "username", 0),
+            ("/unmute", Command::UNMUTE, "username", 0),
+            ("/file", Command::FILE, "file name", 0),
+            ("/file notes.txt", Command::FILE, "file content", 1),
+            ("/code", Command::CODE, "language", 0),
+            ("/code rust", Command::CODE, "source", 1),
+            ("/filestart", Command::FILE_START, "transfer id", 0),
+            ("/filestart 1", Command::FILE_START, "file size", 1),
+            ("/filestart 1 10", Command::FILE_START, "file name", 2),
+            ("/filechunk", Command::FILE_CHUNK, "transfer id", 0),
+            ("/filechunk 1", Command::FILE_CHUNK, "chunk index", 1),
+            ("/filechunk 1 0", Command::FILE_CHUNK, "chunk count", 2),
+            ("/filechunk 1 0 2", Command::FILE_CHUNK, "file content", 3),
+            ("/fileend", Command::FILE_END, "transfer id", 0),
+            ("/msg", Command::MSG, "username", 0),
+            ("/msg bob", Command::MSG, "message", 1),
+            ("/read", Command::READ, "message id", 0),
+            ("/nudge", Command::NUDGE, "username", 0),
+            ("/oper", Command::OPER, "password", 0),
+            ("/sysmsg", Command::SYSMSG, "room name", 0),
+            ("/sysmsg den", Command::SYSMSG, "message", 1),
+            ("/edit", Command::EDIT, "message id", 0),
+            ("/edit 3", Command::EDIT, "message", 1),
+            ("/react", Command::REACT, "message id", 0),
+            ("/react 3", Command::REACT, "emoji", 1),
+            ("/initiative", Command::INITIATIVE, "subcommand", 0),
+            ("/initiative add", Command::INITIATIVE, "name", 1),
+            (
+                "/initiative add goblin",
+                Command::INITIATIVE,
+                "initiative value",
+                2,
+            ),
+            ("/pong", Command::PONG, "ping id", 0),
+            ("/status", Command::STATUS, "status", 0),
+            ("/search", Command::SEARCH, "query", 0),
+            ("/translate", Command::TRANSLATE, "language", 0),
+            ("/translate de", Command::TRANSLATE, "message", 1),
+        ] {
+            let expected = CommandParseError::MissingArg {
+                command,
+                arg_name,
+                position,
+            };
+            assert_eq!(parse(line), Err(expected.clone()), "{line}");
+            // tabs and repeated spaces separate the arguments just like single spaces
+            let spaced = line.replace(' ', " \t  ");
+            assert_eq!(parse(&spaced), Err(expected), "{spaced}");
+        }
+    }
+
+    #[test]
+    fn arguments_are_separated_by_any_whitespace() {
+        assert_eq!(
+            parse("/msg\tbob \t hi  there"),
+            Ok(Command::DirectMessage(
+                "bob".into(),
+                "hi  there".to_string()
+            ))
+        );
+        assert_eq!(
+            parse("/filestart 1\t10\tmy notes.txt"),
+            Ok(Command::FileStart(1, 10, "my notes.txt".to_string()))
+        );
+        assert_eq!(
+            parse("/file notes.txt\taGk="),
+            Ok(Command::SendFile(
+                "notes.txt".to_string(),
+                "aGk=".to_string()
+            ))
+        );
+        assert_eq!(
+            parse("/status away\tat lunch"),
+            Ok(Command::Status(
+                UserStatus::Away,
+                Some("at lunch".to_string())
+            ))
+        );
+    }
+
+    #[test]
+    fn search_query_is_trimmed() {
+        assert_eq!(
+            parse("/search   release date  "),
+            Ok(Command::Search("release date".to_string()))
+        );
+        assert!(parse("/search   ").is_err());
+    }
+
+    #[test]
+    fn empty_messages_are_none() {
+        for (command, expected) in [
+            (Command::Away(Some(String::new())), Command::Away(None)),
+            (Command::Quit(Some(String::new())), Command::Quit(None)),
+            (
+                Command::Status(UserStatus::Away, Some(String::new())),
+                Command::Status(UserStatus::Away, None),
+            ),
+        ] {
+            assert_eq!(parse(&command.to_string()), Ok(expected));
+        }
+    }
+
+    #[test]
+    fn unknown_capabilities_are_ignored() {
+        let capabilities = Capabilities {
+            file_chunking: true,
+            ..Capabilities::default()
+        };
+        assert_eq!(
+            parse("/hello 1 file_chunking telepathy"),
+            Ok(Command::Hello(1, capabilities))
+        );
+    }
+
+    #[test]
+    fn invalid_numbers_are_rejected() {
+        for line in [
+            "/hello one",
+            "/filestart x 1 a",
+            "/fileend -1",
+            "/pong x",
+            "/edit x hi",
+        ] {
+            assert!(
+                matches!(parse(line), Err(CommandParseError::InvalidArg { .. })),
+                "{line}"
+            );
         }
+        assert_eq!(
+            parse("/frobnicate"),
+            Err(CommandParseError::UnknownCommand("/frobnicate".to_string()))
+        );
     }
 }
@@ -0,0 +1,97 @@
+use base64::{engine::general_purpose::STANDARD, DecodeError, Engine};
+use strum_macros::Display;
+
+/// Encodes the contents of a file to be sent with `/file`
+///
+/// The contents are prefixed with their length and a colon (e.g. `5:aGVsbG8=`), so that
+/// truncated contents are detected when decoding. The standard base64 alphabet is used with
+/// padding and without line breaks, so any bytes (e.g. CRLF line endings or binary data)
+/// round-trip exactly.
+pub fn encode_file(contents: &[u8]) -> String {
+    format!("{}:{}", contents.len(), STANDARD.encode(contents))
+}
+
+/// Decodes the contents of a file that was encoded with [`encode_file`]
+///
+/// Plain base64 without the length prefix is accepted as well, for clients that don't use
+/// [`encode_file`].
+pub fn decode_file(encoded: &str) -> Result<Vec<u8>, FileDecodeError> {
+    let Some((length, encoded)) = encoded.split_once(':') else {
+        return STANDARD.decode(encoded).map_err(FileDecodeError::Base64);
+    };
+    let expected = length
+        .parse()
+        .map_err(|_| FileDecodeError::InvalidLength(length.to_string()))?;
+    let contents = STANDARD.decode(encoded).map_err(FileDecodeError::Base64)?;
+    if contents.len() != expected {
+        return Err(FileDecodeError::LengthMismatch {
+            expected,
+            actual: contents.len(),
+        });
+    }
+    Ok(contents)
+}
+
+/// An error that occurred while decoding the contents of a file
+#[derive(Debug, Clone, PartialEq, Eq, Display)]
+pub enum FileDecodeError {
+    #[strum(to_string = "Invalid file length: {0}")]
+    InvalidLength(String),
+    #[strum(to_string = "Invalid base64 contents: {0}")]
+    Base64(DecodeError),
+    #[strum(to_string = "Expected {expected} bytes but got {actual}")]
+    LengthMismatch { expected: usize, actual: usize },
+    #[strum(to_string = "Chunk {index} does not fit the {total} chunks of the file")]
+    InvalidChunk { index: u32, total: u32 },
+}
+
+impl std::error::Error for FileDecodeError {}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn crlf_line_endings_round_trip() {
+        let contents = b"first line\r\nsecond line\r\n\r\nlast\n";
+        let encoded = encode_file(contents);
+        assert!(!encoded.contains(['\r', '\n', ' ']));
+        assert_eq!(decode_file(&encoded).unwrap(), contents);
+    }
+
+    #[test]
+    fn binary_contents_round_trip() {
+        let contents: Vec<u8> = (0..=u8::MAX).cycle().take(1000).collect();
+        assert_eq!(decode_file(&encode_file(&contents)).unwrap(), contents);
+        assert_eq!(decode_file(&encode_file(&[])).unwrap(), b"");
+    }
+
+    #[test]
+    fn length_is_prefixed() {
+        assert_eq!(encode_file(b"hello"), "5:aGVsbG8=");
+    }
+
+    #[test]
+    fn plain_base64_is_accepted() {
+        assert_eq!(decode_file("aGVsbG8=").unwrap(), b"hello");
+    }
+
+    #[test]
+    fn truncated_contents_are_rejected() {
+        assert_eq!(
+            decode_file("10:aGVsbG8="),
+            Err(FileDecodeError::LengthMismatch {
+                expected: 10,
+                actual: 5
+            })
+        );
+        assert_eq!(
+            decode_file("five:aGVsbG8="),
+            Err(FileDecodeError::InvalidLength("five".to_string()))
+        );
+        assert!(matches!(
+            decode_file("5:not base64"),
+            Err(FileDecodeError::Base64(_))
+        ));
+    }
+}
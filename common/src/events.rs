@@ -1,10 +1,28 @@
+use std::time::Duration;
+
+use chrono::{DateTime, Local, Utc};
 use serde::{Deserialize, Serialize};
-use strum_macros::Display;
+use strum_macros::{Display, EnumDiscriminants, IntoStaticStr};
 
-use crate::{RoomName, Username};
+use crate::{Capabilities, MessageId, RoomName, UserStatus, Username, PROTOCOL_VERSION};
 
-#[derive(Debug, Clone, Serialize, Deserialize, Display)]
+/// An event that the server sends to a client
+///
+/// New events are added as the protocol grows, so clients need a catch-all arm when matching.
+#[derive(Debug, Clone, Serialize, Deserialize, Display, EnumDiscriminants)]
+#[strum_discriminants(name(ServerEventKind), vis(pub(crate)), derive(IntoStaticStr))]
+#[non_exhaustive]
 pub enum ServerEvent {
+    #[strum(to_string = "Hello({version}, protocol {protocol_version})")]
+    Hello {
+        version: String,
+        #[serde(default)]
+        protocol_version: u32,
+        capabilities: Capabilities,
+    },
+    /// The features that the server uses after the client announced its own with `/hello`
+    #[strum(to_string = "Capabilities({0:?})")]
+    Capabilities(Capabilities),
     #[strum(to_string = "Help({0}, {1})")]
     CommandHelp(Username, String),
     #[strum(to_string = "{username} {event}")]
@@ -12,23 +30,111 @@ pub enum ServerEvent {
         room_name: RoomName,
         username: Username,
         date: String,
+        /// When the server received the event
+        timestamp: DateTime<Utc>,
         event: RoomEvent,
     },
+    #[strum(to_string = "{from} -> {to}: {text}")]
+    DirectMessage {
+        /// The id that the recipient marks the message as read with, see
+        /// [`Command::MarkRead`](crate::Command::MarkRead)
+        id: MessageId,
+        from: Username,
+        to: Username,
+        date: String,
+        text: String,
+    },
+    #[strum(to_string = "History({room_name})")]
+    History {
+        room_name: RoomName,
+        events: Vec<ServerEvent>,
+    },
+    #[strum(to_string = "Search Results({room_name}, {query})")]
+    SearchResults {
+        room_name: RoomName,
+        query: String,
+        /// The matching messages, the oldest one first
+        results: Vec<ServerEvent>,
+    },
     #[strum(to_string = "Room Created({0})")]
     RoomCreated(RoomName),
     #[strum(to_string = "Room Deleted({0})")]
     RoomDeleted(RoomName),
+    #[strum(to_string = "Join Denied({room_name}, {reason})")]
+    JoinDenied { room_name: RoomName, reason: String },
     #[strum(to_string = "Error({0})")]
     Error(String),
     #[strum(to_string = "Rooms({0:?})")]
     Rooms(Vec<(RoomName, usize)>),
+    #[strum(to_string = "Memberships({rooms:?})")]
+    Memberships {
+        rooms: Vec<RoomName>,
+        current: RoomName,
+    },
     #[strum(to_string = "Users({0:?})")]
     Users(Vec<Username>),
+    #[strum(to_string = "User Count({0})")]
+    UserCount(usize),
     #[strum(to_string = "Disconnected")]
     Disconnect,
+    #[strum(to_string = "Draining")]
+    Draining,
+    #[strum(to_string = "Server Full")]
+    ServerFull,
+    /// The server is shutting down, the connection is closed right after this event
+    #[strum(to_string = "Server Shutdown({reason})")]
+    ServerShutdown { reason: String },
+    #[strum(to_string = "Announcement({0})")]
+    Announcement(String),
+    #[strum(to_string = "Message of the Day({0})")]
+    Motd(String),
+    #[strum(to_string = "Kicked({username})")]
+    Kicked { username: Username, banned: bool },
+    #[strum(to_string = "Summary({room_name}, {messages} messages)")]
+    Summary {
+        room_name: RoomName,
+        messages: usize,
+        joined: Vec<Username>,
+        left: Vec<Username>,
+        mentions: Vec<MessageId>,
+    },
+    /// The previous names of the user, the most recent one first
+    #[strum(to_string = "Name History({0:?})")]
+    NameHistory(Vec<Username>),
+    #[strum(to_string = "Translation({language}, {text})")]
+    Translation { language: String, text: String },
+    #[strum(to_string = "Pong({0:?})")]
+    Pong(Option<String>),
+    /// Checks that the user is still connected, answered with `/pong {id}`
+    #[strum(to_string = "Ping({0})")]
+    Ping(u64),
+    #[strum(to_string = "Rate Limited({retry_after:?})")]
+    RateLimited { retry_after: Duration },
+    #[strum(to_string = "Quota Exceeded({kind}, {limit})")]
+    QuotaExceeded { kind: QuotaKind, limit: usize },
+    /// The recipient of a direct message marked it as read
+    #[strum(to_string = "Read Receipt({id}, {reader})")]
+    ReadReceipt { id: MessageId, reader: Username },
+}
+
+/// The kind of limit that was exceeded
+#[derive(Debug, Clone, Copy, Serialize, Deserialize, Display, PartialEq, Eq)]
+pub enum QuotaKind {
+    /// The size of a file in bytes
+    FileSize,
+    /// The length of a line in bytes
+    LineLength,
 }
 
 impl ServerEvent {
+    pub fn hello(version: &str, capabilities: Capabilities) -> Self {
+        Self::Hello {
+            version: version.to_string(),
+            protocol_version: PROTOCOL_VERSION,
+            capabilities,
+        }
+    }
+
     pub fn help(username: &Username, commands: &str) -> Self {
         Self::CommandHelp(username.clone(), commands.to_string())
     }
@@ -37,20 +143,128 @@ impl ServerEvent {
         Self::Error(message.to_string())
     }
 
+    pub fn join_denied(room_name: &RoomName, reason: &str) -> Self {
+        Self::JoinDenied {
+            room_name: room_name.clone(),
+            reason: reason.to_string(),
+        }
+    }
+
     pub fn rooms(rooms: Vec<(RoomName, usize)>) -> Self {
         Self::Rooms(rooms)
     }
 
+    pub fn memberships(rooms: Vec<RoomName>, current: &RoomName) -> Self {
+        Self::Memberships {
+            rooms,
+            current: current.clone(),
+        }
+    }
+
     pub fn users(users: Vec<Username>) -> Self {
         Self::Users(users)
     }
 
+    pub fn user_count(count: usize) -> Self {
+        Self::UserCount(count)
+    }
+
+    pub fn rate_limited(retry_after: Duration) -> Self {
+        Self::RateLimited { retry_after }
+    }
+
+    pub fn quota_exceeded(kind: QuotaKind, limit: usize) -> Self {
+        Self::QuotaExceeded { kind, limit }
+    }
+
+    pub fn announcement(message: &str) -> Self {
+        Self::Announcement(message.to_string())
+    }
+
+    pub fn server_shutdown(reason: &str) -> Self {
+        Self::ServerShutdown {
+            reason: reason.to_string(),
+        }
+    }
+
+    pub fn kicked(username: &Username, banned: bool) -> Self {
+        Self::Kicked {
+            username: username.clone(),
+            banned,
+        }
+    }
+
+    pub fn name_history(names: Vec<Username>) -> Self {
+        Self::NameHistory(names)
+    }
+
+    pub fn translation(language: &str, text: &str) -> Self {
+        Self::Translation {
+            language: language.to_string(),
+            text: text.to_string(),
+        }
+    }
+
+    pub fn summary(
+        room_name: &RoomName,
+        messages: usize,
+        joined: Vec<Username>,
+        left: Vec<Username>,
+        mentions: Vec<MessageId>,
+    ) -> Self {
+        Self::Summary {
+            room_name: room_name.clone(),
+            messages,
+            joined,
+            left,
+            mentions,
+        }
+    }
+
     pub fn room_event(room_name: &RoomName, username: &Username, event: RoomEvent) -> Self {
+        Self::room_event_at(room_name, username, event, Utc::now())
+    }
+
+    pub fn room_event_at(
+        room_name: &RoomName,
+        username: &Username,
+        event: RoomEvent,
+        timestamp: DateTime<Utc>,
+    ) -> Self {
         Self::RoomEvent {
             room_name: room_name.clone(),
             username: username.clone(),
             event,
-            date: chrono::Local::now().format("%H:%M:%S").to_string(),
+            date: timestamp
+                .with_timezone(&Local)
+                .format("%H:%M:%S")
+                .to_string(),
+            timestamp,
+        }
+    }
+
+    pub fn history(room_name: &RoomName, events: Vec<ServerEvent>) -> Self {
+        Self::History {
+            room_name: room_name.clone(),
+            events,
+        }
+    }
+
+    pub fn direct_message(id: MessageId, from: &Username, to: &Username, text: &str) -> Self {
+        Self::DirectMessage {
+            id,
+            from: from.clone(),
+            to: to.clone(),
+            date: Local::now().format("%H:%M:%S").to_string(),
+            text: text.to_string(),
+        }
+    }
+
+    pub fn search_results(room_name: &RoomName, query: &str, results: Vec<ServerEvent>) -> Self {
+        Self::SearchResults {
+            room_name: room_name.clone(),
+            query: query.to_string(),
+            results,
         }
     }
 
@@ -69,38 +283,175 @@ impl ServerEvent {
     pub fn from_json_str(json_str: &str) -> Result<Self, serde_json::Error> {
         serde_json::from_str(json_str)
     }
+
+    /// Returns the name of the kind of the event, e.g. for logging it without its contents
+    ///
+    /// The kind of room events is the kind of the event in the room.
+    pub fn kind(&self) -> &'static str {
+        match self {
+            Self::RoomEvent { event, .. } => RoomEventKind::from(event).into(),
+            event => ServerEventKind::from(event).into(),
+        }
+    }
 }
 
-#[derive(Debug, Clone, Serialize, Deserialize, Display)]
+/// An event that happened in a room
+///
+/// New events are added as the protocol grows, so clients need a catch-all arm when matching.
+#[derive(Debug, Clone, Serialize, Deserialize, Display, EnumDiscriminants)]
+#[strum_discriminants(name(RoomEventKind), vis(pub(crate)), derive(IntoStaticStr))]
+#[non_exhaustive]
 pub enum RoomEvent {
-    #[strum(to_string = "created room {0}")]
-    Message(String),
+    #[strum(to_string = "sent message: {text}")]
+    Message {
+        id: MessageId,
+        text: String,
+        /// The members of the room that are mentioned with `@username` in the text
+        #[serde(default)]
+        mentions: Vec<Username>,
+    },
     #[strum(to_string = "sent file: {filename}")]
-    File { filename: String, contents: String },
+    File {
+        filename: String,
+        /// The encoded contents, see [`encode_file`](crate::encode_file)
+        contents: String,
+        /// The size of the decoded contents in bytes
+        size: usize,
+    },
+    #[strum(to_string = "started sending file: {filename}")]
+    FileStart {
+        /// The id of the transfer, unique among the transfers of the sender
+        transfer: u64,
+        filename: String,
+        /// The size of the decoded contents in bytes
+        size: usize,
+    },
+    /// Chunks can arrive in any order, see [`FileAssembler`](crate::FileAssembler) for reassembling
+    /// them
+    #[strum(to_string = "sent a chunk of file transfer {transfer}")]
+    FileChunk {
+        transfer: u64,
+        /// The index of the chunk, starting from zero
+        index: u32,
+        /// The number of chunks that the file is split into
+        total: u32,
+        /// The encoded contents of the chunk, see [`encode_file`](crate::encode_file)
+        contents: String,
+    },
+    #[strum(to_string = "finished sending file transfer {transfer}")]
+    FileEnd { transfer: u64 },
+    #[strum(to_string = "aborted file transfer {transfer}")]
+    FileAborted { transfer: u64 },
     #[strum(to_string = "joined room {0}")]
     Joined(RoomName),
-    #[strum(to_string = "left room {0}")]
-    Left(RoomName),
+    #[strum(to_string = "left room {room_name}")]
+    Left {
+        room_name: RoomName,
+        farewell: Option<String>,
+    },
     #[strum(to_string = "changed name to {0}")]
     NameChange(Username),
     #[strum(to_string = "nudged {0}")]
     Nudge(Username),
+    #[strum(to_string = "made {0} a moderator")]
+    Opped(Username),
+    #[strum(to_string = "kicked {0}")]
+    Kicked(Username),
+    #[strum(to_string = "banned {0}")]
+    Banned(Username),
+    #[strum(to_string = "unbanned {0}")]
+    Unbanned(Username),
+    #[strum(to_string = "muted {0}")]
+    Muted(Username),
+    #[strum(to_string = "unmuted {0}")]
+    Unmuted(Username),
+    #[strum(to_string = "announced: {0}")]
+    System(String),
+    #[strum(to_string = "deleted message {0}")]
+    Deleted(MessageId),
+    #[strum(to_string = "cleared the history")]
+    HistoryCleared,
+    #[strum(to_string = "closed the room")]
+    Closed,
+    #[strum(to_string = "edited message {id}: {text}")]
+    Edited { id: MessageId, text: String },
+    #[strum(to_string = "shared {language} code")]
+    Code { language: String, source: String },
+    #[strum(to_string = "reacted to message {message_id} with {emoji}")]
+    Reaction {
+        message_id: MessageId,
+        emoji: String,
+    },
+    #[strum(to_string = "updated the initiative order")]
+    Initiative {
+        order: Vec<(String, i32)>,
+        turn: Option<usize>,
+    },
+    #[strum(to_string = "renamed room {from} to {to}")]
+    Renamed { from: RoomName, to: RoomName },
+    #[strum(to_string = "is back")]
+    Back,
+    #[strum(to_string = "is {status}")]
+    StatusChanged {
+        status: UserStatus,
+        message: Option<String>,
+    },
 }
 
 impl RoomEvent {
-    pub fn message(message: &str) -> Self {
-        Self::Message(message.to_string())
+    pub fn message(id: MessageId, message: &str, mentions: Vec<Username>) -> Self {
+        Self::Message {
+            id,
+            text: message.to_string(),
+            mentions,
+        }
     }
 
-    pub fn file(filename: &str, contents: &str) -> Self {
+    pub fn file(filename: &str, contents: &str, size: usize) -> Self {
         Self::File {
             filename: filename.to_string(),
             contents: contents.to_string(),
+            size,
+        }
+    }
+
+    pub fn file_start(transfer: u64, filename: &str, size: usize) -> Self {
+        Self::FileStart {
+            transfer,
+            filename: filename.to_string(),
+            size,
+        }
+    }
+
+    pub fn file_chunk(transfer: u64, index: u32, total: u32, contents: &str) -> Self {
+        Self::FileChunk {
+            transfer,
+            index,
+            total,
+            contents: contents.to_string(),
+        }
+    }
+
+    pub fn file_end(transfer: u64) -> Self {
+        Self::FileEnd { transfer }
+    }
+
+    pub fn file_aborted(transfer: u64) -> Self {
+        Self::FileAborted { transfer }
+    }
+
+    pub fn code(language: &str, source: &str) -> Self {
+        Self::Code {
+            language: language.to_string(),
+            source: source.to_string(),
         }
     }
 
-    pub fn left(room_name: &RoomName) -> Self {
-        Self::Left(room_name.clone())
+    pub fn left(room_name: &RoomName, farewell: Option<&str>) -> Self {
+        Self::Left {
+            room_name: room_name.clone(),
+            farewell: farewell.map(String::from),
+        }
     }
 
     pub fn joined(room_name: &RoomName) -> Self {
@@ -114,4 +465,106 @@ impl RoomEvent {
     pub fn nudge(username: &Username) -> Self {
         Self::Nudge(username.clone())
     }
+
+    pub fn opped(username: &Username) -> Self {
+        Self::Opped(username.clone())
+    }
+
+    pub fn kicked(username: &Username) -> Self {
+        Self::Kicked(username.clone())
+    }
+
+    pub fn banned(username: &Username) -> Self {
+        Self::Banned(username.clone())
+    }
+
+    pub fn unbanned(username: &Username) -> Self {
+        Self::Unbanned(username.clone())
+    }
+
+    pub fn muted(username: &Username) -> Self {
+        Self::Muted(username.clone())
+    }
+
+    pub fn unmuted(username: &Username) -> Self {
+        Self::Unmuted(username.clone())
+    }
+
+    pub fn system(message: &str) -> Self {
+        Self::System(message.to_string())
+    }
+
+    pub fn deleted(id: MessageId) -> Self {
+        Self::Deleted(id)
+    }
+
+    pub fn edited(id: MessageId, text: &str) -> Self {
+        Self::Edited {
+            id,
+            text: text.to_string(),
+        }
+    }
+
+    pub fn reaction(message_id: MessageId, emoji: &str) -> Self {
+        Self::Reaction {
+            message_id,
+            emoji: emoji.to_string(),
+        }
+    }
+
+    pub fn renamed(from: &RoomName, to: &RoomName) -> Self {
+        Self::Renamed {
+            from: from.clone(),
+            to: to.clone(),
+        }
+    }
+
+    pub fn status_changed(status: UserStatus, message: Option<String>) -> Self {
+        Self::StatusChanged { status, message }
+    }
+
+    pub fn initiative(order: Vec<(String, i32)>, turn: Option<usize>) -> Self {
+        Self::Initiative { order, turn }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn kind_leaves_out_the_contents() {
+        let event = ServerEvent::room_event(
+            &RoomName::lobby(),
+            &Username::from("alice"),
+            RoomEvent::file("secret.txt", "c2VjcmV0", 6),
+        );
+        assert_eq!(event.kind(), "File");
+        assert_eq!(ServerEvent::error("oops").kind(), "Error");
+        assert_eq!(ServerEvent::Ping(1).kind(), "Ping");
+    }
+
+    #[test]
+    fn limits_round_trip() {
+        let retry_after = Duration::from_millis(1500);
+        let json = ServerEvent::rate_limited(retry_after).as_json_str();
+        let event = ServerEvent::from_json_str(&json).unwrap();
+        let ServerEvent::RateLimited { retry_after: after } = event else {
+            panic!("{json}")
+        };
+        assert_eq!(after, retry_after);
+
+        let json = ServerEvent::quota_exceeded(QuotaKind::FileSize, 1024).as_json_str();
+        let event = ServerEvent::from_json_str(&json).unwrap();
+        assert!(
+            matches!(
+                event,
+                ServerEvent::QuotaExceeded {
+                    kind: QuotaKind::FileSize,
+                    limit: 1024
+                }
+            ),
+            "{json}"
+        );
+    }
 }
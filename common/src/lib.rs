@@ -1,9 +1,21 @@
-pub use command::Command;
-pub use events::{RoomEvent, ServerEvent};
+pub use assembler::FileAssembler;
+pub use capabilities::{Capabilities, PROTOCOL_VERSION};
+pub use codec::{Codec, CodecError};
+pub use command::{Command, CommandParseError};
+pub use encoding::{decode_file, encode_file, FileDecodeError};
+pub use events::{QuotaKind, RoomEvent, ServerEvent};
+pub use message_id::MessageId;
 pub use room_name::RoomName;
+pub use status::UserStatus;
 pub use username::Username;
 
+mod assembler;
+mod capabilities;
+mod codec;
 mod command;
+mod encoding;
 mod events;
+mod message_id;
 mod room_name;
+mod status;
 mod username;
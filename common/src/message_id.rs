@@ -0,0 +1,60 @@
+use std::{fmt, num::ParseIntError, str::FromStr};
+
+use serde::{Deserialize, Serialize};
+
+#[derive(
+    Debug, Clone, Copy, Serialize, Deserialize, PartialEq, Eq, Hash, Default, PartialOrd, Ord,
+)]
+#[serde(transparent)]
+pub struct MessageId(u64);
+
+impl MessageId {
+    pub fn new(value: u64) -> Self {
+        Self(value)
+    }
+
+    pub fn as_u64(&self) -> u64 {
+        self.0
+    }
+}
+
+impl fmt::Display for MessageId {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}", self.0)
+    }
+}
+
+impl From<u64> for MessageId {
+    fn from(value: u64) -> Self {
+        Self(value)
+    }
+}
+
+impl FromStr for MessageId {
+    type Err = ParseIntError;
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        Ok(MessageId(s.parse()?))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn ids_are_plain_numbers() {
+        let id = MessageId::new(42);
+        assert_eq!(id.to_string(), "42");
+        assert_eq!("42".parse(), Ok(id));
+        assert!("-1".parse::<MessageId>().is_err());
+        assert!("#42".parse::<MessageId>().is_err());
+        assert_eq!(serde_json::to_string(&id).unwrap(), "42");
+        assert_eq!(serde_json::from_str::<MessageId>("42").unwrap(), id);
+    }
+
+    #[test]
+    fn ids_are_ordered_by_value() {
+        assert!(MessageId::new(9) < MessageId::new(10));
+        assert_eq!(MessageId::from(3).as_u64(), 3);
+    }
+}
@@ -6,6 +6,9 @@ use serde::{Deserialize, Serialize};
 pub struct RoomName(String);
 
 impl RoomName {
+    /// The name of the default room that users join on connect
+    pub const LOBBY: &'static str = "lobby";
+
     pub fn new(name: String) -> Self {
         Self(name)
     }
@@ -15,7 +18,7 @@ impl RoomName {
     }
 
     pub fn lobby() -> Self {
-        Self("lobby".to_string())
+        Self(Self::LOBBY.to_string())
     }
 }
 
@@ -61,3 +64,23 @@ impl<'a> From<&'a RoomName> for Cow<'a, str> {
         Cow::Borrowed(&value.0)
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn lobby_has_a_fixed_name() {
+        assert_eq!(RoomName::lobby().as_str(), RoomName::LOBBY);
+        assert_eq!(RoomName::from("lobby"), RoomName::lobby());
+    }
+
+    #[test]
+    fn room_names_are_plain_strings() {
+        let den = RoomName::from("den");
+        assert_eq!(serde_json::to_string(&den).unwrap(), r#""den""#);
+        assert_eq!(serde_json::from_str::<RoomName>(r#""den""#).unwrap(), den);
+        assert_eq!(den.to_string(), "den");
+        assert_eq!("den".parse(), Ok(den));
+    }
+}
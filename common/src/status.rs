@@ -0,0 +1,16 @@
+use serde::{Deserialize, Serialize};
+use strum_macros::{Display, EnumString};
+
+/// The availability of a user
+#[derive(
+    Debug, Clone, Copy, Serialize, Deserialize, Display, EnumString, PartialEq, Eq, Hash, Default,
+)]
+pub enum UserStatus {
+    #[default]
+    #[strum(to_string = "online")]
+    Online,
+    #[strum(to_string = "away")]
+    Away,
+    #[strum(to_string = "busy")]
+    Busy,
+}
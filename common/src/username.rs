@@ -1,5 +1,7 @@
 use std::{borrow::Cow, convert::Infallible, fmt, str::FromStr};
 
+use petname::{Generator, Petnames};
+use rand::RngCore;
 use serde::{Deserialize, Serialize};
 
 #[derive(Debug, Clone, Serialize, Deserialize, PartialEq, Eq, Hash, Default, PartialOrd, Ord)]
@@ -12,7 +14,14 @@ impl Username {
     }
 
     pub fn random() -> Self {
-        let username = petname::petname(1, "").expect("failed to generate petname");
+        Self::random_with(&mut rand::thread_rng())
+    }
+
+    /// Generates a random username using the given RNG
+    pub fn random_with(rng: &mut impl RngCore) -> Self {
+        let username = Petnames::default()
+            .generate(rng, 1, "")
+            .expect("failed to generate petname");
         Self(username)
     }
 
@@ -63,3 +72,31 @@ impl<'a> From<&'a Username> for Cow<'a, str> {
         Cow::Borrowed(&value.0)
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use rand::{rngs::StdRng, SeedableRng};
+
+    use super::*;
+
+    #[test]
+    fn random_names_depend_on_the_rng() {
+        let first = Username::random_with(&mut StdRng::seed_from_u64(7));
+        let second = Username::random_with(&mut StdRng::seed_from_u64(7));
+        assert_eq!(first, second);
+        assert!(!first.as_str().is_empty());
+        assert!(!first.as_str().contains(char::is_whitespace));
+    }
+
+    #[test]
+    fn usernames_are_plain_strings() {
+        let alice = Username::from("alice");
+        assert_eq!(serde_json::to_string(&alice).unwrap(), r#""alice""#);
+        assert_eq!(
+            serde_json::from_str::<Username>(r#""alice""#).unwrap(),
+            alice
+        );
+        assert_eq!(alice.to_string(), "alice");
+        assert_eq!("alice".parse(), Ok(alice));
+    }
+}
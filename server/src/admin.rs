@@ -0,0 +1,152 @@
+use std::{io, os::unix::fs::FileTypeExt, path::Path, str::FromStr};
+
+use common::{RoomName, Username};
+use futures::SinkExt;
+use itertools::Itertools;
+use tokio::net::{UnixListener, UnixStream};
+use tokio_stream::StreamExt;
+use tokio_util::codec::{Framed, LinesCodec};
+
+use crate::state::SharedState;
+
+/// The name that room events caused by the admin console are sent with
+const ADMIN_NAME: &str = "admin";
+
+/// A command of the admin console
+///
+/// Unlike the user commands, these are plain words (e.g. `close-room lobby`) that are answered
+/// with plain text instead of events.
+#[derive(Debug, Clone, PartialEq, Eq)]
+enum AdminCommand {
+    Help,
+    ListConnections,
+    Broadcast(String),
+    Kick(Username),
+    CloseRoom(RoomName),
+    Stats,
+}
+
+impl AdminCommand {
+    const USAGE: &'static str =
+        "help | list-connections | broadcast {message} | kick {name} | close-room {room} | stats";
+}
+
+impl FromStr for AdminCommand {
+    type Err = String;
+
+    fn from_str(line: &str) -> Result<Self, Self::Err> {
+        let line = line.trim();
+        let (name, rest) = match line.split_once(char::is_whitespace) {
+            Some((name, rest)) => (name, rest.trim_start()),
+            None => (line, ""),
+        };
+        let argument = |arg_name: &str| match rest {
+            "" => Err(format!("The {arg_name} is required for {name}")),
+            rest => Ok(rest),
+        };
+        // names end at the first whitespace, like in the user commands
+        let first_word = |arg_name: &str| {
+            argument(arg_name).map(|rest| rest.split_whitespace().next().unwrap_or(rest))
+        };
+        match name {
+            "help" => Ok(Self::Help),
+            "list-connections" => Ok(Self::ListConnections),
+            "broadcast" => Ok(Self::Broadcast(argument("message")?.to_string())),
+            "kick" => Ok(Self::Kick(Username::from(first_word("name")?))),
+            "close-room" => Ok(Self::CloseRoom(RoomName::from(first_word("room")?))),
+            "stats" => Ok(Self::Stats),
+            _ => Err(format!("Unknown command: {name}, try help")),
+        }
+    }
+}
+
+/// Binds the admin console to the given path, replacing a socket that was left behind
+pub fn bind(path: &Path) -> io::Result<UnixListener> {
+    match std::fs::symlink_metadata(path) {
+        Ok(metadata) if metadata.file_type().is_socket() => {
+            tracing::debug!("Removing stale admin socket {}", path.display());
+            std::fs::remove_file(path)?;
+        }
+        _ => {}
+    }
+    UnixListener::bind(path)
+}
+
+/// Accepts connections on the admin console and handles their commands
+pub async fn serve(listener: UnixListener, shared: SharedState) {
+    loop {
+        let stream = match listener.accept().await {
+            Ok((stream, _)) => stream,
+            Err(err) => {
+                tracing::error!("Failed to accept admin connection: {err}");
+                continue;
+            }
+        };
+        tracing::info!("Admin console connected");
+        let shared = shared.clone();
+        tokio::spawn(async move {
+            if let Err(err) = handle(stream, shared).await {
+                tracing::error!("Admin connection error: {err}");
+            }
+        });
+    }
+}
+
+async fn handle(stream: UnixStream, shared: SharedState) -> anyhow::Result<()> {
+    let codec = LinesCodec::new_with_max_length(shared.config.max_line_length);
+    let mut lines = Framed::new(stream, codec);
+    while let Some(line) = lines.next().await {
+        let line = line?;
+        if line.trim().is_empty() {
+            continue;
+        }
+        let response = line.parse().and_then(|command: AdminCommand| {
+            tracing::info!("Received admin command: {command:?}");
+            execute(&shared, command)
+        });
+        let response = response.unwrap_or_else(|err| format!("error: {err}"));
+        lines.send(response).await?;
+    }
+    tracing::info!("Admin console disconnected");
+    Ok(())
+}
+
+fn execute(shared: &SharedState, command: AdminCommand) -> Result<String, String> {
+    match command {
+        AdminCommand::Help => Ok(AdminCommand::USAGE.to_string()),
+        AdminCommand::ListConnections => {
+            let sessions = shared.sessions.list();
+            if sessions.is_empty() {
+                return Ok("No connections".to_string());
+            }
+            let mut connections = sessions.iter().map(|(username, addr, connected_for)| {
+                format!(
+                    "{username} {addr} connected for {}s in {}",
+                    connected_for.as_secs(),
+                    shared.rooms.memberships(username).iter().join(", ")
+                )
+            });
+            Ok(connections.join("\n"))
+        }
+        AdminCommand::Broadcast(message) => {
+            shared.announce(&message);
+            Ok("Broadcasted".to_string())
+        }
+        AdminCommand::Kick(username) => match shared.kick(&username, false) {
+            true => Ok(format!("Kicked {username}")),
+            false => Err("User not found".to_string()),
+        },
+        AdminCommand::CloseRoom(room_name) => {
+            let admin = Username::from(ADMIN_NAME);
+            shared.rooms.close(&admin, &room_name)?;
+            Ok(format!("Closed {room_name}"))
+        }
+        AdminCommand::Stats => {
+            let stats = shared.stats();
+            Ok(format!(
+                "users: {}\nrooms: {}\nconnections: {}\ndraining: {}",
+                stats.users, stats.rooms, stats.connections, stats.draining,
+            ))
+        }
+    }
+}
@@ -0,0 +1,227 @@
+use std::{
+    fs,
+    net::IpAddr,
+    path::{Path, PathBuf},
+    time::Duration,
+};
+
+use anyhow::Context;
+use clap::ValueEnum;
+use common::Command;
+use serde::Deserialize;
+
+/// What happens when a user takes a name that is already in use
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, ValueEnum, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum DuplicateNamePolicy {
+    /// The name change is refused
+    #[default]
+    Refuse,
+    /// The user that has the name is disconnected
+    Ghost,
+}
+
+/// Runtime configuration of the server
+#[derive(Clone, Debug)]
+pub struct Config {
+    /// The prefix that marks a message as a command
+    pub command_prefix: String,
+    /// The password that grants operator privileges via `/oper`
+    pub oper_password: Option<String>,
+    /// The maximum size of a transferred file in bytes (decoded)
+    pub max_file_size: usize,
+    /// How long a file transfer can go without new chunks before it is aborted (transfers never
+    /// time out if not set)
+    pub transfer_timeout: Option<Duration>,
+    /// The maximum length of a line received from a user in bytes
+    pub max_line_length: usize,
+    /// Whether to resolve the hostnames of connected users
+    pub resolve_hostnames: bool,
+    /// Whether to announce users coming back from being away
+    pub announce_returns: bool,
+    /// The seed of the random number generator (e.g. for generating usernames)
+    pub seed: Option<u64>,
+    /// The names of the commands that users are allowed to run (all if not set)
+    pub allowed_commands: Option<Vec<String>>,
+    /// How long an empty room is kept around before being deleted
+    pub idle_room_ttl: Option<Duration>,
+    /// The maximum number of users that can be connected at the same time
+    pub max_users: Option<usize>,
+    /// What happens when a user takes a name that is already in use
+    pub duplicate_names: DuplicateNamePolicy,
+    /// The maximum number of connections per minute from the same address
+    pub max_connections_per_minute: Option<usize>,
+    /// The port of the WebSocket listener (disabled if not set)
+    pub websocket_port: Option<u16>,
+    /// The maximum number of lines per second that a user can send
+    pub max_messages_per_second: Option<u32>,
+    /// The maximum number of bytes per second that a user can send
+    pub max_bytes_per_second: Option<usize>,
+    /// How long a user that exceeds a rate limit is muted for
+    pub rate_limit_mute: Duration,
+    /// The localhost port of the JSON-RPC control socket (disabled if not set)
+    pub control_port: Option<u16>,
+    /// The token that control requests have to include (if set)
+    pub control_token: Option<String>,
+    /// The path of the Unix socket of the admin console (disabled if not set)
+    #[cfg(unix)]
+    pub admin_socket: Option<PathBuf>,
+    /// How often buffered events are sent to users (every event is sent immediately if not set)
+    pub flush_interval: Option<Duration>,
+    /// The number of recent messages sent to users joining a room
+    pub history_replay: usize,
+    /// The number of recent messages that each room keeps
+    pub history_size: usize,
+    /// The message that is sent to users when they connect
+    pub motd: Option<String>,
+    /// How long after sending a message it can be edited (editing is disabled if not set)
+    pub edit_window: Option<Duration>,
+    /// How long a connection can be idle before TCP keepalive probes are sent
+    pub keepalive_idle: Option<Duration>,
+    /// The interval between TCP keepalive probes (where supported by the platform)
+    pub keepalive_interval: Duration,
+    /// The interval between the pings that are sent to the users that sent `/hello` (pings are
+    /// disabled if not set)
+    pub heartbeat_interval: Option<Duration>,
+    /// The number of unanswered pings after which a user is disconnected
+    pub max_missed_heartbeats: u32,
+    /// The directory that the rooms are saved to (they are not saved if not set)
+    pub data_dir: Option<PathBuf>,
+    /// The minimum time between saving the rooms after they changed
+    pub save_interval: Duration,
+    /// How long to wait for the connections to close when shutting down
+    pub shutdown_grace_period: Duration,
+    /// The PEM file with the TLS certificate chain (TLS is disabled if not set)
+    pub tls_cert: Option<PathBuf>,
+    /// The PEM file with the private key of the TLS certificate
+    pub tls_key: Option<PathBuf>,
+}
+
+impl Config {
+    /// Returns whether connections are encrypted with TLS
+    pub fn is_tls_enabled(&self) -> bool {
+        self.tls_cert.is_some() && self.tls_key.is_some()
+    }
+
+    /// Returns whether the command with the given name (without the slash) is allowed
+    ///
+    /// `/help` and `/quit` are always allowed, and so are `/hello` and `/pong` since clients send
+    /// them on their own.
+    pub fn is_command_allowed(&self, name: &str) -> bool {
+        match &self.allowed_commands {
+            Some(allowed) => {
+                matches!(
+                    name,
+                    Command::HELP | Command::QUIT | Command::HELLO | Command::PONG
+                ) || allowed.iter().any(|command| command == name)
+            }
+            None => true,
+        }
+    }
+}
+
+/// The settings that can be given in a TOML file with `--config`
+///
+/// The keys are the same as the command line flags (e.g. `max-users = 100`) and the flags that
+/// are given on the command line take precedence over the file.
+#[derive(Clone, Debug, Default, Deserialize)]
+#[serde(deny_unknown_fields, rename_all = "kebab-case")]
+pub struct ServerConfig {
+    pub ip: Option<IpAddr>,
+    pub port: Option<u16>,
+    pub oper_password: Option<String>,
+    pub max_file_size: Option<usize>,
+    pub transfer_timeout: Option<u64>,
+    pub max_line_length: Option<usize>,
+    pub resolve_hostnames: Option<bool>,
+    pub announce_returns: Option<bool>,
+    pub command_prefix: Option<String>,
+    pub seed: Option<u64>,
+    pub allowed_commands: Option<Vec<String>>,
+    pub idle_room_ttl: Option<u64>,
+    pub max_users: Option<usize>,
+    pub duplicate_names: Option<DuplicateNamePolicy>,
+    pub max_connections_per_minute: Option<usize>,
+    pub max_messages_per_second: Option<u32>,
+    pub max_bytes_per_second: Option<usize>,
+    pub rate_limit_mute: Option<u64>,
+    pub websocket_port: Option<u16>,
+    pub control_port: Option<u16>,
+    pub control_token: Option<String>,
+    #[cfg(unix)]
+    pub admin_socket: Option<PathBuf>,
+    pub flush_interval: Option<u64>,
+    pub history_replay: Option<usize>,
+    pub history_size: Option<usize>,
+    pub motd: Option<String>,
+    pub edit_window: Option<u64>,
+    pub keepalive_idle: Option<u64>,
+    pub keepalive_interval: Option<u64>,
+    pub heartbeat_interval: Option<u64>,
+    pub max_missed_heartbeats: Option<u32>,
+    pub data_dir: Option<PathBuf>,
+    pub save_interval: Option<u64>,
+    pub shutdown_grace_period: Option<u64>,
+    pub cert: Option<PathBuf>,
+    pub key: Option<PathBuf>,
+}
+
+impl ServerConfig {
+    pub fn load(path: &Path) -> anyhow::Result<Self> {
+        let contents = fs::read_to_string(path)
+            .with_context(|| format!("Failed to read the config file {}", path.display()))?;
+        toml::from_str(&contents)
+            .with_context(|| format!("Failed to parse the config file {}", path.display()))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_utils::config;
+
+    #[test]
+    fn files_use_the_names_of_the_flags() {
+        let file: ServerConfig = toml::from_str(
+            r#"
+            port = 4242
+            max-users = 100
+            duplicate-names = "ghost"
+            allowed-commands = ["join", "users"]
+            motd = "Welcome!"
+            "#,
+        )
+        .unwrap();
+        assert_eq!(file.port, Some(4242));
+        assert_eq!(file.max_users, Some(100));
+        assert_eq!(file.duplicate_names, Some(DuplicateNamePolicy::Ghost));
+        assert_eq!(
+            file.allowed_commands.as_deref(),
+            Some(["join".to_string(), "users".to_string()].as_slice())
+        );
+        assert_eq!(file.motd.as_deref(), Some("Welcome!"));
+        assert_eq!(file.ip, None);
+    }
+
+    #[test]
+    fn unknown_keys_are_rejected() {
+        let err = toml::from_str::<ServerConfig>("max_users = 100").unwrap_err();
+        assert!(err.message().contains("unknown field `max_users`"), "{err}");
+        assert!(toml::from_str::<ServerConfig>("max-users = -1").is_err());
+    }
+
+    #[test]
+    fn protocol_commands_are_always_allowed() {
+        let config = config(&["--allowed-commands", "join,users"]);
+        for name in ["join", "users", "help", "quit", "hello", "pong"] {
+            assert!(config.is_command_allowed(name), "{name}");
+        }
+        assert!(!config.is_command_allowed("nudge"));
+    }
+
+    #[test]
+    fn every_command_is_allowed_without_a_list() {
+        let config = config(&[]);
+        assert!(config.is_command_allowed("nudge"));
+    }
+}
@@ -1,34 +1,116 @@
-use std::net::SocketAddr;
+use std::{
+    collections::{HashMap, VecDeque},
+    net::SocketAddr,
+    sync::Arc,
+};
 
 use anyhow::Context;
-use common::{Command, RoomEvent, RoomName, ServerEvent, Username};
-use futures::SinkExt;
-use tokio::{net::TcpStream, sync::broadcast::Receiver};
-use tokio_stream::StreamExt;
-use tokio_util::codec::{Framed, LinesCodec};
+use common::{
+    Capabilities, Codec, Command, QuotaKind, RoomEvent, RoomName, ServerEvent, UserStatus,
+    Username, PROTOCOL_VERSION,
+};
+use futures::{
+    stream::{SplitSink, SplitStream},
+    SinkExt,
+};
+use itertools::Itertools;
+use tokio::{
+    sync::{
+        broadcast::{error::RecvError, Receiver},
+        mpsc,
+    },
+    task::JoinHandle,
+    time::{self, Duration, MissedTickBehavior},
+};
+use tokio_stream::{
+    wrappers::{errors::BroadcastStreamRecvError, BroadcastStream},
+    StreamExt, StreamMap,
+};
+use tokio_util::{codec::LinesCodecError, sync::CancellationToken};
 use tracing::instrument;
 
-use crate::{room::Room, rooms::Rooms, server::COMMANDS, users::Users};
+use crate::{
+    config::{Config, DuplicateNamePolicy},
+    hostnames::Hostnames,
+    room::Room,
+    rooms::Rooms,
+    server::{self, CAPABILITIES},
+    sessions::SessionSignals,
+    state::SharedState,
+    throttle::{MessageThrottle, Verdict},
+    transfers::Transfers,
+    transport::{Frame, Transport},
+    users::Users,
+};
 
-pub struct Connection {
+/// A connected user, talking over the transport `T`
+pub struct Connection<T> {
     /// The events that are come from the user
-    user_events: Framed<TcpStream, LinesCodec>,
+    user_events: SplitStream<T>,
+    /// The events that are queued to be written to the user by the writer task
+    outgoing: mpsc::Sender<ServerEvent>,
+    /// The task that writes the queued events to the user
+    writer: JoinHandle<()>,
     /// The events that are broadcasted to all users
     server_events: Receiver<ServerEvent>,
-    /// The events that are broadcasted to the user's current room
-    room_events: Receiver<ServerEvent>,
+    /// The events that are broadcasted to the rooms that the user is in
+    room_events: StreamMap<RoomName, BroadcastStream<ServerEvent>>,
     /// The users that are connected to the server
     users: Users,
     /// The rooms that are available on the server
     rooms: Rooms,
+    /// The configuration of the server
+    config: Arc<Config>,
+    /// The resolved hostnames of the connected users
+    hostnames: Hostnames,
+    /// The state that is shared with the server
+    shared: SharedState,
     /// The username of the connected user
     username: Username,
     /// The address of the connected user
     addr: SocketAddr,
     /// The current state of the connection
     state: ConnectionState,
-    /// The room that the user is currently in
+    /// The rooms that the user is in
+    memberships: HashMap<RoomName, Room>,
+    /// The names of the rooms that the user is in, the most recently joined one last
+    joined_rooms: Vec<RoomName>,
+    /// The room that the user is currently talking in
     room: Room,
+    /// Whether the user has operator privileges
+    is_operator: bool,
+    /// The previous usernames of the user, the most recent one last
+    previous_names: VecDeque<Username>,
+    /// The message that is sent to the rooms of the user when they quit
+    farewell: Option<String>,
+    /// The signals from and to the rest of the server
+    signals: SessionSignals,
+    /// The number of events that were skipped because the user could not keep up
+    dropped_events: u64,
+    /// Limits how fast the user can send messages
+    throttle: MessageThrottle,
+    /// The files that the user is sending in chunks
+    transfers: Transfers,
+    /// The features that are used with the user, all of the server's until they announce theirs
+    capabilities: Capabilities,
+    /// The id of the next ping that is sent to the user
+    next_ping_id: u64,
+    /// The number of pings that were sent since the user last sent anything
+    unanswered_pings: u32,
+    /// Whether the client announced itself with `/hello`, only such clients are pinged
+    negotiated: bool,
+    /// The name that the user asked for while its ghosted owner disconnects
+    pending_name: Option<PendingName>,
+}
+
+/// A name that is claimed once the user that has it is gone (see `claim_name`)
+#[derive(Debug)]
+struct PendingName {
+    name: Username,
+    /// Cancelled once the ghosted user is gone
+    released: CancellationToken,
+    /// When to give up on the name
+    deadline: time::Instant,
 }
 
 #[derive(Clone, Copy, Debug, PartialEq, Eq)]
@@ -37,74 +119,233 @@ enum ConnectionState {
     Disconnected,
 }
 
-impl Connection {
+impl<T: Transport> Connection<T> {
+    /// The maximum number of previous usernames that are remembered
+    const NAME_HISTORY_CAPACITY: usize = 10;
+    /// The maximum number of events that are queued to be written to the user
+    const OUTGOING_CAPACITY: usize = 1024;
+    /// How long the queued events are written for after the connection ends
+    const WRITER_TIMEOUT: Duration = Duration::from_secs(10);
+
+    /// Creates the connection of a user whose name is already registered in [`Users`]
     pub fn new(
-        tcp: TcpStream,
+        transport: T,
         server_events: Receiver<ServerEvent>,
-        users: Users,
-        rooms: Rooms,
+        shared: SharedState,
+        username: Username,
         addr: SocketAddr,
     ) -> Self {
-        let username = Username::random();
         tracing::info!("{addr} connected with the name: {username}");
-        let user_events = Framed::new(tcp, LinesCodec::new());
-        let (room, room_events) = rooms.join(&username, &RoomName::lobby());
+        if shared.config.resolve_hostnames {
+            shared.hostnames.resolve(addr.ip());
+        }
+        let (sink, user_events) = futures::StreamExt::split(transport);
+        let (outgoing, events) = mpsc::channel(Self::OUTGOING_CAPACITY);
+        let writer = tokio::spawn(write_events(sink, events, shared.config.flush_interval));
+        let signals = shared.sessions.insert(&username, addr, outgoing.clone());
+        let (room, events) = shared
+            .rooms
+            .join(&username, &RoomName::lobby(), None)
+            .expect("the lobby is never locked");
+        let mut room_events = StreamMap::new();
+        room_events.insert(room.name(), BroadcastStream::new(events));
+        let capabilities = Capabilities {
+            tls: shared.config.is_tls_enabled(),
+            ..CAPABILITIES
+        };
+        let throttle = MessageThrottle::new(
+            shared.config.max_messages_per_second,
+            shared.config.max_bytes_per_second,
+            shared.config.rate_limit_mute,
+        );
         Self {
             user_events,
+            outgoing,
+            writer,
             server_events,
             room_events,
-            users,
-            rooms,
+            users: shared.users.clone(),
+            rooms: shared.rooms.clone(),
+            config: shared.config.clone(),
+            hostnames: shared.hostnames.clone(),
+            shared,
             username,
             addr,
             state: ConnectionState::Connected,
+            memberships: HashMap::from([(room.name(), room.clone())]),
+            joined_rooms: vec![room.name()],
             room,
+            is_operator: false,
+            previous_names: VecDeque::new(),
+            farewell: None,
+            signals,
+            dropped_events: 0,
+            throttle,
+            transfers: Transfers::default(),
+            capabilities,
+            next_ping_id: 0,
+            unanswered_pings: 0,
+            negotiated: false,
+            pending_name: None,
         }
     }
 
     async fn send_event(&mut self, event: ServerEvent) {
         tracing::debug!(?event, "Sending event");
-        if let Err(err) = self.user_events.send(event.as_json_str()).await {
-            tracing::error!("Failed to send event: {err}");
+        // waits for the writer to catch up if the queue is full
+        if self.outgoing.send(event).await.is_err() {
+            tracing::error!("Failed to send event, the writer has stopped");
             self.state = ConnectionState::Disconnected;
         }
     }
 
     #[instrument(skip(self), fields(addr = %self.addr, username = %self.username))]
-    pub async fn handle(&mut self) {
-        let help = ServerEvent::help(&self.username, COMMANDS);
+    pub async fn handle(mut self) {
+        let hello = ServerEvent::hello(env!("CARGO_PKG_VERSION"), self.capabilities);
+        self.send_event(hello).await;
+
+        let help = ServerEvent::help(&self.username, &server::commands_help(&self.config));
         self.send_event(help).await;
 
+        if let Some(motd) = &self.config.motd {
+            self.send_event(ServerEvent::Motd(motd.clone())).await;
+        }
+
         let rooms = self.rooms.list();
         self.send_event(ServerEvent::rooms(rooms)).await;
 
         let users = self.room.list_users();
         self.send_event(ServerEvent::users(users)).await;
+        self.send_history().await;
 
         if let Err(err) = self.run().await {
             tracing::error!("Connection error: {err}");
         }
-
-        self.rooms.leave(&self.username, &self.room);
+        for id in self.transfers.ids() {
+            self.abort_transfer(id);
+        }
+        for room in self.memberships.values() {
+            self.rooms
+                .leave(&self.username, room, self.farewell.as_deref());
+        }
+        self.shared.presence.remove(&self.username);
+        self.shared.sessions.remove(&self.username);
+        // the name is released last since it might be taken over right away (see `claim_name`)
         self.users.remove(&self.username);
-        tracing::info!("disconnected");
+        self.signals.released.cancel();
+        if self.dropped_events > 0 {
+            tracing::info!("Skipped {} events in total", self.dropped_events);
+        }
+        match self.hostnames.get(&self.addr.ip()) {
+            Some(hostname) => tracing::info!(%hostname, "disconnected"),
+            None => tracing::info!("disconnected"),
+        }
+        // let the writer send the remaining events before closing the connection, unless the
+        // user stopped reading them
+        drop(self.outgoing);
+        match time::timeout(Self::WRITER_TIMEOUT, &mut self.writer).await {
+            Ok(Ok(())) => {}
+            Ok(Err(err)) => tracing::error!("Writer task failed: {err}"),
+            Err(_) => {
+                tracing::warn!("Dropping the remaining events, the user stopped reading them");
+                self.writer.abort();
+            }
+        }
     }
 
     async fn run(&mut self) -> anyhow::Result<()> {
+        let mut resyncing = false;
+        // plain text clients (e.g. netcat) would not answer the pings
+        let heartbeat_interval = self.config.heartbeat_interval;
+        let period = heartbeat_interval.unwrap_or(Duration::from_secs(1));
+        let mut heartbeat = time::interval_at(time::Instant::now() + period, period);
+        heartbeat.set_missed_tick_behavior(MissedTickBehavior::Delay);
         while self.state == ConnectionState::Connected {
+            let transfer_timeout = self.config.transfer_timeout.unwrap_or_default();
+            let transfer_deadline = self
+                .config
+                .transfer_timeout
+                .and_then(|timeout| self.transfers.deadline(timeout));
             tokio::select! {
-                Some(message) = self.user_events.next() => {
-                    let message = message.context("failed to read from stream")?;
-                    self.handle_message(message).await;
+                message = self.user_events.next() => match message {
+                    Some(Ok(message)) => {
+                        // over WebSocket, no `None` comes between a discarded message and the next
+                        resyncing = false;
+                        self.unanswered_pings = 0;
+                        self.handle_message(message).await
+                    }
+                    Some(Err(LinesCodecError::MaxLineLengthExceeded)) => {
+                        tracing::warn!("Discarding message exceeding the maximum line length");
+                        let limit = self.config.max_line_length;
+                        let event = ServerEvent::quota_exceeded(QuotaKind::LineLength, limit);
+                        self.send_event(event).await;
+                        // the stream yields `None` once after a decoding error
+                        resyncing = true;
+                    }
+                    Some(Err(err)) => return Err(err).context("failed to read from stream"),
+                    None if resyncing => resyncing = false,
+                    None => {
+                        tracing::debug!("Connection closed by the user");
+                        break;
+                    }
                 },
-                event = self.room_events.recv() => {
-                    let event = event.context("failed to read from room events")?;
-                    self.send_event(event).await;
+                Some((room_name, event)) = self.room_events.next() => match event {
+                    Ok(event) => {
+                        if let ServerEvent::RoomEvent {
+                            event: RoomEvent::Renamed { from, to },
+                            ..
+                        } = &event
+                        {
+                            self.rename_membership(from, to);
+                        }
+                        if !self.is_supported(&event) {
+                            continue;
+                        }
+                        let removed = match &event {
+                            ServerEvent::RoomEvent {
+                                event: RoomEvent::Kicked(target) | RoomEvent::Banned(target),
+                                ..
+                            } => target == &self.username,
+                            ServerEvent::RoomEvent {
+                                event: RoomEvent::Closed,
+                                ..
+                            } => true,
+                            _ => false,
+                        };
+                        self.send_event(event).await;
+                        if removed {
+                            self.leave_after_removal(&room_name);
+                        }
+                    }
+                    Err(BroadcastStreamRecvError::Lagged(count)) => {
+                        self.record_lag(&format!("{room_name} room"), count);
+                    }
                 },
-                event = self.server_events.recv() => {
-                    let event = event.context("failed to read from server events")?;
-                    self.send_event(event).await;
+                event = self.server_events.recv() => match event {
+                    Ok(event) => self.handle_server_event(event).await,
+                    Err(RecvError::Lagged(count)) => self.record_lag("server", count),
+                    Err(err) => return Err(err).context("failed to read from server events"),
                 },
+                _ = heartbeat.tick(), if heartbeat_interval.is_some() && self.negotiated => {
+                    self.send_ping().await
+                }
+                released = wait_for_name(self.pending_name.as_ref()),
+                    if self.pending_name.is_some() =>
+                {
+                    self.finish_name_claim(released).await
+                }
+                _ = time::sleep_until(transfer_deadline.unwrap_or_else(time::Instant::now)),
+                    if transfer_deadline.is_some() =>
+                {
+                    self.expire_transfers(transfer_timeout).await
+                }
+                _ = self.signals.disconnect.cancelled() => {
+                    tracing::info!("Disconnecting, the name was taken over by another user");
+                    let message = "Your name was taken over by another connection";
+                    self.send_event(ServerEvent::error(message)).await;
+                    self.send_event(ServerEvent::Disconnect).await;
+                    break;
+                }
                 else => {
                     tracing::error!("Connection closed");
                     break;
@@ -114,20 +355,301 @@ impl Connection {
         Ok(())
     }
 
+    /// Pings the user, or disconnects them if they missed too many pings
+    async fn send_ping(&mut self) {
+        if self.unanswered_pings >= self.config.max_missed_heartbeats {
+            tracing::warn!(
+                "Disconnecting the user, they did not answer {} pings",
+                self.unanswered_pings
+            );
+            self.state = ConnectionState::Disconnected;
+            return;
+        }
+        self.send_event(ServerEvent::Ping(self.next_ping_id)).await;
+        self.next_ping_id += 1;
+        self.unanswered_pings += 1;
+    }
+
+    /// Registers the given name for the user, returning whether it was available or is pending
+    ///
+    /// With the [`Ghost`](DuplicateNamePolicy::Ghost) policy, the user that has the name is
+    /// disconnected and the name becomes pending. It is claimed by `finish_name_claim` once they
+    /// are gone, without holding up the other events of the connection in the meantime.
+    fn claim_name(&mut self, name: &Username) -> bool {
+        const GHOST_TIMEOUT: Duration = Duration::from_secs(5);
+        // a new name replaces the one that is still pending
+        self.pending_name = None;
+        if self.users.insert(name) {
+            return true;
+        }
+        if self.config.duplicate_names == DuplicateNamePolicy::Refuse || name == &self.username {
+            return false;
+        }
+        let Some(released) = self.shared.sessions.disconnect(name) else {
+            return false;
+        };
+        tracing::info!("Ghosting the existing user {name}");
+        self.pending_name = Some(PendingName {
+            name: name.clone(),
+            released,
+            deadline: time::Instant::now() + GHOST_TIMEOUT,
+        });
+        true
+    }
+
+    /// Claims the pending name if its ghosted owner is gone
+    async fn finish_name_claim(&mut self, released: bool) {
+        let Some(PendingName { name, .. }) = self.pending_name.take() else {
+            return;
+        };
+        // someone else might take the name before this connection does
+        if released && self.users.insert(&name) {
+            self.rename(name);
+        } else {
+            let message = format!("{name} is already taken");
+            self.send_event(ServerEvent::error(&message)).await;
+        }
+    }
+
+    /// Renames the user to the given name, which has to be claimed already
+    fn rename(&mut self, new_name: Username) {
+        for room in self.memberships.values() {
+            room.change_user_name(&self.username, &new_name);
+        }
+        self.shared.presence.rename(&self.username, &new_name);
+        self.shared.sessions.rename(&self.username, &new_name);
+        self.users.remove(&self.username);
+        let old_name = std::mem::replace(&mut self.username, new_name);
+        self.previous_names.push_back(old_name);
+        if self.previous_names.len() > Self::NAME_HISTORY_CAPACITY {
+            self.previous_names.pop_front();
+        }
+    }
+
+    async fn handle_server_event(&mut self, event: ServerEvent) {
+        let kicked = match &event {
+            ServerEvent::Kicked { username, banned } if username == &self.username => Some(*banned),
+            _ => None,
+        };
+        let shutdown = matches!(event, ServerEvent::ServerShutdown { .. });
+        self.send_event(event).await;
+        if shutdown {
+            tracing::info!("Disconnecting for the server shutdown");
+            self.state = ConnectionState::Disconnected;
+        }
+        if let Some(banned) = kicked {
+            tracing::info!(banned, "Kicked from the server");
+            if banned {
+                self.shared.banned.insert(self.addr.ip());
+            }
+            self.state = ConnectionState::Disconnected;
+        }
+    }
+
+    /// Keeps track of the events that were skipped because the user could not keep up
+    fn record_lag(&mut self, source: &str, count: u64) {
+        self.dropped_events += count;
+        tracing::warn!(
+            total = self.dropped_events,
+            "Skipped {count} {source} events, the user is lagging behind"
+        );
+    }
+
+    /// Joins the given room (if not already in it) and makes it the current room
+    ///
+    /// Returns whether the room was newly joined, the password is only checked in that case.
+    fn join(&mut self, room_name: &RoomName, password: Option<&str>) -> Result<bool, &'static str> {
+        if let Some(room) = self.memberships.get(room_name) {
+            self.room = room.clone();
+            return Ok(false);
+        }
+        let (room, events) = self.rooms.join(&self.username, room_name, password)?;
+        let (status, message) = self.shared.presence.get(&self.username);
+        if status != UserStatus::Online {
+            room.send_event(&self.username, RoomEvent::status_changed(status, message));
+        }
+        self.room_events
+            .insert(room_name.clone(), BroadcastStream::new(events));
+        self.memberships.insert(room_name.clone(), room.clone());
+        self.joined_rooms.push(room_name.clone());
+        self.room = room;
+        Ok(true)
+    }
+
+    /// Sets the status of the user and broadcasts it to their rooms if it changed
+    ///
+    /// Only actual changes are broadcast, e.g. repeating the same away message is not.
+    fn set_status(&self, status: UserStatus, message: Option<String>) {
+        if !self
+            .shared
+            .presence
+            .set(&self.username, status, message.clone())
+        {
+            return;
+        }
+        let returned = status == UserStatus::Online && self.config.announce_returns;
+        for room in self.memberships.values() {
+            let event = RoomEvent::status_changed(status, message.clone());
+            room.send_event(&self.username, event);
+            if returned {
+                room.send_event(&self.username, RoomEvent::Back);
+            }
+        }
+    }
+
+    /// Sends the most recent messages of the current room for context
+    async fn send_history(&mut self) {
+        let count = self.config.history_replay;
+        if count > 0 {
+            let history = self.room.history(count);
+            self.send_event(history).await;
+        }
+    }
+
+    /// Leaves the given room, switching to the most recently joined room if it was the current one
+    fn leave(&mut self, room_name: &RoomName) -> Result<(), &'static str> {
+        if !self.memberships.contains_key(room_name) {
+            return Err("You are not in that room");
+        }
+        if self.memberships.len() == 1 {
+            return Err("You cannot leave your only room");
+        }
+        if let Some(room) = self.memberships.remove(room_name) {
+            self.room_events.remove(room_name);
+            self.joined_rooms.retain(|name| name != room_name);
+            self.rooms.leave(&self.username, &room, None);
+        }
+        if &self.room.name() == room_name {
+            let last_joined = self.joined_rooms.last();
+            if let Some(room) = last_joined.and_then(|name| self.memberships.get(name)) {
+                self.room = room.clone();
+            }
+        }
+        Ok(())
+    }
+
+    /// Leaves a room that the user was kicked or banned from, or that was closed
+    ///
+    /// The user is moved to the lobby if it was their only room.
+    fn leave_after_removal(&mut self, room_name: &RoomName) {
+        tracing::info!("Removed from room {room_name}");
+        if self.memberships.len() == 1 {
+            if let Err(err) = self.join(&RoomName::lobby(), None) {
+                tracing::error!("Failed to move to the lobby: {err}");
+            }
+        }
+        if let Err(err) = self.leave(room_name) {
+            tracing::error!("Failed to leave room {room_name}: {err}");
+        }
+    }
+
+    /// Returns whether the given event can be sent with the negotiated capabilities
+    fn is_supported(&self, event: &ServerEvent) -> bool {
+        match event {
+            ServerEvent::RoomEvent { event, .. } => match event {
+                RoomEvent::FileStart { .. }
+                | RoomEvent::FileChunk { .. }
+                | RoomEvent::FileEnd { .. }
+                | RoomEvent::FileAborted { .. } => self.capabilities.file_chunking,
+                RoomEvent::Reaction { .. } => self.capabilities.reactions,
+                _ => true,
+            },
+            _ => true,
+        }
+    }
+
+    /// Cancels the file transfers that stopped receiving chunks
+    async fn expire_transfers(&mut self, timeout: Duration) {
+        for id in self.transfers.expired(timeout) {
+            tracing::warn!("Aborting file transfer {id}: no chunks for {timeout:?}");
+            self.abort_transfer(id);
+            let message = format!("File transfer {id} timed out");
+            self.send_event(ServerEvent::error(&message)).await;
+        }
+    }
+
+    /// Cancels a chunked file transfer, letting the room know about it
+    fn abort_transfer(&mut self, id: u64) {
+        if let Some(room) = self.transfers.abort(id) {
+            room.send_event(&self.username, RoomEvent::file_aborted(id));
+        }
+    }
+
+    /// Checks whether the user can moderate the given user in the current room
+    ///
+    /// Server operators can moderate every room, including its owner.
+    fn check_moderation(&self, target: &Username) -> Result<(), &'static str> {
+        if !self.is_operator && !self.room.is_moderator(&self.username) {
+            return Err("Only the moderators of the room can do that");
+        }
+        if target == &self.username {
+            return Err("You cannot moderate yourself");
+        }
+        if !self.is_operator && self.room.is_owner(target) {
+            return Err("The owner of the room cannot be moderated");
+        }
+        Ok(())
+    }
+
+    /// Keeps track of a room that the user is in under its new name
+    fn rename_membership(&mut self, old_name: &RoomName, new_name: &RoomName) {
+        if let Some(room) = self.memberships.remove(old_name) {
+            self.memberships.insert(new_name.clone(), room);
+        }
+        for name in &mut self.joined_rooms {
+            if name == old_name {
+                *name = new_name.clone();
+            }
+        }
+        if let Some(events) = self.room_events.remove(old_name) {
+            self.room_events.insert(new_name.clone(), events);
+        }
+    }
+
     async fn handle_message(&mut self, message: String) {
-        if !message.starts_with("/") {
+        let prefix = self.config.command_prefix.clone();
+        let command = match message.strip_prefix(prefix.as_str()) {
+            // a doubled prefix escapes it
+            Some(rest) if rest.starts_with(prefix.as_str()) => None,
+            Some(rest) => Some(Command::try_from(format!("/{rest}"))),
+            None => None,
+        };
+        // the replies to pings and the chunks of announced files are part of the protocol
+        let is_exempt = match &command {
+            Some(Ok(Command::Pong(_))) => true,
+            Some(Ok(Command::FileChunk(id, ..))) => self.transfers.contains(*id),
+            _ => false,
+        };
+        if !is_exempt {
+            match self.throttle.check(message.len()) {
+                Verdict::Allowed => {}
+                Verdict::Muted(duration) => {
+                    tracing::warn!("Muting the user for {duration:?}, they are sending too fast");
+                    self.send_event(ServerEvent::rate_limited(duration)).await;
+                    return;
+                }
+                Verdict::StillMuted => {
+                    tracing::debug!("Dropping message from muted user");
+                    return;
+                }
+            }
+        }
+        let Some(command) = command else {
+            let message = message.strip_prefix(prefix.as_str()).unwrap_or(&message);
             tracing::info!("Received message: {:?}", message);
-            self.room.send_message(&self.username, &message);
+            if let Err(err) = self.room.send_message(&self.username, message) {
+                self.send_event(ServerEvent::error(err)).await;
+            }
             return;
-        }
-        match Command::try_from(message) {
+        };
+        match command {
             Ok(command) => {
                 self.log_command(&command);
                 self.handle_command(command).await
             }
             Err(err) => {
                 tracing::error!("Invalid command: {err}");
-                let event = ServerEvent::error(&format!("{err}, try /help"));
+                let event = ServerEvent::error(&format!("{err}, try {prefix}help"));
                 self.send_event(event).await;
             }
         }
@@ -137,59 +659,1454 @@ impl Connection {
         if let Command::SendFile(filename, contents) = &command {
             tracing::info!("Received file: {filename}");
             tracing::trace!("Received file contents: {contents}");
+        } else if let Command::FileChunk(id, index, total, contents) = &command {
+            tracing::debug!("Received chunk {index}/{total} of file transfer {id}");
+            tracing::trace!("Received file contents: {contents}");
+        } else if let Command::Pong(id) = &command {
+            tracing::debug!("Received pong {id}");
+        } else if let Command::Oper(_) = &command {
+            tracing::info!("Received command: Oper");
+        } else if let Command::Join(room_name, Some(_)) = &command {
+            tracing::info!("Received command: Join({room_name}) with a password");
         } else {
             tracing::info!("Received command: {command:?}");
         }
     }
 
     async fn handle_command(&mut self, command: Command) {
+        if !self.config.is_command_allowed(command.name()) {
+            let message = format!("/{} is disabled on this server", command.name());
+            self.send_event(ServerEvent::error(&message)).await;
+            return;
+        }
         match command {
+            Command::Hello(version, _) if version != PROTOCOL_VERSION => {
+                tracing::warn!("Disconnecting client with unsupported protocol version {version}");
+                let message = format!(
+                    "Unsupported protocol version {version}, \
+                     the server speaks version {PROTOCOL_VERSION}"
+                );
+                self.send_event(ServerEvent::error(&message)).await;
+                self.send_event(ServerEvent::Disconnect).await;
+                self.state = ConnectionState::Disconnected;
+            }
+            Command::Hello(_, capabilities) => {
+                self.negotiated = true;
+                self.capabilities = self.capabilities.intersection(&capabilities);
+                tracing::debug!(capabilities = ?self.capabilities, "Negotiated capabilities");
+                let event = ServerEvent::Capabilities(self.capabilities);
+                self.send_event(event).await;
+            }
             Command::Help => {
-                let help = ServerEvent::help(&self.username, COMMANDS);
+                let help = ServerEvent::help(&self.username, &server::commands_help(&self.config));
                 self.send_event(help).await;
             }
             Command::ChangeUsername(new_name) => {
-                let changed_name = self.users.insert(&new_name);
-                if changed_name {
-                    self.room.change_user_name(&self.username, &new_name);
-                    self.username = new_name;
-                } else {
+                if !self.claim_name(&new_name) {
                     let message = format!("{new_name} is already taken");
                     self.send_event(ServerEvent::error(&message)).await;
+                } else if self.pending_name.is_none() {
+                    self.rename(new_name);
                 }
             }
-            Command::Join(new_room) => {
-                (self.room, self.room_events) =
-                    self.rooms.change(&self.username, &self.room, &new_room);
-                let users = self.room.list_users();
-                self.send_event(ServerEvent::users(users)).await;
+            Command::NameHistory => {
+                let names = self.previous_names.iter().rev().cloned().collect();
+                self.send_event(ServerEvent::name_history(names)).await;
+            }
+            Command::Join(new_room, _) if new_room == self.room.name() => {
+                let event = ServerEvent::error("You are already in that room");
+                self.send_event(event).await;
+            }
+            Command::Join(new_room, password) => match self.join(&new_room, password.as_deref()) {
+                Ok(joined) => {
+                    let users = self.room.list_users();
+                    self.send_event(ServerEvent::users(users)).await;
+                    if joined {
+                        self.send_history().await;
+                    }
+                }
+                Err(reason) => {
+                    tracing::debug!("Denied joining room {new_room}: {reason}");
+                    let event = ServerEvent::join_denied(&new_room, reason);
+                    self.send_event(event).await;
+                }
+            },
+            Command::Leave(room_name) => {
+                if let Err(err) = self.leave(&room_name) {
+                    self.send_event(ServerEvent::error(err)).await;
+                }
+            }
+            Command::RenameRoom(new_name) => {
+                let result = if self.room.is_owner(&self.username) {
+                    self.rooms.rename(&self.username, &self.room, &new_name)
+                } else {
+                    Err("Only the owner of the room can rename it")
+                };
+                if let Err(err) = result {
+                    self.send_event(ServerEvent::error(err)).await;
+                }
+            }
+            Command::ClearRoom => {
+                if self.room.is_owner(&self.username) {
+                    self.room.clear_history(&self.username);
+                } else {
+                    let event = ServerEvent::error("Only the owner of the room can clear it");
+                    self.send_event(event).await;
+                }
+            }
+            Command::Op(target) => {
+                let result = if self.room.is_owner(&self.username) || self.is_operator {
+                    self.room.op(&self.username, &target)
+                } else {
+                    Err("Only the owner of the room can make moderators")
+                };
+                if let Err(err) = result {
+                    self.send_event(ServerEvent::error(err)).await;
+                }
+            }
+            Command::Kick(target) | Command::Ban(target) if self.room.is_lobby() => {
+                tracing::debug!("Refusing to remove {target} from the lobby");
+                let event = ServerEvent::error("Users cannot be removed from the lobby");
+                self.send_event(event).await;
+            }
+            Command::Kick(target) => {
+                let result = self
+                    .check_moderation(&target)
+                    .and_then(|_| self.room.kick(&self.username, &target));
+                if let Err(err) = result {
+                    self.send_event(ServerEvent::error(err)).await;
+                }
+            }
+            Command::Ban(target) => {
+                let result = self
+                    .check_moderation(&target)
+                    .and_then(|_| self.room.ban(&self.username, &target));
+                if let Err(err) = result {
+                    self.send_event(ServerEvent::error(err)).await;
+                }
+            }
+            Command::Unban(target) => {
+                let result = self
+                    .check_moderation(&target)
+                    .and_then(|_| self.room.unban(&self.username, &target));
+                if let Err(err) = result {
+                    self.send_event(ServerEvent::error(err)).await;
+                }
+            }
+            Command::Mute(target) => {
+                let result = self
+                    .check_moderation(&target)
+                    .and_then(|_| self.room.mute(&self.username, &target));
+                if let Err(err) = result {
+                    self.send_event(ServerEvent::error(err)).await;
+                }
+            }
+            Command::Unmute(target) => {
+                let result = self
+                    .check_moderation(&target)
+                    .and_then(|_| self.room.unmute(&self.username, &target));
+                if let Err(err) = result {
+                    self.send_event(ServerEvent::error(err)).await;
+                }
             }
             Command::ListRooms => {
                 let rooms_list = self.rooms.list();
                 self.send_event(ServerEvent::rooms(rooms_list)).await;
             }
+            Command::ListMemberships => {
+                let rooms = self.memberships.keys().cloned().sorted().collect();
+                let event = ServerEvent::memberships(rooms, &self.room.name());
+                self.send_event(event).await;
+            }
             Command::ListUsers => {
                 let users = self.room.list_users();
                 self.send_event(ServerEvent::users(users)).await;
             }
-            Command::SendFile(filename, contents) => {
-                self.room
-                    .send_event(&self.username, RoomEvent::file(&filename, &contents));
+            Command::CountUsers => {
+                let count = self.room.user_count();
+                self.send_event(ServerEvent::user_count(count)).await;
+            }
+            Command::SendFile(filename, contents) => match common::decode_file(&contents) {
+                Ok(decoded) if decoded.len() > self.config.max_file_size => {
+                    tracing::warn!("Rejected file {filename} of {} bytes", decoded.len());
+                    let limit = self.config.max_file_size;
+                    let event = ServerEvent::quota_exceeded(QuotaKind::FileSize, limit);
+                    self.send_event(event).await;
+                }
+                Ok(decoded) => {
+                    let event = RoomEvent::file(&filename, &contents, decoded.len());
+                    if let Err(err) = self.room.post(&self.username, event) {
+                        self.send_event(ServerEvent::error(err)).await;
+                    }
+                }
+                Err(err) => {
+                    tracing::warn!("Rejected file {filename} with invalid contents: {err}");
+                    let message = format!("Invalid file contents: {err}");
+                    self.send_event(ServerEvent::error(&message)).await;
+                }
+            },
+            Command::Code(language, encoded) => {
+                let source = common::decode_file(&encoded)
+                    .ok()
+                    .and_then(|decoded| String::from_utf8(decoded).ok());
+                match source {
+                    Some(source) => {
+                        let event = RoomEvent::code(&language, &source);
+                        if let Err(err) = self.room.post(&self.username, event) {
+                            self.send_event(ServerEvent::error(err)).await;
+                        }
+                    }
+                    None => {
+                        tracing::warn!("Rejected {language} code with invalid source");
+                        let message = "The source must be encoded UTF-8 text";
+                        self.send_event(ServerEvent::error(message)).await;
+                    }
+                }
+            }
+            Command::FileStart(_, size, filename) if size > self.config.max_file_size => {
+                tracing::warn!("Rejected file {filename} of {size} bytes");
+                let limit = self.config.max_file_size;
+                let event = ServerEvent::quota_exceeded(QuotaKind::FileSize, limit);
+                self.send_event(event).await;
+            }
+            Command::FileStart(id, size, filename) => {
+                let result = self
+                    .room
+                    .check_can_post(&self.username)
+                    .and_then(|_| self.transfers.start(id, self.room.clone(), &filename, size));
+                match result {
+                    Ok(()) => {
+                        tracing::info!("Started file transfer {id}: {filename} ({size} bytes)");
+                        let event = RoomEvent::file_start(id, &filename, size);
+                        self.room.send_event(&self.username, event);
+                    }
+                    Err(err) => self.send_event(ServerEvent::error(err)).await,
+                }
+            }
+            Command::FileChunk(id, index, total, contents) => {
+                let result = common::decode_file(&contents)
+                    .map_err(|_| "Invalid file contents")
+                    .and_then(|decoded| {
+                        let len = decoded.len();
+                        self.transfers.chunk(id, index, total, len, &self.username)
+                    });
+                match result {
+                    Ok(Some(room)) => {
+                        let event = RoomEvent::file_chunk(id, index, total, &contents);
+                        room.send_event(&self.username, event);
+                    }
+                    Ok(None) => {}
+                    Err(err) => {
+                        tracing::warn!("Aborting file transfer {id}: {err}");
+                        self.abort_transfer(id);
+                        self.send_event(ServerEvent::error(err)).await;
+                    }
+                }
+            }
+            Command::FileEnd(id) => match self.transfers.finish(id, &self.username) {
+                Ok(room) => room.send_event(&self.username, RoomEvent::file_end(id)),
+                Err(err) => {
+                    tracing::warn!("Aborting file transfer {id}: {err}");
+                    self.abort_transfer(id);
+                    self.send_event(ServerEvent::error(err)).await;
+                }
+            },
+            Command::DirectMessage(username, text) => {
+                let id = self.rooms.next_message_id();
+                let event = ServerEvent::direct_message(id, &self.username, &username, &text);
+                let sessions = &self.shared.sessions;
+                if username == self.username {
+                    self.send_event(event).await;
+                } else if sessions.send_direct_message(id, &self.username, &username, &event) {
+                    tracing::debug!("Sent a direct message to {username}");
+                    self.send_event(event).await;
+                } else {
+                    let message = format!("Could not deliver the message to {username}");
+                    self.send_event(ServerEvent::error(&message)).await;
+                }
+            }
+            Command::MarkRead(id) => {
+                if let Err(err) = self.shared.sessions.mark_read(id, &self.username) {
+                    self.send_event(ServerEvent::error(err)).await;
+                }
             }
             Command::Nudge(username) => {
                 let users = self.room.list_users();
                 if users.contains(&username) {
                     let nudge = RoomEvent::Nudge(username);
-                    self.room.send_event(&self.username, nudge);
+                    if let Err(err) = self.room.post(&self.username, nudge) {
+                        self.send_event(ServerEvent::error(err)).await;
+                    }
                 } else {
                     self.send_event(ServerEvent::error("user not found")).await;
                 }
             }
-            Command::Quit => {
-                self.room.leave(&self.username);
+            Command::Oper(password) => {
+                if self.config.oper_password.as_ref() == Some(&password) {
+                    tracing::info!("Granted operator privileges");
+                    self.is_operator = true;
+                } else {
+                    tracing::warn!("Failed operator authentication");
+                    let event = ServerEvent::error("Invalid operator password");
+                    self.send_event(event).await;
+                }
+            }
+            Command::SystemMessage(room_name, message) => {
+                if !self.is_operator {
+                    let event = ServerEvent::error("Permission denied");
+                    self.send_event(event).await;
+                } else if let Some(room) = self.rooms.get(&room_name) {
+                    room.send_system_message(&self.username, &message);
+                } else {
+                    let message = format!("Room {room_name} does not exist");
+                    self.send_event(ServerEvent::error(&message)).await;
+                }
+            }
+            Command::Drain => {
+                if self.is_operator {
+                    tracing::warn!("Server is draining, new connections will be refused");
+                    self.shared.start_draining();
+                    self.rooms.send_server_event(ServerEvent::Draining);
+                } else {
+                    let event = ServerEvent::error("Permission denied");
+                    self.send_event(event).await;
+                }
+            }
+            Command::Undo => {
+                if self.room.undo(&self.username).is_none() {
+                    let event = ServerEvent::error("You have no messages to undo");
+                    self.send_event(event).await;
+                }
+            }
+            Command::Edit(id, text) => {
+                let result = match self.config.edit_window {
+                    Some(window) => self.room.edit(&self.username, id, &text, window),
+                    None => Err("Editing messages is disabled"),
+                };
+                if let Err(err) = result {
+                    self.send_event(ServerEvent::error(err)).await;
+                }
+            }
+            Command::Search(query) => {
+                let results = self.room.search(&query);
+                self.send_event(results).await;
+            }
+            Command::React(id, emoji) => {
+                if let Err(err) = self.room.react(&self.username, id, &emoji) {
+                    self.send_event(ServerEvent::error(err)).await;
+                }
+            }
+            Command::InitiativeAdd(name, value) => {
+                self.room
+                    .update_initiative(&self.username, |initiative| initiative.add(name, value));
+            }
+            Command::InitiativeNext => {
+                self.room
+                    .update_initiative(&self.username, |initiative| initiative.next());
+            }
+            Command::InitiativeClear => {
+                self.room
+                    .update_initiative(&self.username, |initiative| initiative.clear());
+            }
+            Command::Ping(token) => {
+                self.send_event(ServerEvent::Pong(token)).await;
+            }
+            // any message counts as an answer to the pings (see `run`)
+            Command::Pong(_) => {}
+            Command::Away(message) => {
+                let (status, _) = self.shared.presence.get(&self.username);
+                let status = if status == UserStatus::Away && message.is_none() {
+                    UserStatus::Online
+                } else {
+                    UserStatus::Away
+                };
+                self.set_status(status, message);
+            }
+            Command::Status(status, message) => self.set_status(status, message),
+            Command::Summary => {
+                let summary = self.room.summary(&self.username);
+                self.send_event(summary).await;
+            }
+            Command::Translate(language, text) => {
+                let event = match self.shared.translator.translate(&language, &text).await {
+                    Ok(translation) => ServerEvent::translation(&language, &translation),
+                    Err(err) => {
+                        tracing::error!("Failed to translate to {language}: {err}");
+                        ServerEvent::error("Translation failed")
+                    }
+                };
+                self.send_event(event).await;
+            }
+            Command::Quit(farewell) => {
+                self.farewell = farewell;
                 self.send_event(ServerEvent::Disconnect).await;
                 self.state = ConnectionState::Disconnected;
             }
         }
     }
 }
+
+/// Waits until the ghosted owner of the pending name is gone, returning whether they left in time
+async fn wait_for_name(pending: Option<&PendingName>) -> bool {
+    match pending {
+        Some(pending) => time::timeout_at(pending.deadline, pending.released.cancelled())
+            .await
+            .is_ok(),
+        None => std::future::pending().await,
+    }
+}
+
+/// Writes the queued events to the user until the queue is closed
+///
+/// The events are flushed as soon as the queue is empty, or periodically if a flush interval is
+/// given. They are encoded as JSON until a binary codec is negotiated.
+async fn write_events<T: Transport>(
+    mut sink: SplitSink<T, Frame>,
+    mut events: mpsc::Receiver<ServerEvent>,
+    flush_interval: Option<Duration>,
+) {
+    let batching = flush_interval.is_some();
+    let mut interval = time::interval(flush_interval.unwrap_or(Duration::from_secs(1)));
+    let mut codec = Codec::default();
+    loop {
+        let result = tokio::select! {
+            event = events.recv() => match event {
+                Some(event) => {
+                    let frame = Frame::encode(&event, codec);
+                    // the reply to `/hello` is the last event before switching the codec
+                    if let ServerEvent::Capabilities(capabilities) = &event {
+                        codec = Codec::negotiated(capabilities);
+                    }
+                    if batching || !events.is_empty() {
+                        sink.feed(frame).await
+                    } else {
+                        sink.send(frame).await
+                    }
+                }
+                None => break,
+            },
+            _ = interval.tick(), if batching => sink.flush().await,
+        };
+        if let Err(err) = result {
+            tracing::error!("Failed to write to the user: {err}");
+            return;
+        }
+    }
+    if let Err(err) = sink.flush().await {
+        tracing::debug!("Failed to flush events: {err}");
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::net::Ipv4Addr;
+
+    use common::{encode_file, MessageId};
+    use tokio::sync::broadcast;
+    use tokio_util::codec::{Framed, FramedRead, LinesCodec};
+
+    use super::*;
+    use crate::{
+        test_utils::{config, TestClient, TestServer},
+        translator::Translator,
+        transport::FrameCodec,
+    };
+
+    fn is_room_event(event: &ServerEvent, predicate: impl Fn(&RoomEvent) -> bool) -> bool {
+        matches!(event, ServerEvent::RoomEvent { event, .. } if predicate(event))
+    }
+
+    /// Translator that "translates" to shouting
+    #[derive(Debug)]
+    struct UppercaseTranslator;
+
+    #[async_trait::async_trait]
+    impl Translator for UppercaseTranslator {
+        async fn translate(&self, _language: &str, text: &str) -> anyhow::Result<String> {
+            Ok(text.to_uppercase())
+        }
+    }
+
+    async fn join(client: &mut TestClient, room: &str) {
+        client.send(&format!("/join {room}")).await;
+        client
+            .recv_until(|event| matches!(event, ServerEvent::Users(_)))
+            .await;
+    }
+
+    #[tokio::test]
+    async fn batching_delivers_the_same_events() {
+        let mut deliveries = Vec::new();
+        for flush_interval in [None, Some(Duration::from_millis(20))] {
+            let (server_side, client_side) = tokio::io::duplex(4096);
+            let (sink, _) =
+                futures::StreamExt::split(Framed::new(server_side, FrameCodec::new(1024)));
+            let (outgoing, events) = mpsc::channel(100);
+            let writer = tokio::spawn(write_events(sink, events, flush_interval));
+            for n in 0..10 {
+                outgoing
+                    .send(ServerEvent::error(&n.to_string()))
+                    .await
+                    .unwrap();
+            }
+            // the events arrive while the queue is still open, i.e. without the final flush
+            let mut lines = FramedRead::new(client_side, LinesCodec::new());
+            let mut delivered = Vec::new();
+            for _ in 0..10 {
+                let line = time::timeout(Duration::from_secs(1), lines.next())
+                    .await
+                    .expect("the events were not flushed")
+                    .unwrap()
+                    .unwrap();
+                delivered.push(line);
+            }
+            drop(outgoing);
+            writer.await.unwrap();
+            deliveries.push(delivered);
+        }
+        assert_eq!(deliveries[0], deliveries[1]);
+    }
+
+    #[tokio::test]
+    async fn blocked_writers_lose_no_events() {
+        // the buffer only fits a few events, so the writer blocks until they are read
+        let (server_side, client_side) = tokio::io::duplex(64);
+        let (sink, _) = futures::StreamExt::split(Framed::new(server_side, FrameCodec::new(1024)));
+        let (outgoing, events) = mpsc::channel(100);
+        let writer = tokio::spawn(write_events(sink, events, None));
+        for n in 0..100 {
+            outgoing
+                .send(ServerEvent::error(&n.to_string()))
+                .await
+                .unwrap();
+        }
+        time::sleep(Duration::from_millis(50)).await;
+        assert!(!writer.is_finished());
+
+        drop(outgoing);
+        let lines: Vec<_> = FramedRead::new(client_side, LinesCodec::new())
+            .map(|line| ServerEvent::from_json_str(&line.unwrap()).unwrap())
+            .collect()
+            .await;
+        writer.await.unwrap();
+        assert_eq!(lines.len(), 100);
+        for (n, event) in lines.iter().enumerate() {
+            assert!(
+                matches!(event, ServerEvent::Error(message) if *message == n.to_string()),
+                "{event:?}"
+            );
+        }
+    }
+
+    #[tokio::test]
+    async fn unanswered_pings_disconnect() {
+        let flags = ["--heartbeat-interval", "1", "--max-missed-heartbeats", "2"];
+        let server = TestServer::start(&flags).await;
+        let mut client = server.connect().await;
+        let mut plain_client = server.connect().await;
+        client.send(&format!("/hello {PROTOCOL_VERSION}")).await;
+        let events = client.recv_until_closed().await;
+        let pings = events
+            .iter()
+            .filter(|event| matches!(event, ServerEvent::Ping(_)))
+            .count();
+        assert_eq!(pings, 2);
+
+        // clients that did not send `/hello` are not pinged
+        plain_client.send("/ping").await;
+        let event = plain_client
+            .recv_until(|event| matches!(event, ServerEvent::Ping(_) | ServerEvent::Pong(_)))
+            .await;
+        assert!(matches!(event, ServerEvent::Pong(None)), "{event:?}");
+        server.shutdown().await;
+    }
+
+    #[tokio::test]
+    async fn duplicate_names_can_be_refused() {
+        let server = TestServer::start(&["--duplicate-names", "refuse"]).await;
+        let mut alice = server.connect_as("alice").await;
+        let mut client = server.connect().await;
+        client.send("/name alice").await;
+        let event = client
+            .recv_until(|event| matches!(event, ServerEvent::Error(_)))
+            .await;
+        let ServerEvent::Error(err) = event else {
+            unreachable!()
+        };
+        assert_eq!(err, "alice is already taken");
+        // the owner of the name keeps it
+        alice.send("/ping still here").await;
+        alice
+            .recv_until(|event| matches!(event, ServerEvent::Pong(_)))
+            .await;
+        server.shutdown().await;
+    }
+
+    #[tokio::test]
+    async fn ghosting_takes_over_the_name() {
+        let server = TestServer::start(&["--duplicate-names", "ghost"]).await;
+        let mut ghost = server.connect_as("alice").await;
+        let mut bob = server.connect_as("bob").await;
+        let mut client = server.connect().await;
+        client.send("/name alice").await;
+        let events = ghost.recv_until_closed().await;
+        assert!(
+            matches!(events.last(), Some(ServerEvent::Disconnect)),
+            "{events:?}"
+        );
+        let alice = Username::from("alice");
+        client
+            .recv_until(|event| {
+                is_room_event(
+                    event,
+                    |event| matches!(event, RoomEvent::NameChange(name) if name == &alice),
+                )
+            })
+            .await;
+
+        // the others are not told about a kick
+        bob.send("/users").await;
+        loop {
+            match bob.recv().await {
+                ServerEvent::Users(users) => {
+                    assert!(users.contains(&alice));
+                    break;
+                }
+                event => assert!(!matches!(event, ServerEvent::Kicked { .. }), "{event:?}"),
+            }
+        }
+        server.shutdown().await;
+    }
+
+    #[tokio::test]
+    async fn leaving_the_current_room_switches_to_the_last_joined_one() {
+        let server = TestServer::start(&[]).await;
+        let mut client = server.connect().await;
+        for room in ["ant", "bee", "cat", "ant"] {
+            join(&mut client, room).await;
+        }
+        client.send("/leave ant").await;
+        client.send("/myrooms").await;
+        let event = client
+            .recv_until(|event| matches!(event, ServerEvent::Memberships { .. }))
+            .await;
+        let ServerEvent::Memberships { rooms, current } = event else {
+            unreachable!()
+        };
+        assert_eq!(current, RoomName::from("cat"));
+        assert_eq!(rooms.len(), 3);
+        server.shutdown().await;
+    }
+
+    #[tokio::test]
+    async fn incompatible_versions_disconnect() {
+        let server = TestServer::start(&[]).await;
+        let mut client = server.connect().await;
+        client
+            .send(&format!("/hello {}", PROTOCOL_VERSION + 1))
+            .await;
+        let events = client.recv_until_closed().await;
+        let [.., ServerEvent::Error(message), ServerEvent::Disconnect] = events.as_slice() else {
+            panic!("{events:?}");
+        };
+        assert!(
+            message.starts_with("Unsupported protocol version"),
+            "{message}"
+        );
+        server.shutdown().await;
+    }
+
+    /// Returns the kinds of the room events that are received until the given message
+    async fn recv_room_events_until(client: &mut TestClient, text: &str) -> Vec<&'static str> {
+        let mut kinds = Vec::new();
+        loop {
+            let event = client.recv().await;
+            if is_room_event(
+                &event,
+                |event| matches!(event, RoomEvent::Message { text: t, .. } if t == text),
+            ) {
+                return kinds;
+            }
+            if matches!(event, ServerEvent::RoomEvent { .. }) {
+                kinds.push(event.kind());
+            }
+        }
+    }
+
+    #[tokio::test]
+    async fn events_are_limited_to_the_negotiated_capabilities() {
+        let server = TestServer::start(&[]).await;
+        let mut plain = server.connect().await;
+        plain.send(&format!("/hello {PROTOCOL_VERSION}")).await;
+        let event = plain
+            .recv_until(|event| matches!(event, ServerEvent::Capabilities(_)))
+            .await;
+        let ServerEvent::Capabilities(capabilities) = event else {
+            unreachable!()
+        };
+        assert_eq!(capabilities, Capabilities::default());
+        let mut capable = server.connect().await;
+        capable
+            .send(&format!(
+                "/hello {PROTOCOL_VERSION} reactions file_chunking"
+            ))
+            .await;
+        capable
+            .recv_until(|event| matches!(event, ServerEvent::Capabilities(_)))
+            .await;
+
+        let mut alice = server.connect_as("alice").await;
+        alice.send("hello").await;
+        let ServerEvent::RoomEvent {
+            event: RoomEvent::Message { id, .. },
+            ..
+        } = alice
+            .recv_until(|event| {
+                is_room_event(event, |event| matches!(event, RoomEvent::Message { .. }))
+            })
+            .await
+        else {
+            unreachable!()
+        };
+        alice.send(&format!("/react {id} +1")).await;
+        alice.send("/filestart 1 0 empty.txt").await;
+        alice.send("/fileend 1").await;
+        alice.send("done").await;
+
+        let kinds = recv_room_events_until(&mut plain, "done").await;
+        assert!(!kinds.contains(&"Reaction"), "{kinds:?}");
+        assert!(!kinds.contains(&"FileStart"), "{kinds:?}");
+        let kinds = recv_room_events_until(&mut capable, "done").await;
+        assert!(kinds.contains(&"Reaction"), "{kinds:?}");
+        assert!(kinds.contains(&"FileStart"), "{kinds:?}");
+        server.shutdown().await;
+    }
+
+    #[tokio::test]
+    async fn transfers_are_aborted_on_disconnect() {
+        let server = TestServer::start(&[]).await;
+        let mut alice = server.connect_as("alice").await;
+        let mut bob = server.connect_as("bob").await;
+        bob.send("/filestart 1 10 notes.txt").await;
+        bob.send(&format!("/filechunk 1 0 2 {}", encode_file(b"notes")))
+            .await;
+        alice
+            .recv_until(|event| {
+                is_room_event(event, |event| matches!(event, RoomEvent::FileChunk { .. }))
+            })
+            .await;
+        // the connection closes without a `/fileend`
+        drop(bob);
+        alice
+            .recv_until(|event| {
+                is_room_event(event, |event| {
+                    matches!(event, RoomEvent::FileAborted { transfer: 1 })
+                })
+            })
+            .await;
+        server.shutdown().await;
+    }
+
+    #[tokio::test]
+    async fn stalled_transfers_time_out() {
+        let server = TestServer::start(&["--transfer-timeout", "1"]).await;
+        let mut alice = server.connect_as("alice").await;
+        let mut bob = server.connect_as("bob").await;
+        bob.send("/filestart 1 10 notes.txt").await;
+        bob.send(&format!("/filechunk 1 0 2 {}", encode_file(b"notes")))
+            .await;
+        alice
+            .recv_until(|event| {
+                is_room_event(event, |event| {
+                    matches!(event, RoomEvent::FileAborted { transfer: 1 })
+                })
+            })
+            .await;
+        let event = bob
+            .recv_until(|event| matches!(event, ServerEvent::Error(_)))
+            .await;
+        assert!(
+            matches!(&event, ServerEvent::Error(err) if err == "File transfer 1 timed out"),
+            "{event:?}"
+        );
+        server.shutdown().await;
+    }
+
+    #[tokio::test]
+    async fn protocol_lines_are_not_throttled() {
+        let server = TestServer::start(&["--max-messages-per-second", "2"]).await;
+        let mut alice = server.connect_as("alice").await;
+        let mut bob = server.connect_as("bob").await;
+        bob.send("/filestart 1 20 notes.txt").await;
+        for index in 0..5 {
+            bob.send(&format!("/filechunk 1 {index} 5 {}", encode_file(b"data")))
+                .await;
+            bob.send("/pong 0").await;
+        }
+        bob.send("/fileend 1").await;
+        alice
+            .recv_until(|event| {
+                is_room_event(event, |event| {
+                    matches!(event, RoomEvent::FileEnd { transfer: 1 })
+                })
+            })
+            .await;
+        server.shutdown().await;
+    }
+
+    #[tokio::test]
+    async fn unknown_chunks_are_throttled() {
+        let server = TestServer::start(&["--max-messages-per-second", "2"]).await;
+        let mut client = server.connect().await;
+        for index in 0..5 {
+            client
+                .send(&format!("/filechunk 1 {index} 5 {}", encode_file(b"data")))
+                .await;
+        }
+        client
+            .recv_until(|event| matches!(event, ServerEvent::RateLimited { .. }))
+            .await;
+        server.shutdown().await;
+    }
+
+    #[tokio::test]
+    async fn undo_needs_a_message() {
+        let server = TestServer::start(&[]).await;
+        let mut client = server.connect().await;
+        client.send("/undo").await;
+        let event = client
+            .recv_until(|event| matches!(event, ServerEvent::Error(_)))
+            .await;
+        assert!(
+            matches!(&event, ServerEvent::Error(err) if err == "You have no messages to undo"),
+            "{event:?}"
+        );
+        server.shutdown().await;
+    }
+
+    #[tokio::test]
+    async fn farewells_reach_the_remaining_users() {
+        let server = TestServer::start(&[]).await;
+        let mut alice = server.connect_as("alice").await;
+        let mut bob = server.connect_as("bob").await;
+        bob.send("/quit see you all").await;
+        bob.recv_until_closed().await;
+        let event = alice
+            .recv_until(|event| {
+                is_room_event(event, |event| matches!(event, RoomEvent::Left { .. }))
+            })
+            .await;
+        let ServerEvent::RoomEvent {
+            username,
+            event: RoomEvent::Left { farewell, .. },
+            ..
+        } = event
+        else {
+            unreachable!()
+        };
+        assert_eq!(username, Username::from("bob"));
+        assert_eq!(farewell.as_deref(), Some("see you all"));
+        server.shutdown().await;
+    }
+
+    /// Receives the id of the next direct message
+    async fn recv_direct_message(client: &mut TestClient) -> MessageId {
+        let event = client
+            .recv_until(|event| matches!(event, ServerEvent::DirectMessage { .. }))
+            .await;
+        let ServerEvent::DirectMessage { id, .. } = event else {
+            unreachable!()
+        };
+        id
+    }
+
+    #[tokio::test]
+    async fn read_receipts_reach_the_sender() {
+        let server = TestServer::start(&[]).await;
+        let mut alice = server.connect_as("alice").await;
+        let mut bob = server.connect_as("bob").await;
+        alice.send("/msg bob are you there?").await;
+        let id = recv_direct_message(&mut bob).await;
+        bob.send(&format!("/read {id}")).await;
+        let event = alice
+            .recv_until(|event| matches!(event, ServerEvent::ReadReceipt { .. }))
+            .await;
+        let ServerEvent::ReadReceipt { id: read, reader } = event else {
+            unreachable!()
+        };
+        assert_eq!(read, id);
+        assert_eq!(reader, Username::from("bob"));
+        // a message is only read once
+        bob.send(&format!("/read {id}")).await;
+        let event = bob
+            .recv_until(|event| matches!(event, ServerEvent::Error(_)))
+            .await;
+        let ServerEvent::Error(err) = event else {
+            unreachable!()
+        };
+        assert_eq!(err, "No unread direct message with that id");
+        server.shutdown().await;
+    }
+
+    #[tokio::test]
+    async fn read_receipts_need_the_sender_online() {
+        let server = TestServer::start(&[]).await;
+        let mut alice = server.connect_as("alice").await;
+        let mut bob = server.connect_as("bob").await;
+        alice.send("/msg bob are you there?").await;
+        let id = recv_direct_message(&mut bob).await;
+        alice.send("/quit").await;
+        alice.recv_until_closed().await;
+        bob.recv_until(|event| {
+            is_room_event(event, |event| matches!(event, RoomEvent::Left { .. }))
+        })
+        .await;
+        // the message is still marked as read, but nobody gets a receipt
+        bob.send(&format!("/read {id}")).await;
+        bob.send("/ping done").await;
+        loop {
+            let event = bob.recv().await;
+            assert!(!matches!(event, ServerEvent::Error(_)), "{event:?}");
+            if matches!(event, ServerEvent::Pong(_)) {
+                break;
+            }
+        }
+        // the messages of an offline recipient are never delivered, so there is nothing to read
+        bob.send("/msg alice are you there?").await;
+        let event = bob
+            .recv_until(|event| matches!(event, ServerEvent::Error(_)))
+            .await;
+        let ServerEvent::Error(err) = event else {
+            unreachable!()
+        };
+        assert_eq!(err, "Could not deliver the message to alice");
+        server.shutdown().await;
+    }
+
+    #[tokio::test]
+    async fn statuses_are_announced_in_joined_rooms() {
+        let server = TestServer::start(&[]).await;
+        let mut bob = server.connect_as("bob").await;
+        join(&mut bob, "den").await;
+        let mut alice = server.connect_as("alice").await;
+        alice.send("/away lunch").await;
+        join(&mut alice, "den").await;
+        let event = bob
+            .recv_until(|event| {
+                is_room_event(event, |event| {
+                    matches!(event, RoomEvent::StatusChanged { .. })
+                })
+            })
+            .await;
+        let ServerEvent::RoomEvent {
+            username,
+            event: RoomEvent::StatusChanged { status, message },
+            ..
+        } = event
+        else {
+            unreachable!()
+        };
+        assert_eq!(username, Username::from("alice"));
+        assert_eq!(status, UserStatus::Away);
+        assert_eq!(message.as_deref(), Some("lunch"));
+        server.shutdown().await;
+    }
+
+    #[tokio::test]
+    async fn returns_are_announced_if_configured() {
+        for announce_returns in [false, true] {
+            let flags: &[&str] = if announce_returns {
+                &["--announce-returns"]
+            } else {
+                &[]
+            };
+            let server = TestServer::start(flags).await;
+            let mut bob = server.connect_as("bob").await;
+            let mut alice = server.connect_as("alice").await;
+            alice.send("/away lunch").await;
+            alice.send("/away").await;
+            // the message marks the end of the events that the return causes
+            alice.send("hello").await;
+            let mut back = false;
+            loop {
+                let ServerEvent::RoomEvent { event, .. } = bob.recv().await else {
+                    continue;
+                };
+                match event {
+                    RoomEvent::Back => back = true,
+                    RoomEvent::Message { .. } => break,
+                    _ => {}
+                }
+            }
+            assert_eq!(back, announce_returns);
+            server.shutdown().await;
+        }
+    }
+
+    #[tokio::test]
+    async fn busy_statuses_reach_the_room() {
+        let server = TestServer::start(&[]).await;
+        let mut bob = server.connect_as("bob").await;
+        let mut alice = server.connect_as("alice").await;
+        alice.send("/status busy in a meeting").await;
+        alice.send("/status busy in a meeting").await;
+        alice.send("/status online").await;
+        let mut statuses = Vec::new();
+        while statuses.last() != Some(&UserStatus::Online) {
+            let event = bob
+                .recv_until(|event| {
+                    is_room_event(event, |event| {
+                        matches!(event, RoomEvent::StatusChanged { .. })
+                    })
+                })
+                .await;
+            if let ServerEvent::RoomEvent {
+                event: RoomEvent::StatusChanged { status, message },
+                ..
+            } = event
+            {
+                if status == UserStatus::Busy {
+                    assert_eq!(message.as_deref(), Some("in a meeting"));
+                }
+                statuses.push(status);
+            }
+        }
+        // the repeated status is not broadcast again
+        assert_eq!(statuses, [UserStatus::Busy, UserStatus::Online]);
+        server.shutdown().await;
+    }
+
+    #[tokio::test]
+    async fn commands_use_the_configured_prefix() {
+        let server = TestServer::start(&["--command-prefix", "!"]).await;
+        let mut client = server.connect().await;
+        client.send("!join den").await;
+        client
+            .recv_until(|event| {
+                is_room_event(event, |event| {
+                    matches!(event, RoomEvent::Joined(room) if room == &RoomName::from("den"))
+                })
+            })
+            .await;
+        // the usual prefix is a plain message, and a doubled prefix a literal one
+        client.send("/join hall").await;
+        client.send("!!join hall").await;
+        for expected in ["/join hall", "!join hall"] {
+            let event = client
+                .recv_until(|event| {
+                    is_room_event(event, |event| matches!(event, RoomEvent::Message { .. }))
+                })
+                .await;
+            let ServerEvent::RoomEvent {
+                room_name,
+                event: RoomEvent::Message { text, .. },
+                ..
+            } = event
+            else {
+                unreachable!()
+            };
+            assert_eq!(room_name, RoomName::from("den"));
+            assert_eq!(text, expected);
+        }
+        server.shutdown().await;
+    }
+
+    #[tokio::test]
+    async fn overlong_lines_keep_the_session_open() {
+        let server = TestServer::start(&["--max-line-length", "32"]).await;
+        let mut client = server.connect().await;
+        client.send(&"a".repeat(100)).await;
+        let event = client
+            .recv_until(|event| matches!(event, ServerEvent::QuotaExceeded { .. }))
+            .await;
+        assert!(
+            matches!(
+                event,
+                ServerEvent::QuotaExceeded {
+                    kind: QuotaKind::LineLength,
+                    limit: 32
+                }
+            ),
+            "{event:?}"
+        );
+        client.send("/ping still here").await;
+        client
+            .recv_until(|event| matches!(event, ServerEvent::Pong(_)))
+            .await;
+        server.shutdown().await;
+    }
+
+    #[tokio::test]
+    async fn system_messages_reach_the_target_room_only() {
+        let server = TestServer::start(&["--oper-password", "hunter2"]).await;
+        let mut admin = server.connect_as("admin").await;
+        let mut bob = server.connect_as("bob").await;
+        let mut carol = server.connect_as("carol").await;
+        join(&mut bob, "den").await;
+        admin.send("/oper hunter2").await;
+        admin.send("/sysmsg den be nice").await;
+        admin.send("/sysmsg lobby welcome").await;
+        let den = RoomName::from("den");
+        bob.recv_until(|event| {
+            matches!(event, ServerEvent::RoomEvent { room_name, event: RoomEvent::System(_), .. }
+                if room_name == &den)
+        })
+        .await;
+        // the message to the lobby comes after the one to the den
+        let event = carol
+            .recv_until(|event| is_room_event(event, |event| matches!(event, RoomEvent::System(_))))
+            .await;
+        let ServerEvent::RoomEvent {
+            event: RoomEvent::System(message),
+            ..
+        } = event
+        else {
+            unreachable!()
+        };
+        assert_eq!(message, "welcome");
+        server.shutdown().await;
+    }
+
+    #[tokio::test]
+    async fn files_up_to_the_limit_are_accepted() {
+        let server = TestServer::start(&["--max-file-size", "4"]).await;
+        let mut alice = server.connect_as("alice").await;
+        let mut bob = server.connect_as("bob").await;
+        bob.send(&format!("/file fits.txt {}", encode_file(b"four")))
+            .await;
+        let event = alice
+            .recv_until(|event| {
+                is_room_event(event, |event| matches!(event, RoomEvent::File { .. }))
+            })
+            .await;
+        assert!(
+            is_room_event(&event, |event| {
+                matches!(event, RoomEvent::File { filename, size: 4, .. } if filename == "fits.txt")
+            }),
+            "{event:?}"
+        );
+        bob.send(&format!("/file large.txt {}", encode_file(b"fives")))
+            .await;
+        // the same limit applies to the files that are sent in chunks
+        bob.send("/filestart 1 5 large.txt").await;
+        for _ in 0..2 {
+            let event = bob
+                .recv_until(|event| matches!(event, ServerEvent::QuotaExceeded { .. }))
+                .await;
+            assert!(
+                matches!(
+                    event,
+                    ServerEvent::QuotaExceeded {
+                        kind: QuotaKind::FileSize,
+                        limit: 4
+                    }
+                ),
+                "{event:?}"
+            );
+        }
+        server.shutdown().await;
+    }
+
+    #[tokio::test]
+    async fn user_count_matches_the_listing() {
+        let server = TestServer::start(&[]).await;
+        let mut alice = server.connect_as("alice").await;
+        let mut bob = server.connect_as("bob").await;
+        let _carol = server.connect_as("carol").await;
+        join(&mut bob, "den").await;
+        // bob talks in the den, alice in the lobby
+        for (client, expected) in [(&mut alice, 3), (&mut bob, 1)] {
+            client.send("/users").await;
+            let event = client
+                .recv_until(|event| matches!(event, ServerEvent::Users(_)))
+                .await;
+            let ServerEvent::Users(users) = event else {
+                unreachable!()
+            };
+            client.send("/users count").await;
+            let event = client
+                .recv_until(|event| matches!(event, ServerEvent::UserCount(_)))
+                .await;
+            assert!(
+                matches!(event, ServerEvent::UserCount(count) if count == users.len()),
+                "{event:?} {users:?}"
+            );
+            assert_eq!(users.len(), expected, "{users:?}");
+        }
+        server.shutdown().await;
+    }
+
+    #[tokio::test]
+    async fn disabled_commands_are_rejected() {
+        let server = TestServer::start(&["--allowed-commands", "join,users"]).await;
+        let mut client = server.connect().await;
+        client
+            .send(&format!("/file notes.txt {}", encode_file(b"notes")))
+            .await;
+        let event = client
+            .recv_until(|event| matches!(event, ServerEvent::Error(_)))
+            .await;
+        let ServerEvent::Error(err) = event else {
+            unreachable!()
+        };
+        assert_eq!(err, "/file is disabled on this server");
+        // the allowed commands keep working
+        join(&mut client, "den").await;
+        client.send("/users").await;
+        client
+            .recv_until(|event| matches!(event, ServerEvent::Users(_)))
+            .await;
+        server.shutdown().await;
+    }
+
+    #[tokio::test]
+    async fn joiners_receive_the_occupants() {
+        let server = TestServer::start(&[]).await;
+        let mut alice = server.connect_as("alice").await;
+        let mut bob = server.connect_as("bob").await;
+        join(&mut alice, "den").await;
+        join(&mut bob, "den").await;
+        let mut carol = server.connect_as("carol").await;
+        carol.send("/join den").await;
+        let event = carol
+            .recv_until(|event| matches!(event, ServerEvent::Users(_)))
+            .await;
+        let ServerEvent::Users(mut users) = event else {
+            unreachable!()
+        };
+        users.sort();
+        assert_eq!(users, ["alice", "bob", "carol"].map(Username::from));
+        // an empty room has only the joiner in it
+        carol.send("/join hall").await;
+        let event = carol
+            .recv_until(|event| matches!(event, ServerEvent::Users(_)))
+            .await;
+        assert!(
+            matches!(&event, ServerEvent::Users(users) if users == &[Username::from("carol")]),
+            "{event:?}"
+        );
+        server.shutdown().await;
+    }
+
+    #[tokio::test]
+    async fn members_of_two_rooms_receive_both() {
+        let server = TestServer::start(&[]).await;
+        let mut alice = server.connect_as("alice").await;
+        let mut bob = server.connect_as("bob").await;
+        let mut carol = server.connect_as("carol").await;
+        join(&mut bob, "den").await;
+        join(&mut carol, "hall").await;
+        join(&mut alice, "den").await;
+        join(&mut alice, "hall").await;
+        bob.send("from the den").await;
+        carol.send("from the hall").await;
+        let mut received = Vec::new();
+        while received.len() < 2 {
+            if let ServerEvent::RoomEvent {
+                room_name,
+                event: RoomEvent::Message { text, .. },
+                ..
+            } = alice.recv().await
+            {
+                received.push((room_name.to_string(), text));
+            }
+        }
+        received.sort();
+        assert_eq!(
+            received,
+            [
+                ("den".to_string(), "from the den".to_string()),
+                ("hall".to_string(), "from the hall".to_string())
+            ]
+        );
+        server.shutdown().await;
+    }
+
+    #[tokio::test]
+    async fn name_history_starts_with_the_latest_name() {
+        let server = TestServer::start(&[]).await;
+        let mut client = server.connect_as("alice").await;
+        client.send("/name bob").await;
+        client.send("/name carol").await;
+        client.send("/nick-history").await;
+        let event = client
+            .recv_until(|event| matches!(event, ServerEvent::NameHistory(_)))
+            .await;
+        let ServerEvent::NameHistory(names) = event else {
+            unreachable!()
+        };
+        // the random name that the user got on connecting comes last
+        assert_eq!(names.len(), 3, "{names:?}");
+        assert_eq!(names[..2], ["bob", "alice"].map(Username::from));
+        server.shutdown().await;
+    }
+
+    #[tokio::test]
+    async fn clearing_the_room_empties_the_history() {
+        let server = TestServer::start(&[]).await;
+        let mut alice = server.connect_as("alice").await;
+        join(&mut alice, "den").await;
+        alice.send("hello").await;
+        alice.send("/clearroom").await;
+        alice
+            .recv_until(|event| {
+                is_room_event(event, |event| matches!(event, RoomEvent::HistoryCleared))
+            })
+            .await;
+        let mut bob = server.connect_as("bob").await;
+        join(&mut bob, "den").await;
+        let history = bob
+            .recv_until(|event| matches!(event, ServerEvent::History { .. }))
+            .await;
+        let ServerEvent::History { room_name, events } = history else {
+            unreachable!()
+        };
+        assert_eq!(room_name, RoomName::from("den"));
+        assert!(events.is_empty(), "{events:?}");
+        server.shutdown().await;
+    }
+
+    #[tokio::test]
+    async fn translations_come_from_the_translator() {
+        let server = TestServer::start_with_translator(&[], Arc::new(UppercaseTranslator)).await;
+        let mut alice = server.connect_as("alice").await;
+        alice.send("/translate de hello there").await;
+        let translation = alice
+            .recv_until(|event| matches!(event, ServerEvent::Translation { .. }))
+            .await;
+        let ServerEvent::Translation { language, text } = translation else {
+            unreachable!()
+        };
+        assert_eq!(language, "de");
+        assert_eq!(text, "HELLO THERE");
+        server.shutdown().await;
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn skipped_events_are_counted() {
+        let (server_events, receiver) = broadcast::channel(2);
+        let shared = SharedState::new(config(&[]), Rooms::new(server_events.clone(), 10));
+        let (server_side, _client_side) = tokio::io::duplex(4096);
+        let transport = Framed::new(server_side, FrameCodec::new(1024));
+        let addr = (Ipv4Addr::LOCALHOST, 0).into();
+        let mut connection =
+            Connection::new(transport, receiver, shared, Username::from("alice"), addr);
+        for index in 0..5 {
+            let event = ServerEvent::announcement(&format!("announcement {index}"));
+            server_events.send(event).unwrap();
+        }
+        // the connection runs until the user leaves, so it is stopped once it caught up
+        let _ = time::timeout(Duration::from_secs(1), connection.run()).await;
+        assert_eq!(connection.dropped_events, 3);
+    }
+
+    #[tokio::test]
+    async fn memberships_list_the_joined_rooms() {
+        let server = TestServer::start(&[]).await;
+        let mut alice = server.connect_as("alice").await;
+        for room in ["den", "attic", "cellar"] {
+            join(&mut alice, room).await;
+        }
+        alice.send("/myrooms").await;
+        let memberships = alice
+            .recv_until(|event| matches!(event, ServerEvent::Memberships { .. }))
+            .await;
+        let ServerEvent::Memberships { rooms, current } = memberships else {
+            unreachable!()
+        };
+        let mut expected = ["attic", "cellar", "den"].map(RoomName::from).to_vec();
+        expected.push(RoomName::lobby());
+        expected.sort();
+        assert_eq!(rooms, expected);
+        assert_eq!(current, RoomName::from("cellar"));
+        server.shutdown().await;
+    }
+
+    #[tokio::test]
+    async fn crlf_line_endings_are_accepted() {
+        let server = TestServer::start(&[]).await;
+        let mut client = server.connect().await;
+        // the codec adds the line feed
+        client.send("/join room\r").await;
+        let event = client
+            .recv_until(|event| matches!(event, ServerEvent::RoomCreated(_)))
+            .await;
+        assert!(
+            matches!(&event, ServerEvent::RoomCreated(name) if name.as_str() == "room"),
+            "{event:?}"
+        );
+        server.shutdown().await;
+    }
+
+    #[tokio::test]
+    async fn muted_users_cannot_post() {
+        let server = TestServer::start(&[]).await;
+        let mut alice = server.connect_as("alice").await;
+        join(&mut alice, "den").await;
+        let mut bob = server.connect_as("bob").await;
+        join(&mut bob, "den").await;
+        bob.send("/filestart 1 4 chunked.txt").await;
+        bob.send("/filestart 2 0 empty.txt").await;
+        bob.recv_until(|event| {
+            is_room_event(event, |event| {
+                matches!(event, RoomEvent::FileStart { transfer: 2, .. })
+            })
+        })
+        .await;
+        alice.send("hello").await;
+        let ServerEvent::RoomEvent {
+            event: RoomEvent::Message { id, .. },
+            ..
+        } = alice
+            .recv_until(|event| {
+                is_room_event(event, |event| matches!(event, RoomEvent::Message { .. }))
+            })
+            .await
+        else {
+            unreachable!()
+        };
+        alice.send("/mute bob").await;
+        bob.recv_until(|event| is_room_event(event, |event| matches!(event, RoomEvent::Muted(_))))
+            .await;
+
+        let posts = [
+            "hi".to_string(),
+            format!("/file notes.txt {}", encode_file(b"notes")),
+            format!("/code rust {}", encode_file(b"fn main() {}")),
+            "/filestart 3 4 new.txt".to_string(),
+            format!("/filechunk 1 0 1 {}", encode_file(b"data")),
+            "/fileend 2".to_string(),
+            format!("/react {id} +1"),
+            "/nudge alice".to_string(),
+        ];
+        for post in posts {
+            bob.send(&post).await;
+            let event = bob
+                .recv_until(|event| matches!(event, ServerEvent::Error(_)))
+                .await;
+            assert!(
+                matches!(&event, ServerEvent::Error(err) if err == "You are muted in this room"),
+                "{post}: {event:?}"
+            );
+        }
+
+        // the unmuting is broadcast after anything that bob might have posted
+        alice.send("/unmute bob").await;
+        let bob_name = Username::from("bob");
+        loop {
+            match alice.recv().await {
+                ServerEvent::RoomEvent {
+                    event: RoomEvent::Unmuted(_),
+                    ..
+                } => break,
+                ServerEvent::RoomEvent {
+                    username, event, ..
+                } if username == bob_name => {
+                    assert!(matches!(event, RoomEvent::FileAborted { .. }), "{event:?}")
+                }
+                _ => {}
+            }
+        }
+        server.shutdown().await;
+    }
+}
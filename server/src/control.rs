@@ -0,0 +1,198 @@
+use common::Username;
+use futures::SinkExt;
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use tokio::net::{TcpListener, TcpStream};
+use tokio_stream::StreamExt;
+use tokio_util::codec::{Framed, LinesCodec};
+
+use crate::state::SharedState;
+
+/// A JSON-RPC 2.0 request received on the control socket
+#[derive(Debug, Deserialize)]
+struct Request {
+    #[serde(default)]
+    id: Value,
+    method: String,
+    #[serde(default)]
+    params: Value,
+}
+
+/// A JSON-RPC 2.0 response sent back on the control socket
+#[derive(Debug, Serialize)]
+struct Response {
+    jsonrpc: &'static str,
+    id: Value,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    result: Option<Value>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    error: Option<Error>,
+}
+
+impl Response {
+    fn new(id: Value, result: Result<Value, Error>) -> Self {
+        let (result, error) = match result {
+            Ok(result) => (Some(result), None),
+            Err(error) => (None, Some(error)),
+        };
+        Self {
+            jsonrpc: "2.0",
+            id,
+            result,
+            error,
+        }
+    }
+}
+
+#[derive(Debug, Serialize)]
+struct Error {
+    code: i32,
+    message: String,
+}
+
+impl Error {
+    const PARSE_ERROR: i32 = -32700;
+    const METHOD_NOT_FOUND: i32 = -32601;
+    const INVALID_PARAMS: i32 = -32602;
+    const INTERNAL_ERROR: i32 = -32603;
+    const UNAUTHORIZED: i32 = -32001;
+    const NOT_FOUND: i32 = -32002;
+
+    fn new(code: i32, message: impl Into<String>) -> Self {
+        Self {
+            code,
+            message: message.into(),
+        }
+    }
+}
+
+/// Accepts connections on the control socket and handles their requests
+pub async fn serve(listener: TcpListener, shared: SharedState) {
+    loop {
+        let (stream, addr) = match listener.accept().await {
+            Ok(ok) => ok,
+            Err(err) => {
+                tracing::error!("Failed to accept control connection: {err}");
+                continue;
+            }
+        };
+        tracing::info!("{addr} connected to the control socket");
+        let shared = shared.clone();
+        tokio::spawn(async move {
+            if let Err(err) = handle(stream, shared).await {
+                tracing::error!("Control connection error: {err}");
+            }
+        });
+    }
+}
+
+async fn handle(stream: TcpStream, shared: SharedState) -> anyhow::Result<()> {
+    let codec = LinesCodec::new_with_max_length(shared.config.max_line_length);
+    let mut lines = Framed::new(stream, codec);
+    while let Some(line) = lines.next().await {
+        let response = match serde_json::from_str::<Request>(&line?) {
+            Ok(request) => {
+                tracing::info!("Received control request: {}", request.method);
+                Response::new(request.id.clone(), dispatch(&shared, request))
+            }
+            Err(err) => Response::new(
+                Value::Null,
+                Err(Error::new(Error::PARSE_ERROR, err.to_string())),
+            ),
+        };
+        lines.send(serde_json::to_string(&response)?).await?;
+    }
+    Ok(())
+}
+
+fn dispatch(shared: &SharedState, request: Request) -> Result<Value, Error> {
+    if let Some(token) = &shared.config.control_token {
+        if request.params.get("token").and_then(Value::as_str) != Some(token) {
+            return Err(Error::new(Error::UNAUTHORIZED, "Invalid token"));
+        }
+    }
+    match request.method.as_str() {
+        "kick" => kick(shared, &request.params, false),
+        "ban" => kick(shared, &request.params, true),
+        "announce" => {
+            shared.announce(string_param(&request.params, "message")?);
+            Ok(Value::Bool(true))
+        }
+        "snapshot" => to_value(shared.rooms.snapshot()),
+        "restore" => {
+            let snapshot = request.params.get("snapshot").cloned().unwrap_or_default();
+            let snapshot = serde_json::from_value(snapshot)
+                .map_err(|err| Error::new(Error::INVALID_PARAMS, err.to_string()))?;
+            shared.rooms.restore(snapshot);
+            Ok(Value::Bool(true))
+        }
+        "stats" => to_value(shared.stats()),
+        method => Err(Error::new(
+            Error::METHOD_NOT_FOUND,
+            format!("Unknown method: {method}"),
+        )),
+    }
+}
+
+/// Disconnects the given user, also banning their address if requested
+fn kick(shared: &SharedState, params: &Value, banned: bool) -> Result<Value, Error> {
+    let username = Username::from(string_param(params, "username")?);
+    if !shared.kick(&username, banned) {
+        return Err(Error::new(Error::NOT_FOUND, "User not found"));
+    }
+    Ok(Value::Bool(true))
+}
+
+fn to_value(value: impl Serialize) -> Result<Value, Error> {
+    serde_json::to_value(value).map_err(|err| Error::new(Error::INTERNAL_ERROR, err.to_string()))
+}
+
+fn string_param<'a>(params: &'a Value, name: &str) -> Result<&'a str, Error> {
+    params.get(name).and_then(Value::as_str).ok_or_else(|| {
+        Error::new(
+            Error::INVALID_PARAMS,
+            format!("Missing string parameter: {name}"),
+        )
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use std::net::Ipv4Addr;
+
+    use common::ServerEvent;
+
+    use super::*;
+    use crate::test_utils::TestServer;
+
+    #[tokio::test]
+    async fn kick_disconnects_the_user() {
+        let port = TcpListener::bind((Ipv4Addr::LOCALHOST, 0))
+            .await
+            .unwrap()
+            .local_addr()
+            .unwrap()
+            .port()
+            .to_string();
+        let server = TestServer::start(&["--control-port", &port]).await;
+        let mut alice = server.connect_as("alice").await;
+        let stream = TcpStream::connect(format!("127.0.0.1:{port}"))
+            .await
+            .unwrap();
+        let mut control = Framed::new(stream, LinesCodec::new());
+        let request = r#"{"jsonrpc":"2.0","id":1,"method":"kick","params":{"username":"alice"}}"#;
+        control.send(request).await.unwrap();
+        let response: Value =
+            serde_json::from_str(&control.next().await.unwrap().unwrap()).unwrap();
+        assert_eq!(response["id"], 1);
+        assert_eq!(response["result"], true);
+        let events = alice.recv_until_closed().await;
+        assert!(
+            events
+                .iter()
+                .any(|event| matches!(event, ServerEvent::Kicked { .. })),
+            "{events:?}"
+        );
+        server.shutdown().await;
+    }
+}
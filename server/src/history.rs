@@ -0,0 +1,261 @@
+use std::{
+    collections::VecDeque,
+    sync::{Arc, Mutex},
+};
+
+use tokio::time::Duration;
+
+use chrono::{DateTime, Utc};
+use common::{MessageId, Username};
+use serde::{Deserialize, Serialize};
+
+/// A message that was sent to a room
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct HistoryEntry {
+    pub id: MessageId,
+    pub username: Username,
+    pub text: String,
+    #[serde(default)]
+    pub mentions: Vec<Username>,
+    /// When the message was sent, also used for the edit window so that it survives restarts
+    #[serde(default)]
+    pub timestamp: DateTime<Utc>,
+}
+
+/// A user joining or leaving a room
+#[derive(Clone, Debug)]
+pub struct Movement {
+    /// Id of the next message at the time of the movement
+    pub position: u64,
+    pub username: Username,
+    pub joined: bool,
+}
+
+/// Bounded buffer of the most recent messages and movements of a room
+#[derive(Clone, Debug)]
+pub struct History {
+    entries: Arc<Mutex<VecDeque<HistoryEntry>>>,
+    movements: Arc<Mutex<VecDeque<Movement>>>,
+    capacity: usize,
+}
+
+impl History {
+    pub fn new(capacity: usize) -> Self {
+        Self {
+            entries: Arc::new(Mutex::new(VecDeque::with_capacity(capacity))),
+            movements: Arc::new(Mutex::new(VecDeque::with_capacity(capacity))),
+            capacity,
+        }
+    }
+
+    /// Appends an entry, evicting the oldest one if the buffer is full
+    pub fn push(&self, entry: HistoryEntry) {
+        let mut entries = self.entries.lock().unwrap();
+        entries.push_back(entry);
+        while entries.len() > self.capacity {
+            entries.pop_front();
+        }
+    }
+
+    /// Records a movement, evicting the oldest one if the buffer is full
+    pub fn push_movement(&self, movement: Movement) {
+        let mut movements = self.movements.lock().unwrap();
+        movements.push_back(movement);
+        while movements.len() > self.capacity {
+            movements.pop_front();
+        }
+    }
+
+    /// Returns the id of the most recent entry of the given user
+    pub fn last_id_by(&self, username: &Username) -> Option<MessageId> {
+        let entries = self.entries.lock().unwrap();
+        entries
+            .iter()
+            .rfind(|entry| &entry.username == username)
+            .map(|entry| entry.id)
+    }
+
+    /// Returns the entries that were added after the given message
+    ///
+    /// If no message is given, all entries are returned.
+    pub fn entries_after(&self, id: Option<MessageId>) -> Vec<HistoryEntry> {
+        let entries = self.entries.lock().unwrap();
+        entries
+            .iter()
+            .filter(|entry| id.is_none_or(|id| entry.id.as_u64() > id.as_u64()))
+            .cloned()
+            .collect()
+    }
+
+    /// Returns the movements that happened after the given message
+    ///
+    /// If no message is given, all movements are returned.
+    pub fn movements_after(&self, id: Option<MessageId>) -> Vec<Movement> {
+        let movements = self.movements.lock().unwrap();
+        movements
+            .iter()
+            .filter(|movement| id.is_none_or(|id| movement.position > id.as_u64()))
+            .cloned()
+            .collect()
+    }
+
+    /// Replaces the text of a message of the given user that was sent within the edit window
+    pub fn edit(
+        &self,
+        id: MessageId,
+        username: &Username,
+        text: &str,
+        window: Duration,
+    ) -> Result<(), &'static str> {
+        self.edit_at(id, username, text, window, Utc::now())
+    }
+
+    /// Same as [`History::edit`], with the given time as the current time
+    fn edit_at(
+        &self,
+        id: MessageId,
+        username: &Username,
+        text: &str,
+        window: Duration,
+        now: DateTime<Utc>,
+    ) -> Result<(), &'static str> {
+        let mut entries = self.entries.lock().unwrap();
+        let entry = entries
+            .iter_mut()
+            .find(|entry| entry.id == id)
+            .ok_or("Message not found")?;
+        if &entry.username != username {
+            return Err("You can only edit your own messages");
+        }
+        // a clock that went backwards counts as no time having passed
+        let age = (now - entry.timestamp).to_std().unwrap_or_default();
+        if age > window {
+            return Err("The message can no longer be edited");
+        }
+        entry.text = text.to_string();
+        Ok(())
+    }
+
+    /// Returns whether the given message is in the buffer
+    pub fn contains(&self, id: MessageId) -> bool {
+        let entries = self.entries.lock().unwrap();
+        entries.iter().any(|entry| entry.id == id)
+    }
+
+    /// Returns the given number of most recent entries, the oldest one first
+    pub fn recent(&self, count: usize) -> Vec<HistoryEntry> {
+        let entries = self.entries.lock().unwrap();
+        let skip = entries.len().saturating_sub(count);
+        entries.iter().skip(skip).cloned().collect()
+    }
+
+    /// Returns the most recent entries that contain the given text (ignoring case), up to the
+    /// given number of them
+    pub fn search(&self, query: &str, limit: usize) -> Vec<HistoryEntry> {
+        let query = query.to_lowercase();
+        let entries = self.entries.lock().unwrap();
+        let mut matches: Vec<HistoryEntry> = entries
+            .iter()
+            .rev()
+            .filter(|entry| entry.text.to_lowercase().contains(&query))
+            .take(limit)
+            .cloned()
+            .collect();
+        matches.reverse();
+        matches
+    }
+
+    /// Returns all entries, the oldest one first
+    pub fn entries(&self) -> Vec<HistoryEntry> {
+        self.entries.lock().unwrap().iter().cloned().collect()
+    }
+
+    /// Replaces all entries with the given ones, keeping the most recent ones that fit
+    pub fn replace(&self, new_entries: Vec<HistoryEntry>) {
+        let mut entries = self.entries.lock().unwrap();
+        let skip = new_entries.len().saturating_sub(self.capacity);
+        *entries = new_entries.into_iter().skip(skip).collect();
+    }
+
+    /// Removes all entries and movements
+    pub fn clear(&self) {
+        self.entries.lock().unwrap().clear();
+        self.movements.lock().unwrap().clear();
+    }
+
+    /// Removes the most recent entry sent by the given user
+    pub fn remove_last_by(&self, username: &Username) -> Option<HistoryEntry> {
+        let mut entries = self.entries.lock().unwrap();
+        let index = entries
+            .iter()
+            .rposition(|entry| &entry.username == username)?;
+        entries.remove(index)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn entry(id: u64, username: &str, text: &str) -> HistoryEntry {
+        HistoryEntry {
+            id: MessageId::new(id),
+            username: Username::from(username),
+            text: text.to_string(),
+            mentions: Vec::new(),
+            timestamp: Utc::now(),
+        }
+    }
+
+    const WINDOW: Duration = Duration::from_secs(60);
+
+    #[test]
+    fn edit_within_the_window() {
+        let history = History::new(10);
+        history.push(entry(0, "alice", "helo"));
+        let alice = Username::from("alice");
+        history
+            .edit(MessageId::new(0), &alice, "hello", WINDOW)
+            .unwrap();
+        assert_eq!(history.entries()[0].text, "hello");
+        assert_eq!(
+            history.edit(MessageId::new(0), &Username::from("bob"), "hi", WINDOW),
+            Err("You can only edit your own messages")
+        );
+        assert_eq!(
+            history.edit(MessageId::new(1), &alice, "hi", WINDOW),
+            Err("Message not found")
+        );
+    }
+
+    #[test]
+    fn edits_end_with_the_window() {
+        let history = History::new(10);
+        let sent = entry(0, "alice", "helo");
+        let timestamp = sent.timestamp;
+        history.push(sent);
+        let alice = Username::from("alice");
+        let edit_after = |seconds| {
+            let now = timestamp + chrono::Duration::seconds(seconds);
+            history.edit_at(MessageId::new(0), &alice, "hello", WINDOW, now)
+        };
+        assert_eq!(edit_after(59), Ok(()));
+        assert_eq!(edit_after(60), Ok(()));
+        assert_eq!(edit_after(61), Err("The message can no longer be edited"));
+        // a clock that went backwards
+        assert_eq!(edit_after(-10), Ok(()));
+    }
+
+    #[test]
+    fn restored_messages_keep_their_age() {
+        let mut old = entry(0, "alice", "helo");
+        old.timestamp -= chrono::Duration::minutes(5);
+        let saved = serde_json::to_string(&old).unwrap();
+        let history = History::new(10);
+        history.replace(vec![serde_json::from_str(&saved).unwrap()]);
+        assert_eq!(
+            history.edit(MessageId::new(0), &Username::from("alice"), "hello", WINDOW),
+            Err("The message can no longer be edited")
+        );
+    }
+}
@@ -0,0 +1,168 @@
+use std::{fmt::Debug, io, net::IpAddr, sync::Arc};
+
+use dashmap::{mapref::entry::Entry, DashMap};
+use tokio::time::{Duration, Instant};
+
+/// A service that looks up the hostnames of addresses (reverse DNS)
+pub trait Resolver: Debug + Send + Sync {
+    /// Looks up the hostname of the given address, blocking until it is known
+    fn lookup(&self, ip: IpAddr) -> io::Result<String>;
+}
+
+/// Resolver that uses the DNS configuration of the system
+#[derive(Clone, Copy, Debug, Default)]
+pub struct DnsResolver;
+
+impl Resolver for DnsResolver {
+    fn lookup(&self, ip: IpAddr) -> io::Result<String> {
+        dns_lookup::lookup_addr(&ip)
+    }
+}
+
+/// A looked up hostname, `None` while the lookup is in progress or if it failed
+#[derive(Clone, Debug)]
+struct CachedHostname {
+    hostname: Option<String>,
+    looked_up_at: Instant,
+}
+
+/// Cache of reverse DNS lookups for the addresses of connected users
+#[derive(Clone, Debug)]
+pub struct Hostnames {
+    inner: Arc<DashMap<IpAddr, CachedHostname>>,
+    resolver: Arc<dyn Resolver>,
+    /// How long the lookups are cached for
+    ttl: Duration,
+}
+
+impl Default for Hostnames {
+    fn default() -> Self {
+        Self::new(Arc::new(DnsResolver), Self::DEFAULT_TTL)
+    }
+}
+
+impl Hostnames {
+    const DEFAULT_TTL: Duration = Duration::from_secs(60 * 60);
+
+    pub fn new(resolver: Arc<dyn Resolver>, ttl: Duration) -> Self {
+        Self {
+            inner: Arc::default(),
+            resolver,
+            ttl,
+        }
+    }
+
+    /// Returns the hostname of the given address if it has been resolved
+    pub fn get(&self, ip: &IpAddr) -> Option<String> {
+        self.inner.get(ip).and_then(|entry| entry.hostname.clone())
+    }
+
+    /// Resolves the hostname of the given address in the background
+    ///
+    /// Each address is only looked up once until the lookup expires, failed lookups are cached
+    /// as well. The expired lookups are dropped, so the cache only grows with the number of
+    /// addresses that connect within the TTL.
+    pub fn resolve(&self, ip: IpAddr) {
+        self.inner
+            .retain(|_, cached| cached.looked_up_at.elapsed() < self.ttl);
+        match self.inner.entry(ip) {
+            Entry::Occupied(_) => return,
+            Entry::Vacant(entry) => {
+                entry.insert(CachedHostname {
+                    hostname: None,
+                    looked_up_at: Instant::now(),
+                });
+            }
+        }
+        let inner = self.inner.clone();
+        let resolver = self.resolver.clone();
+        tokio::spawn(async move {
+            match tokio::task::spawn_blocking(move || resolver.lookup(ip)).await {
+                Ok(Ok(hostname)) => {
+                    tracing::debug!("Resolved {ip} to {hostname}");
+                    if let Some(mut cached) = inner.get_mut(&ip) {
+                        cached.hostname = Some(hostname);
+                    }
+                }
+                Ok(Err(err)) => tracing::debug!("Failed to resolve {ip}: {err}"),
+                Err(err) => tracing::error!("Hostname lookup failed: {err}"),
+            }
+        });
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        net::Ipv4Addr,
+        sync::atomic::{AtomicUsize, Ordering},
+    };
+
+    use tokio::time;
+
+    use super::*;
+
+    /// Resolves every address except the unspecified one, counting the lookups
+    #[derive(Debug, Default)]
+    struct StubResolver {
+        lookups: AtomicUsize,
+    }
+
+    impl Resolver for StubResolver {
+        fn lookup(&self, ip: IpAddr) -> io::Result<String> {
+            self.lookups.fetch_add(1, Ordering::Relaxed);
+            if ip.is_unspecified() {
+                return Err(io::Error::other("no such host"));
+            }
+            Ok(format!("host-{ip}"))
+        }
+    }
+
+    async fn wait_until(condition: impl Fn() -> bool) {
+        time::timeout(Duration::from_secs(5), async {
+            while !condition() {
+                time::sleep(Duration::from_millis(1)).await;
+            }
+        })
+        .await
+        .expect("timed out waiting for the lookup");
+    }
+
+    fn lookups(resolver: &StubResolver) -> usize {
+        resolver.lookups.load(Ordering::Relaxed)
+    }
+
+    #[tokio::test]
+    async fn lookups_are_cached() {
+        let resolver = Arc::new(StubResolver::default());
+        let hostnames = Hostnames::new(resolver.clone(), Duration::from_secs(60));
+        let ip = IpAddr::from(Ipv4Addr::LOCALHOST);
+        hostnames.resolve(ip);
+        wait_until(|| hostnames.get(&ip).is_some()).await;
+        assert_eq!(hostnames.get(&ip).as_deref(), Some("host-127.0.0.1"));
+
+        let unresolvable = IpAddr::from(Ipv4Addr::UNSPECIFIED);
+        hostnames.resolve(unresolvable);
+        wait_until(|| lookups(&resolver) == 2).await;
+        assert_eq!(hostnames.get(&unresolvable), None);
+
+        hostnames.resolve(ip);
+        hostnames.resolve(unresolvable);
+        time::sleep(Duration::from_millis(10)).await;
+        assert_eq!(lookups(&resolver), 2);
+    }
+
+    #[tokio::test]
+    async fn expired_lookups_are_repeated() {
+        let resolver = Arc::new(StubResolver::default());
+        let hostnames = Hostnames::new(resolver.clone(), Duration::ZERO);
+        let ip = IpAddr::from(Ipv4Addr::LOCALHOST);
+        hostnames.resolve(ip);
+        wait_until(|| lookups(&resolver) == 1).await;
+        hostnames.resolve(ip);
+        wait_until(|| lookups(&resolver) == 2).await;
+        hostnames.resolve(IpAddr::from(Ipv4Addr::BROADCAST));
+        // the expired lookups were dropped
+        assert_eq!(hostnames.inner.len(), 1);
+    }
+}
@@ -0,0 +1,100 @@
+use common::RoomEvent;
+
+/// Turn order tracker of a room
+#[derive(Clone, Debug, Default)]
+pub struct Initiative {
+    /// The participants sorted by their initiative value in descending order
+    entries: Vec<(String, i32)>,
+    /// The index of the participant whose turn it is
+    turn: Option<usize>,
+}
+
+impl Initiative {
+    /// Adds a participant, keeping the insertion order of equal values
+    pub fn add(&mut self, name: String, value: i32) {
+        let index = self.entries.partition_point(|(_, other)| *other >= value);
+        self.entries.insert(index, (name, value));
+        if let Some(turn) = self.turn.as_mut() {
+            if index <= *turn {
+                *turn += 1;
+            }
+        }
+    }
+
+    /// Advances to the next participant, wrapping around at the end
+    pub fn next(&mut self) {
+        if self.entries.is_empty() {
+            return;
+        }
+        self.turn = Some(match self.turn {
+            Some(turn) => (turn + 1) % self.entries.len(),
+            None => 0,
+        });
+    }
+
+    pub fn clear(&mut self) {
+        self.entries.clear();
+        self.turn = None;
+    }
+
+    pub fn to_event(&self) -> RoomEvent {
+        RoomEvent::initiative(self.entries.clone(), self.turn)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn order(initiative: &Initiative) -> (Vec<&str>, Option<usize>) {
+        let names = initiative
+            .entries
+            .iter()
+            .map(|(name, _)| name.as_str())
+            .collect();
+        (names, initiative.turn)
+    }
+
+    #[test]
+    fn participants_are_sorted_by_initiative() {
+        let mut initiative = Initiative::default();
+        for (name, value) in [("goblin", 5), ("alice", 12), ("orc", 5), ("bob", -1)] {
+            initiative.add(name.to_string(), value);
+        }
+        assert_eq!(
+            order(&initiative),
+            (vec!["alice", "goblin", "orc", "bob"], None)
+        );
+    }
+
+    #[test]
+    fn turns_wrap_around() {
+        let mut initiative = Initiative::default();
+        initiative.next();
+        assert_eq!(initiative.turn, None);
+        initiative.add("alice".to_string(), 10);
+        initiative.add("bob".to_string(), 5);
+        initiative.next();
+        assert_eq!(initiative.turn, Some(0));
+        initiative.next();
+        assert_eq!(initiative.turn, Some(1));
+        initiative.next();
+        assert_eq!(initiative.turn, Some(0));
+        initiative.clear();
+        assert_eq!(order(&initiative), (vec![], None));
+    }
+
+    #[test]
+    fn adding_keeps_the_current_turn() {
+        let mut initiative = Initiative::default();
+        initiative.add("alice".to_string(), 10);
+        initiative.add("bob".to_string(), 5);
+        initiative.next();
+        initiative.next();
+        // bob's turn, even after someone is added before him
+        initiative.add("carol".to_string(), 20);
+        assert_eq!(order(&initiative), (vec!["carol", "alice", "bob"], Some(2)));
+        initiative.add("dave".to_string(), 1);
+        assert_eq!(initiative.turn, Some(2));
+    }
+}
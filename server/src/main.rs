@@ -1,28 +1,60 @@
 use clap::{
-    builder::{styling::AnsiColor, Styles},
-    Parser,
+    builder::{styling::AnsiColor, NonEmptyStringValueParser, Styles},
+    parser::ValueSource,
+    ArgMatches, CommandFactory, FromArgMatches, Parser,
 };
 use clap_verbosity_flag::{InfoLevel, Verbosity};
-use std::net::{IpAddr, Ipv4Addr, SocketAddr};
+use std::{
+    fmt,
+    net::{IpAddr, Ipv4Addr, SocketAddr},
+    path::PathBuf,
+    time::Duration,
+};
 use tracing::level_filters::LevelFilter;
 use tracing_log::AsTrace;
 use tracing_subscriber::EnvFilter;
 
-use self::server::Server;
+use self::{
+    config::{Config, DuplicateNamePolicy, ServerConfig},
+    server::Server,
+};
 
+#[cfg(unix)]
+mod admin;
+mod config;
 mod connection;
+mod control;
+mod history;
+mod hostnames;
+mod initiative;
+mod presence;
 mod room;
 mod rooms;
 mod server;
+mod sessions;
+mod snapshot;
+mod state;
+mod storage;
+#[cfg(test)]
+mod test_utils;
+mod throttle;
+mod tls;
+mod transfers;
+mod translator;
+mod transport;
 mod users;
 
 #[tokio::main]
 async fn main() -> Result<(), anyhow::Error> {
-    let args = Args::parse();
+    let matches = Args::command().get_matches();
+    let mut args = Args::from_arg_matches(&matches)?;
+    if let Some(path) = args.config.clone() {
+        args.merge(ServerConfig::load(&path)?, &matches)?;
+    }
     let level = args.verbosity.log_level_filter().as_trace();
     init_tracing(level);
     tracing::debug!("Starting server with args: {:#?}", args);
-    let server = Server::listen(args.address()).await?;
+    let server = Server::listen(args.address(), args.config()).await?;
     server.run().await;
     Ok(())
 }
@@ -33,9 +65,15 @@ const STYLES: Styles = Styles::styled()
     .literal(AnsiColor::White.on_default())
     .placeholder(AnsiColor::Green.on_default());
 
-#[derive(Debug, Parser)]
+#[derive(Parser)]
 #[command(styles = STYLES)]
 pub struct Args {
+    /// TOML file with the settings, using the names of the flags as keys (e.g. max-users = 10)
+    ///
+    /// The flags that are given on the command line take precedence over the file.
+    #[arg(short, long)]
+    config: Option<PathBuf>,
+
     /// The IP address to listen on
     #[arg(short, long, default_value_t = Ipv4Addr::LOCALHOST.into())]
     ip: IpAddr,
@@ -44,6 +82,155 @@ pub struct Args {
     #[arg(short, long, default_value_t = 42069)]
     port: u16,
 
+    /// The password for gaining operator privileges with /oper
+    #[arg(long)]
+    oper_password: Option<String>,
+
+    /// The maximum size of a transferred file in bytes
+    #[arg(long, default_value_t = 10 * 1024 * 1024)]
+    max_file_size: usize,
+
+    /// Seconds without new chunks after which a file transfer is aborted (0 to disable)
+    #[arg(long, default_value_t = 30)]
+    transfer_timeout: u64,
+
+    /// The maximum length of a line received from a user in bytes
+    #[arg(long, default_value_t = 16 * 1024 * 1024)]
+    max_line_length: usize,
+
+    /// Resolve the hostnames of connected users (reverse DNS)
+    #[arg(long)]
+    resolve_hostnames: bool,
+
+    /// Announce users coming back from being away
+    #[arg(long)]
+    announce_returns: bool,
+
+    /// The prefix that marks a message as a command (double it to send it literally)
+    #[arg(long, default_value = "/", value_parser = NonEmptyStringValueParser::new())]
+    command_prefix: String,
+
+    /// Seed for the random number generator, useful for deterministic runs
+    #[arg(long)]
+    seed: Option<u64>,
+
+    /// Comma separated list of the commands that users are allowed to run (e.g. "join,users")
+    #[arg(long, value_delimiter = ',')]
+    allowed_commands: Option<Vec<String>>,
+
+    /// Seconds after which empty rooms are deleted (0 to disable)
+    #[arg(long, default_value_t = 300)]
+    idle_room_ttl: u64,
+
+    /// The maximum number of users that can be connected at the same time
+    #[arg(long)]
+    max_users: Option<usize>,
+
+    /// What happens when a user takes a name that is already in use
+    #[arg(long, value_enum, default_value_t)]
+    duplicate_names: DuplicateNamePolicy,
+
+    /// The maximum number of connections per minute from the same address
+    #[arg(long)]
+    max_connections_per_minute: Option<usize>,
+
+    /// The maximum number of lines per second that a user can send
+    ///
+    /// Replies to pings and the chunks of announced files are not counted.
+    #[arg(long)]
+    max_messages_per_second: Option<u32>,
+
+    /// The maximum number of bytes per second that a user can send
+    ///
+    /// Replies to pings and the chunks of announced files are not counted.
+    #[arg(long)]
+    max_bytes_per_second: Option<usize>,
+
+    /// Seconds that a user exceeding a rate limit is muted for
+    #[arg(long, default_value_t = 10)]
+    rate_limit_mute: u64,
+
+    /// Port to also accept WebSocket connections on (same address and protocol)
+    #[arg(long)]
+    websocket_port: Option<u16>,
+
+    /// Port of the JSON-RPC control socket, only reachable from localhost
+    #[arg(long)]
+    control_port: Option<u16>,
+
+    /// Token that the requests to the control socket have to include
+    #[arg(long)]
+    control_token: Option<String>,
+
+    /// Path of the Unix socket of the plain text admin console
+    #[cfg(unix)]
+    #[arg(long)]
+    admin_socket: Option<PathBuf>,
+
+    /// Milliseconds to buffer outgoing events for before sending them (0 to send immediately)
+    #[arg(long, default_value_t = 0)]
+    flush_interval: u64,
+
+    /// The number of recent messages sent to users joining a room (0 to disable)
+    #[arg(long, default_value_t = 20)]
+    history_replay: usize,
+
+    /// The number of recent messages that each room keeps
+    #[arg(long, default_value_t = 100)]
+    history_size: usize,
+
+    /// Message of the day that is sent to users when they connect
+    #[arg(long)]
+    motd: Option<String>,
+
+    /// Seconds after sending a message during which it can be edited (0 to disable)
+    #[arg(long, default_value_t = 300)]
+    edit_window: u64,
+
+    /// Seconds of idleness before TCP keepalive probes are sent (0 to disable)
+    ///
+    /// Disabled by default since the clients that announce themselves with /hello are pinged
+    /// (see --heartbeat-interval), which also detects the connections that are stuck above TCP.
+    #[arg(long, default_value_t = 0)]
+    keepalive_idle: u64,
+
+    /// Seconds between TCP keepalive probes
+    #[arg(long, default_value_t = 10)]
+    keepalive_interval: u64,
+
+    /// Seconds between the pings that are sent to detect dead connections (0 to disable)
+    ///
+    /// Only the clients that announced themselves with /hello are pinged.
+    #[arg(long, default_value_t = 30)]
+    heartbeat_interval: u64,
+
+    /// The number of unanswered pings after which a connection is closed
+    #[arg(long, default_value_t = 3)]
+    max_missed_heartbeats: u32,
+
+    /// Directory that the rooms are saved to, to restore them after a restart
+    ///
+    /// The room passwords are saved as well, so the directory should only be readable by the
+    /// server.
+    #[arg(long)]
+    data_dir: Option<PathBuf>,
+
+    /// Minimum seconds between saves of the rooms, the changes in between are saved together
+    #[arg(long, default_value_t = 1)]
+    save_interval: u64,
+
+    /// Seconds to wait for the connections to close when shutting down
+    #[arg(long, default_value_t = 5)]
+    shutdown_grace_period: u64,
+
+    /// PEM file with the TLS certificate chain (enables TLS)
+    #[arg(long, requires = "key")]
+    cert: Option<PathBuf>,
+
+    /// PEM file with the private key of the TLS certificate
+    #[arg(long, requires = "cert")]
+    key: Option<PathBuf>,
+
     /// Verbosity flags
     ///
     /// Automatically parses one or more --verbose and --quiet flags to set the log level.
@@ -52,10 +239,215 @@ pub struct Args {
     verbosity: Verbosity<InfoLevel>,
 }
 
+// the secrets are redacted since the arguments are logged on startup
+impl fmt::Debug for Args {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        // destructured so that new fields can't be forgotten
+        let Self {
+            config,
+            ip,
+            port,
+            oper_password,
+            max_file_size,
+            transfer_timeout,
+            max_line_length,
+            resolve_hostnames,
+            announce_returns,
+            command_prefix,
+            seed,
+            allowed_commands,
+            idle_room_ttl,
+            max_users,
+            duplicate_names,
+            max_connections_per_minute,
+            max_messages_per_second,
+            max_bytes_per_second,
+            rate_limit_mute,
+            websocket_port,
+            control_port,
+            control_token,
+            #[cfg(unix)]
+            admin_socket,
+            flush_interval,
+            history_replay,
+            history_size,
+            motd,
+            edit_window,
+            keepalive_idle,
+            keepalive_interval,
+            heartbeat_interval,
+            max_missed_heartbeats,
+            data_dir,
+            save_interval,
+            shutdown_grace_period,
+            cert,
+            key,
+            verbosity,
+        } = self;
+        let mut debug = f.debug_struct("Args");
+        debug.field("config", config);
+        debug.field("ip", ip);
+        debug.field("port", port);
+        debug.field(
+            "oper_password",
+            &oper_password.as_ref().map(|_| "<redacted>"),
+        );
+        debug.field("max_file_size", max_file_size);
+        debug.field("transfer_timeout", transfer_timeout);
+        debug.field("max_line_length", max_line_length);
+        debug.field("resolve_hostnames", resolve_hostnames);
+        debug.field("announce_returns", announce_returns);
+        debug.field("command_prefix", command_prefix);
+        debug.field("seed", seed);
+        debug.field("allowed_commands", allowed_commands);
+        debug.field("idle_room_ttl", idle_room_ttl);
+        debug.field("max_users", max_users);
+        debug.field("duplicate_names", duplicate_names);
+        debug.field("max_connections_per_minute", max_connections_per_minute);
+        debug.field("max_messages_per_second", max_messages_per_second);
+        debug.field("max_bytes_per_second", max_bytes_per_second);
+        debug.field("rate_limit_mute", rate_limit_mute);
+        debug.field("websocket_port", websocket_port);
+        debug.field("control_port", control_port);
+        debug.field(
+            "control_token",
+            &control_token.as_ref().map(|_| "<redacted>"),
+        );
+        #[cfg(unix)]
+        debug.field("admin_socket", admin_socket);
+        debug.field("flush_interval", flush_interval);
+        debug.field("history_replay", history_replay);
+        debug.field("history_size", history_size);
+        debug.field("motd", motd);
+        debug.field("edit_window", edit_window);
+        debug.field("keepalive_idle", keepalive_idle);
+        debug.field("keepalive_interval", keepalive_interval);
+        debug.field("heartbeat_interval", heartbeat_interval);
+        debug.field("max_missed_heartbeats", max_missed_heartbeats);
+        debug.field("data_dir", data_dir);
+        debug.field("save_interval", save_interval);
+        debug.field("shutdown_grace_period", shutdown_grace_period);
+        debug.field("cert", cert);
+        debug.field("key", key);
+        debug.field("verbosity", verbosity);
+        debug.finish()
+    }
+}
+
 impl Args {
     pub fn address(&self) -> SocketAddr {
         SocketAddr::new(self.ip, self.port)
     }
+
+    /// Takes the settings from the given config file, except for the flags that were given on the
+    /// command line
+    fn merge(&mut self, file: ServerConfig, matches: &ArgMatches) -> anyhow::Result<()> {
+        let from_command_line =
+            |id: &str| matches.value_source(id) == Some(ValueSource::CommandLine);
+        macro_rules! merge {
+            ($($field:ident),* $(,)?) => {
+                $(
+                    if let Some(value) = file.$field {
+                        if !from_command_line(stringify!($field)) {
+                            self.$field = value.into();
+                        }
+                    }
+                )*
+            };
+        }
+        merge!(
+            ip,
+            port,
+            oper_password,
+            max_file_size,
+            transfer_timeout,
+            max_line_length,
+            resolve_hostnames,
+            announce_returns,
+            command_prefix,
+            seed,
+            allowed_commands,
+            idle_room_ttl,
+            max_users,
+            duplicate_names,
+            max_connections_per_minute,
+            max_messages_per_second,
+            max_bytes_per_second,
+            rate_limit_mute,
+            websocket_port,
+            control_port,
+            control_token,
+            flush_interval,
+            history_replay,
+            history_size,
+            motd,
+            edit_window,
+            keepalive_idle,
+            keepalive_interval,
+            heartbeat_interval,
+            max_missed_heartbeats,
+            data_dir,
+            save_interval,
+            shutdown_grace_period,
+            cert,
+            key,
+        );
+        #[cfg(unix)]
+        merge!(admin_socket);
+        // the checks of the flags that the file can bypass
+        if self.command_prefix.is_empty() {
+            anyhow::bail!("The command prefix can't be empty");
+        }
+        if self.cert.is_some() != self.key.is_some() {
+            anyhow::bail!("The TLS certificate and key have to be given together");
+        }
+        Ok(())
+    }
+
+    pub fn config(&self) -> Config {
+        Config {
+            command_prefix: self.command_prefix.clone(),
+            oper_password: self.oper_password.clone(),
+            max_file_size: self.max_file_size,
+            transfer_timeout: (self.transfer_timeout > 0)
+                .then(|| Duration::from_secs(self.transfer_timeout)),
+            max_line_length: self.max_line_length,
+            resolve_hostnames: self.resolve_hostnames,
+            announce_returns: self.announce_returns,
+            seed: self.seed,
+            allowed_commands: self.allowed_commands.clone(),
+            max_users: self.max_users,
+            duplicate_names: self.duplicate_names,
+            max_connections_per_minute: self.max_connections_per_minute,
+            max_messages_per_second: self.max_messages_per_second,
+            max_bytes_per_second: self.max_bytes_per_second,
+            rate_limit_mute: Duration::from_secs(self.rate_limit_mute),
+            websocket_port: self.websocket_port,
+            control_port: self.control_port,
+            control_token: self.control_token.clone(),
+            #[cfg(unix)]
+            admin_socket: self.admin_socket.clone(),
+            idle_room_ttl: (self.idle_room_ttl > 0)
+                .then(|| Duration::from_secs(self.idle_room_ttl)),
+            flush_interval: (self.flush_interval > 0)
+                .then(|| Duration::from_millis(self.flush_interval)),
+            history_replay: self.history_replay,
+            history_size: self.history_size,
+            motd: self.motd.clone(),
+            edit_window: (self.edit_window > 0).then(|| Duration::from_secs(self.edit_window)),
+            keepalive_idle: (self.keepalive_idle > 0)
+                .then(|| Duration::from_secs(self.keepalive_idle)),
+            keepalive_interval: Duration::from_secs(self.keepalive_interval),
+            heartbeat_interval: (self.heartbeat_interval > 0)
+                .then(|| Duration::from_secs(self.heartbeat_interval)),
+            max_missed_heartbeats: self.max_missed_heartbeats,
+            data_dir: self.data_dir.clone(),
+            save_interval: Duration::from_secs(self.save_interval),
+            shutdown_grace_period: Duration::from_secs(self.shutdown_grace_period),
+            tls_cert: self.cert.clone(),
+            tls_key: self.key.clone(),
+        }
+    }
 }
 
 pub fn init_tracing(level_filter: LevelFilter) {
@@ -68,3 +460,65 @@ pub fn init_tracing(level_filter: LevelFilter) {
         .without_time()
         .init();
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Parses the given flags and merges the given config file into them
+    fn args_with_file(flags: &[&str], file: &str) -> anyhow::Result<Args> {
+        let matches = Args::command().try_get_matches_from(["server"].iter().chain(flags))?;
+        let mut args = Args::from_arg_matches(&matches)?;
+        args.merge(toml::from_str(file)?, &matches)?;
+        Ok(args)
+    }
+
+    #[test]
+    fn flags_take_precedence_over_the_file() {
+        let file = r#"
+            port = 4242
+            max-users = 100
+            motd = "From the file"
+        "#;
+        let args = args_with_file(&["--port", "4343", "--motd", "From the flags"], file).unwrap();
+        assert_eq!(args.port, 4343);
+        assert_eq!(args.max_users, Some(100));
+        assert_eq!(args.motd.as_deref(), Some("From the flags"));
+
+        // the defaults of the flags don't count as given
+        let args = args_with_file(&[], file).unwrap();
+        assert_eq!(args.port, 4242);
+        assert_eq!(args.motd.as_deref(), Some("From the file"));
+    }
+
+    #[test]
+    fn files_cannot_bypass_the_checks_of_the_flags() {
+        let err = args_with_file(&[], r#"command-prefix = """#).unwrap_err();
+        assert_eq!(err.to_string(), "The command prefix can't be empty");
+        let file = r#"
+            cert = "cert.pem"
+            key = "key.pem"
+        "#;
+        assert!(args_with_file(&[], file).unwrap().config().is_tls_enabled());
+        let err = args_with_file(&[], r#"cert = "cert.pem""#).unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "The TLS certificate and key have to be given together"
+        );
+    }
+
+    #[test]
+    fn debug_redacts_secrets() {
+        let args = Args::parse_from([
+            "server",
+            "--oper-password",
+            "hunter2",
+            "--control-token",
+            "letmein",
+        ]);
+        let debug = format!("{args:?}");
+        assert!(!debug.contains("hunter2"));
+        assert!(!debug.contains("letmein"));
+        assert!(debug.contains("oper_password: Some(\"<redacted>\")"));
+    }
+}
@@ -0,0 +1,84 @@
+use std::sync::Arc;
+
+use common::{UserStatus, Username};
+use dashmap::DashMap;
+
+/// The statuses of the users that are not simply online
+#[derive(Clone, Debug, Default)]
+pub struct Presence {
+    inner: Arc<DashMap<Username, (UserStatus, Option<String>)>>,
+}
+
+impl Presence {
+    /// Returns the status of the given user along with its message
+    pub fn get(&self, username: &Username) -> (UserStatus, Option<String>) {
+        self.inner
+            .get(username)
+            .map(|entry| entry.value().clone())
+            .unwrap_or_default()
+    }
+
+    /// Sets the status of the given user, returning whether it changed
+    pub fn set(&self, username: &Username, status: UserStatus, message: Option<String>) -> bool {
+        let previous = if status == UserStatus::Online && message.is_none() {
+            self.inner.remove(username).map(|(_, previous)| previous)
+        } else {
+            self.inner
+                .insert(username.clone(), (status, message.clone()))
+        };
+        previous.unwrap_or_default() != (status, message)
+    }
+
+    pub fn rename(&self, old_name: &Username, new_name: &Username) {
+        if let Some((_, status)) = self.inner.remove(old_name) {
+            self.inner.insert(new_name.clone(), status);
+        }
+    }
+
+    pub fn remove(&self, username: &Username) {
+        self.inner.remove(username);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn statuses_are_kept_until_back_online() {
+        let presence = Presence::default();
+        let alice = Username::from("alice");
+        assert_eq!(presence.get(&alice), (UserStatus::Online, None));
+        let lunch = Some("lunch".to_string());
+        presence.set(&alice, UserStatus::Away, lunch.clone());
+        assert_eq!(presence.get(&alice), (UserStatus::Away, lunch));
+        presence.set(&alice, UserStatus::Online, None);
+        assert_eq!(presence.get(&alice), (UserStatus::Online, None));
+        assert!(presence.inner.is_empty());
+    }
+
+    #[test]
+    fn only_changes_are_reported() {
+        let presence = Presence::default();
+        let alice = Username::from("alice");
+        assert!(!presence.set(&alice, UserStatus::Online, None));
+        let lunch = Some("lunch".to_string());
+        assert!(presence.set(&alice, UserStatus::Away, lunch.clone()));
+        assert!(!presence.set(&alice, UserStatus::Away, lunch));
+        assert!(presence.set(&alice, UserStatus::Away, None));
+        assert!(presence.set(&alice, UserStatus::Online, None));
+        assert!(!presence.set(&alice, UserStatus::Online, None));
+    }
+
+    #[test]
+    fn statuses_follow_renames() {
+        let presence = Presence::default();
+        let [alice, bob] = ["alice", "bob"].map(Username::from);
+        presence.set(&alice, UserStatus::Away, None);
+        presence.rename(&alice, &bob);
+        assert_eq!(presence.get(&alice), (UserStatus::Online, None));
+        assert_eq!(presence.get(&bob), (UserStatus::Away, None));
+        presence.remove(&bob);
+        assert_eq!(presence.get(&bob), (UserStatus::Online, None));
+    }
+}
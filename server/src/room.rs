@@ -1,68 +1,189 @@
-use std::fmt;
+use std::{
+    fmt,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc, Mutex, RwLock,
+    },
+};
 
-use common::{RoomName, ServerEvent, Username};
+use chrono::Utc;
+use common::{MessageId, RoomName, ServerEvent, Username};
 use itertools::Itertools;
-use tokio::sync::broadcast::{self, Receiver, Sender};
+use tokio::{
+    sync::{
+        broadcast::{self, Receiver, Sender},
+        Notify,
+    },
+    time::{Duration, Instant},
+};
 
 use common::RoomEvent;
 
-use crate::users::Users;
+use crate::{
+    history::{History, HistoryEntry, Movement},
+    initiative::Initiative,
+    snapshot::RoomSnapshot,
+    users::Users,
+};
 
 #[derive(Debug, Clone)]
 pub struct Room {
-    name: RoomName,
+    name: Arc<RwLock<RoomName>>,
     events: Sender<ServerEvent>,
     users: Users,
+    history: History,
+    next_message_id: Arc<AtomicU64>,
+    /// Notified whenever an event is sent to the room, i.e. its state might have changed
+    changes: Arc<Notify>,
+    initiative: Arc<Mutex<Initiative>>,
+    owner: Arc<Mutex<Option<Username>>>,
+    /// The users that were made moderators by the owner, for as long as they are in the room
+    moderators: Users,
+    /// The users that can't join the room (by name)
+    banned: Users,
+    /// The users that can't send messages to the room
+    muted: Users,
+    /// The password that is required for joining the room
+    password: Option<Arc<str>>,
+    empty_since: Arc<Mutex<Option<Instant>>>,
 }
 
 impl fmt::Display for Room {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
-        write!(f, "{}", self.name)
+        write!(f, "{}", self.name())
     }
 }
 
 impl Room {
     pub(crate) const ROOM_CHANNEL_CAPACITY: usize = 1024;
+    pub(crate) const SEARCH_RESULTS_LIMIT: usize = 50;
 
     /// Create a new room with the given name
-    pub(crate) fn new(room_name: RoomName) -> Self {
+    ///
+    /// The ids of the messages are allocated from the given counter, the changes are signalled to
+    /// the given notifier and the given number of recent messages are kept. If a password is
+    /// given, the room can only be joined with it.
+    pub(crate) fn new(
+        room_name: RoomName,
+        next_message_id: Arc<AtomicU64>,
+        changes: Arc<Notify>,
+        history_capacity: usize,
+        password: Option<&str>,
+    ) -> Self {
         tracing::debug!("Creating room {room_name}");
         let (events, _) = broadcast::channel(Self::ROOM_CHANNEL_CAPACITY);
         Self {
-            name: room_name,
+            name: Arc::new(RwLock::new(room_name)),
             events,
             users: Users::default(),
+            history: History::new(history_capacity),
+            next_message_id,
+            changes,
+            initiative: Arc::default(),
+            owner: Arc::default(),
+            moderators: Users::default(),
+            banned: Users::default(),
+            muted: Users::default(),
+            password: password.map(Arc::from),
+            empty_since: Arc::new(Mutex::new(Some(Instant::now()))),
         }
     }
 
     /// Returns the name of the room
-    pub fn name(&self) -> &RoomName {
-        &self.name
+    pub fn name(&self) -> RoomName {
+        self.name.read().unwrap().clone()
+    }
+
+    /// Changes the name of the room
+    ///
+    /// The room has to be re-registered under the new name in [`Rooms`](crate::rooms::Rooms).
+    pub(crate) fn set_name(&self, room_name: RoomName) {
+        *self.name.write().unwrap() = room_name;
+    }
+
+    /// Returns whether the given room is this room (rather than one with the same name)
+    pub fn is_same(&self, other: &Room) -> bool {
+        Arc::ptr_eq(&self.name, &other.name)
+    }
+
+    /// Returns whether the given user owns the room
+    pub fn is_owner(&self, username: &Username) -> bool {
+        self.owner.lock().unwrap().as_ref() == Some(username)
+    }
+
+    /// Returns whether the given user owns the room or was made a moderator
+    pub fn is_moderator(&self, username: &Username) -> bool {
+        self.is_owner(username) || self.moderators.contains(username)
+    }
+
+    /// Returns whether the given user is banned from the room
+    pub fn is_banned(&self, username: &Username) -> bool {
+        self.banned.contains(username)
+    }
+
+    /// Checks whether the given password allows joining the room
+    pub fn check_password(&self, password: Option<&str>) -> Result<(), &'static str> {
+        match (self.password.as_deref(), password) {
+            (None, _) => Ok(()),
+            (Some(_), None) => Err("The room is locked, a password is required"),
+            (Some(expected), Some(password)) if expected == password => Ok(()),
+            (Some(_), Some(_)) => Err("Wrong password"),
+        }
     }
 
     /// Adds the specified user to the room
     pub fn join(&self, username: &Username) -> Receiver<ServerEvent> {
         tracing::debug!("User {username} joining room {self}");
         self.users.insert(username);
+        *self.empty_since.lock().unwrap() = None;
+        if !self.is_lobby() {
+            self.owner
+                .lock()
+                .unwrap()
+                .get_or_insert_with(|| username.clone());
+        }
+        self.record_movement(username, true);
         let events = self.events.subscribe();
-        self.send_event(username, RoomEvent::joined(&self.name));
+        self.send_event(username, RoomEvent::joined(&self.name()));
         events
     }
 
-    /// Removes the specified user from the room
-    pub fn leave(&self, username: &Username) {
+    /// Removes the specified user from the room with an optional farewell message
+    pub fn leave(&self, username: &Username, farewell: Option<&str>) {
         tracing::debug!(
             "User {username} leaving room {self} with {count} users",
             count = self.users.len()
         );
         self.users.remove(username);
-        self.send_event(username, RoomEvent::left(&self.name));
+        self.moderators.remove(username);
+        let mut owner = self.owner.lock().unwrap();
+        if owner.as_ref() == Some(username) {
+            *owner = self.users.iter().sorted().next();
+        }
+        drop(owner);
+        if self.users.is_empty() {
+            *self.empty_since.lock().unwrap() = Some(Instant::now());
+        }
+        self.record_movement(username, false);
+        self.send_event(username, RoomEvent::left(&self.name(), farewell));
+    }
+
+    fn record_movement(&self, username: &Username, joined: bool) {
+        self.history.push_movement(Movement {
+            position: self.next_message_id.load(Ordering::Relaxed),
+            username: username.clone(),
+            joined,
+        });
     }
 
     pub fn list_users(&self) -> Vec<Username> {
         self.users.iter().sorted().collect()
     }
 
+    pub fn contains(&self, username: &Username) -> bool {
+        self.users.contains(username)
+    }
+
     pub fn user_count(&self) -> usize {
         self.users.len()
     }
@@ -71,23 +192,398 @@ impl Room {
         self.users.is_empty()
     }
 
+    /// Returns how long the room has been empty for
+    ///
+    /// Restored rooms that nobody joined since are not considered idle.
+    pub fn idle_for(&self) -> Option<Duration> {
+        self.empty_since
+            .lock()
+            .unwrap()
+            .map(|since| since.elapsed())
+    }
+
     pub fn is_lobby(&self) -> bool {
-        self.name.as_str() == "lobby"
+        self.name() == RoomName::lobby()
     }
 
     pub fn change_user_name(&self, old_name: &Username, new_name: &Username) {
         tracing::debug!("User {old_name} changing name to {new_name} in room {self}");
         self.users.remove(old_name);
         self.users.insert(new_name);
+        for users in [&self.moderators, &self.muted] {
+            if users.remove(old_name) {
+                users.insert(new_name);
+            }
+        }
+        let mut owner = self.owner.lock().unwrap();
+        if owner.as_ref() == Some(old_name) {
+            *owner = Some(new_name.clone());
+        }
+        drop(owner);
         self.send_event(old_name, RoomEvent::name_change(new_name));
     }
 
-    pub fn send_message(&self, username: &Username, message: &str) {
-        self.send_event(username, RoomEvent::message(message));
+    /// Checks whether the given user can post to the room, i.e. isn't muted
+    ///
+    /// Everything that users post (messages, files, code, reactions and nudges) is checked here.
+    pub fn check_can_post(&self, username: &Username) -> Result<(), &'static str> {
+        if self.muted.contains(username) {
+            return Err("You are muted in this room");
+        }
+        Ok(())
+    }
+
+    /// Sends an event that the given user posted to the room unless they are muted
+    pub fn post(&self, username: &Username, event: RoomEvent) -> Result<(), &'static str> {
+        self.check_can_post(username)?;
+        self.send_event(username, event);
+        Ok(())
+    }
+
+    /// Sends a message to the room unless the user is muted
+    pub fn send_message(&self, username: &Username, message: &str) -> Result<(), &'static str> {
+        self.check_can_post(username)?;
+        let id = MessageId::new(self.next_message_id.fetch_add(1, Ordering::Relaxed));
+        let timestamp = Utc::now();
+        let mentions = self.mentions(message);
+        self.history.push(HistoryEntry {
+            id,
+            username: username.clone(),
+            text: message.to_string(),
+            mentions: mentions.clone(),
+            timestamp,
+        });
+        let event = RoomEvent::message(id, message, mentions);
+        self.broadcast(ServerEvent::room_event_at(
+            &self.name(),
+            username,
+            event,
+            timestamp,
+        ));
+        Ok(())
+    }
+
+    /// Returns the members of the room that are mentioned with `@username` in the given text
+    ///
+    /// Punctuation right after a name (e.g. `@name,`) is ignored unless it is part of the name.
+    fn mentions(&self, text: &str) -> Vec<Username> {
+        text.split_whitespace()
+            .filter_map(|word| word.strip_prefix('@'))
+            .filter_map(|name| {
+                [
+                    name,
+                    name.trim_end_matches(|c: char| c.is_ascii_punctuation()),
+                ]
+                .into_iter()
+                .map(Username::from)
+                .find(|username| self.users.contains(username))
+            })
+            .unique()
+            .collect()
+    }
+
+    /// Returns the given number of most recent messages as they were sent
+    ///
+    /// Edited messages are returned with their current text.
+    pub fn history(&self, count: usize) -> ServerEvent {
+        let room_name = self.name();
+        let events = Self::message_events(&room_name, self.history.recent(count));
+        ServerEvent::history(&room_name, events)
+    }
+
+    /// Searches the messages of the room that are still in the history buffer
+    pub fn search(&self, query: &str) -> ServerEvent {
+        let room_name = self.name();
+        let entries = self.history.search(query, Self::SEARCH_RESULTS_LIMIT);
+        let results = Self::message_events(&room_name, entries);
+        ServerEvent::search_results(&room_name, query, results)
+    }
+
+    fn message_events(room_name: &RoomName, entries: Vec<HistoryEntry>) -> Vec<ServerEvent> {
+        entries
+            .into_iter()
+            .map(|entry| {
+                let event = RoomEvent::message(entry.id, &entry.text, entry.mentions);
+                ServerEvent::room_event_at(room_name, &entry.username, event, entry.timestamp)
+            })
+            .collect()
+    }
+
+    /// Deletes the most recent message of the given user
+    ///
+    /// Returns the id of the deleted message, if any
+    pub fn undo(&self, username: &Username) -> Option<MessageId> {
+        let entry = self.history.remove_last_by(username)?;
+        tracing::debug!(
+            "User {username} deleted message {}: {:?}",
+            entry.id,
+            entry.text
+        );
+        self.send_event(username, RoomEvent::deleted(entry.id));
+        Some(entry.id)
+    }
+
+    /// Edits a message of the given user if it was sent within the edit window
+    pub fn edit(
+        &self,
+        username: &Username,
+        id: MessageId,
+        text: &str,
+        window: Duration,
+    ) -> Result<(), &'static str> {
+        self.history.edit(id, username, text, window)?;
+        self.send_event(username, RoomEvent::edited(id, text));
+        Ok(())
+    }
+
+    /// Makes the given user a moderator of the room
+    pub fn op(&self, username: &Username, target: &Username) -> Result<(), &'static str> {
+        if !self.users.contains(target) {
+            return Err("User not found in this room");
+        }
+        if self.is_moderator(target) {
+            return Err("User is already a moderator");
+        }
+        self.moderators.insert(target);
+        self.send_event(username, RoomEvent::opped(target));
+        Ok(())
+    }
+
+    /// Tells the given user to leave the room
+    ///
+    /// The user is removed from the room by their connection upon receiving the event.
+    pub fn kick(&self, username: &Username, target: &Username) -> Result<(), &'static str> {
+        if !self.users.contains(target) {
+            return Err("User not found in this room");
+        }
+        self.send_event(username, RoomEvent::kicked(target));
+        Ok(())
+    }
+
+    /// Bans the given user from joining the room, kicking them if they are in it
+    pub fn ban(&self, username: &Username, target: &Username) -> Result<(), &'static str> {
+        if !self.banned.insert(target) {
+            return Err("User is already banned");
+        }
+        self.send_event(username, RoomEvent::banned(target));
+        Ok(())
+    }
+
+    pub fn unban(&self, username: &Username, target: &Username) -> Result<(), &'static str> {
+        if !self.banned.remove(target) {
+            return Err("User is not banned");
+        }
+        self.send_event(username, RoomEvent::unbanned(target));
+        Ok(())
+    }
+
+    /// Prevents the given user from sending messages to the room, even after rejoining it
+    pub fn mute(&self, username: &Username, target: &Username) -> Result<(), &'static str> {
+        if !self.muted.insert(target) {
+            return Err("User is already muted");
+        }
+        self.send_event(username, RoomEvent::muted(target));
+        Ok(())
+    }
+
+    pub fn unmute(&self, username: &Username, target: &Username) -> Result<(), &'static str> {
+        if !self.muted.remove(target) {
+            return Err("User is not muted");
+        }
+        self.send_event(username, RoomEvent::unmuted(target));
+        Ok(())
+    }
+
+    /// Reacts to a message of the room with the given emoji
+    pub fn react(
+        &self,
+        username: &Username,
+        id: MessageId,
+        emoji: &str,
+    ) -> Result<(), &'static str> {
+        if !self.history.contains(id) {
+            return Err("Message not found");
+        }
+        self.post(username, RoomEvent::reaction(id, emoji))
+    }
+
+    /// Removes all messages from the history of the room
+    pub fn clear_history(&self, username: &Username) {
+        tracing::debug!("User {username} cleared the history of room {self}");
+        self.history.clear();
+        self.send_event(username, RoomEvent::HistoryCleared);
+    }
+
+    /// Returns the current state of the room
+    pub fn snapshot(&self) -> RoomSnapshot {
+        RoomSnapshot {
+            name: self.name(),
+            owner: self.owner.lock().unwrap().clone(),
+            users: self.list_users(),
+            history: self.history.entries(),
+            password: self.password.as_deref().map(String::from),
+            moderators: self.moderators.iter().sorted().collect(),
+            banned: self.banned.iter().sorted().collect(),
+            muted: self.muted.iter().sorted().collect(),
+        }
+    }
+
+    /// Restores the history, the moderation state (and the owner, if there is none) from the given
+    /// state
+    ///
+    /// The password can't be changed, so the room has to be created with the one in the state.
+    pub(crate) fn restore(&self, snapshot: RoomSnapshot) {
+        if let Some(owner) = snapshot.owner {
+            self.owner.lock().unwrap().get_or_insert(owner);
+        }
+        for (users, restored) in [
+            (&self.moderators, snapshot.moderators),
+            (&self.banned, snapshot.banned),
+            (&self.muted, snapshot.muted),
+        ] {
+            for username in &restored {
+                users.insert(username);
+            }
+        }
+        let next_id = snapshot
+            .history
+            .iter()
+            .map(|entry| entry.id.as_u64() + 1)
+            .max();
+        self.next_message_id
+            .fetch_max(next_id.unwrap_or_default(), Ordering::Relaxed);
+        self.history.replace(snapshot.history);
+        // kept until someone joins and leaves it again, rather than swept as idle
+        if self.is_empty() {
+            *self.empty_since.lock().unwrap() = None;
+        }
+    }
+
+    /// Summarizes the activity since the given user's last message
+    ///
+    /// Only the activity that is still in the history buffer is taken into account.
+    pub fn summary(&self, username: &Username) -> ServerEvent {
+        let last_id = self.history.last_id_by(username);
+        let entries = self.history.entries_after(last_id);
+        let mentions = entries
+            .iter()
+            .filter(|entry| entry.mentions.contains(username))
+            .map(|entry| entry.id)
+            .collect();
+        let (joined, left): (Vec<_>, Vec<_>) = self
+            .history
+            .movements_after(last_id)
+            .into_iter()
+            .filter(|movement| &movement.username != username)
+            .partition(|movement| movement.joined);
+        let usernames = |movements: Vec<Movement>| {
+            movements
+                .into_iter()
+                .map(|movement| movement.username)
+                .unique()
+                .collect()
+        };
+        ServerEvent::summary(
+            &self.name(),
+            entries.len(),
+            usernames(joined),
+            usernames(left),
+            mentions,
+        )
+    }
+
+    pub fn send_system_message(&self, username: &Username, message: &str) {
+        self.send_event(username, RoomEvent::system(message));
+    }
+
+    /// Applies the given change to the initiative tracker and broadcasts the result
+    pub fn update_initiative(&self, username: &Username, update: impl FnOnce(&mut Initiative)) {
+        let event = {
+            let mut initiative = self.initiative.lock().unwrap();
+            update(&mut initiative);
+            initiative.to_event()
+        };
+        self.send_event(username, event);
     }
 
     pub fn send_event(&self, username: &Username, event: RoomEvent) {
-        let event = ServerEvent::room_event(&self.name, username, event);
-        let _ = self.events.send(event);
+        self.broadcast(ServerEvent::room_event(&self.name(), username, event));
+    }
+
+    fn broadcast(&self, event: ServerEvent) {
+        // sending only fails when nobody is in the room (e.g. after the last user left)
+        if let Err(err) = self.events.send(event) {
+            tracing::trace!("No users in room {self} to receive {} event", err.0.kind());
+        }
+        self.changes.notify_one();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn room() -> Room {
+        Room::new(
+            RoomName::from("den"),
+            Arc::default(),
+            Arc::default(),
+            10,
+            None,
+        )
+    }
+
+    #[test]
+    fn sending_without_subscribers() {
+        let room = room();
+        let alice = Username::from("alice");
+        room.send_event(&alice, RoomEvent::Back);
+        room.send_message(&alice, "anyone here?").unwrap();
+        assert_eq!(room.snapshot().history.len(), 1);
+    }
+
+    #[test]
+    fn undo_deletes_the_latest_own_message() {
+        let room = room();
+        let [alice, bob] = ["alice", "bob"].map(Username::from);
+        room.send_message(&alice, "first").unwrap();
+        room.send_message(&alice, "second").unwrap();
+        room.send_message(&bob, "third").unwrap();
+        assert_eq!(room.undo(&alice), Some(MessageId::new(1)));
+        let texts: Vec<_> = room
+            .snapshot()
+            .history
+            .into_iter()
+            .map(|entry| entry.text)
+            .collect();
+        assert_eq!(texts, ["first", "third"]);
+        assert_eq!(room.undo(&alice), Some(MessageId::new(0)));
+        assert_eq!(room.undo(&alice), None);
+        assert_eq!(room.snapshot().history.len(), 1);
+    }
+
+    #[test]
+    fn summary_counts_exact_mentions() {
+        let room = room();
+        let [alice, bob, bobby] = ["alice", "bob", "bobby"].map(Username::from);
+        for username in [&alice, &bob, &bobby] {
+            room.join(username);
+        }
+        room.send_message(&bob, "hi").unwrap();
+        room.send_message(&alice, "@bobby welcome").unwrap();
+        room.send_message(&alice, "@bob, hello").unwrap();
+        room.leave(&bobby, None);
+        let ServerEvent::Summary {
+            messages,
+            left,
+            mentions,
+            ..
+        } = room.summary(&bob)
+        else {
+            unreachable!()
+        };
+        assert_eq!(messages, 2);
+        assert_eq!(left, [bobby]);
+        assert_eq!(mentions, [MessageId::new(2)]);
     }
 }
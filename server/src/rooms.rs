@@ -1,43 +1,99 @@
-use std::{cmp::Ordering, sync::Arc};
+use std::{
+    cmp::Ordering,
+    sync::{
+        atomic::{self, AtomicU64},
+        Arc,
+    },
+    time::Duration,
+};
 
-use common::{RoomName, ServerEvent, Username};
-use dashmap::DashMap;
-use tokio::sync::broadcast::{Receiver, Sender};
+use common::{MessageId, RoomEvent, RoomName, ServerEvent, Username};
+use dashmap::{mapref::entry::Entry, DashMap};
+use tokio::sync::{
+    broadcast::{Receiver, Sender},
+    Notify,
+};
 
-use crate::room::Room;
+use crate::{
+    room::Room,
+    snapshot::{RoomSnapshot, StateSnapshot},
+};
 
 #[derive(Clone, Debug)]
 pub struct Rooms {
     rooms: Arc<DashMap<RoomName, Room>>,
     events: Sender<ServerEvent>,
+    /// The id of the next message, shared by all rooms so that ids are never reused
+    next_message_id: Arc<AtomicU64>,
+    /// Notified whenever the state of the rooms might have changed, see [`Rooms::changed`]
+    changes: Arc<Notify>,
+    /// The number of recent messages that each room keeps
+    history_capacity: usize,
 }
 
 impl Rooms {
-    pub fn new(events: Sender<ServerEvent>) -> Self {
+    pub fn new(events: Sender<ServerEvent>, history_capacity: usize) -> Self {
         let rooms = Arc::new(DashMap::new());
-        let lobby = Room::new(RoomName::lobby());
-        rooms.insert(lobby.name().clone(), lobby);
-        Self { rooms, events }
+        let next_message_id = Arc::new(AtomicU64::new(0));
+        let changes = Arc::new(Notify::new());
+        let lobby = Room::new(
+            RoomName::lobby(),
+            next_message_id.clone(),
+            changes.clone(),
+            history_capacity,
+            None,
+        );
+        rooms.insert(lobby.name(), lobby);
+        Self {
+            rooms,
+            events,
+            next_message_id,
+            changes,
+            history_capacity,
+        }
     }
 
-    pub fn join(&self, username: &Username, room_name: &RoomName) -> (Room, Receiver<ServerEvent>) {
+    /// Adds the given user to the given room, creating the room if it doesn't exist
+    ///
+    /// A room that is created with a password can only be joined with the same password, and users
+    /// that are banned from a room can't join it at all.
+    pub fn join(
+        &self,
+        username: &Username,
+        room_name: &RoomName,
+        password: Option<&str>,
+    ) -> Result<(Room, Receiver<ServerEvent>), &'static str> {
         let room = self
             .rooms
             .entry(room_name.clone())
-            .or_insert_with(|| self.create_room(room_name));
+            .or_insert_with(|| self.create_room(room_name, password));
+        if room.is_banned(username) {
+            return Err("You are banned from this room");
+        }
+        room.check_password(password)?;
         let events = room.join(username);
-        (room.clone(), events)
+        Ok((room.clone(), events))
+    }
+
+    pub fn get(&self, room_name: &RoomName) -> Option<Room> {
+        self.rooms.get(room_name).map(|room| room.clone())
     }
 
-    fn create_room(&self, room_name: &RoomName) -> Room {
+    fn create_room(&self, room_name: &RoomName, password: Option<&str>) -> Room {
         tracing::debug!("Creating room {room_name}");
-        let room = Room::new(room_name.clone());
+        let room = Room::new(
+            room_name.clone(),
+            self.next_message_id.clone(),
+            self.changes.clone(),
+            self.history_capacity,
+            password,
+        );
         self.send_server_event(ServerEvent::room_created(room_name));
         room
     }
 
-    pub fn leave(&self, username: &Username, room: &Room) {
-        room.leave(username);
+    pub fn leave(&self, username: &Username, room: &Room, farewell: Option<&str>) {
+        room.leave(username, farewell);
         if room.is_empty() {
             self.delete_room(room);
         }
@@ -48,23 +104,118 @@ impl Rooms {
             tracing::debug!("no users in the lobby, not deleting");
             return;
         }
-        tracing::debug!("Deleting room {room}");
-        self.rooms.remove(room.name());
-        self.send_server_event(ServerEvent::room_deleted(room.name()));
+        // someone might have joined in the meantime
+        let room_name = room.name();
+        if self
+            .rooms
+            .remove_if(&room_name, |_, room| room.is_empty())
+            .is_some()
+        {
+            tracing::debug!("Deleting room {room_name}");
+            self.send_server_event(ServerEvent::room_deleted(&room_name));
+        }
     }
 
-    pub fn change(
+    /// Renames the given room on behalf of the given user
+    pub fn rename(
         &self,
         username: &Username,
-        previous: &Room,
-        next: &RoomName,
-    ) -> (Room, Receiver<ServerEvent>) {
-        if next == previous.name() {
-            let event = ServerEvent::error("You are already in that room");
-            self.send_server_event(event);
+        room: &Room,
+        new_name: &RoomName,
+    ) -> Result<(), &'static str> {
+        if room.is_lobby() || new_name == &RoomName::lobby() {
+            return Err("The lobby cannot be renamed");
+        }
+        let old_name = room.name();
+        if !self.rooms.contains_key(&old_name) {
+            return Err("Room does not exist");
+        }
+        // the room is added under the new name before removing the old one, so that it can always
+        // be found (e.g. joining it by the old name in the meantime doesn't create a new room)
+        match self.rooms.entry(new_name.clone()) {
+            Entry::Occupied(_) => return Err("A room with that name already exists"),
+            Entry::Vacant(entry) => {
+                room.set_name(new_name.clone());
+                entry.insert(room.clone());
+            }
+        }
+        self.rooms
+            .remove_if(&old_name, |_, old_room| old_room.is_same(room));
+        tracing::debug!("Renamed room {old_name} to {new_name}");
+        room.send_event(username, RoomEvent::renamed(&old_name, new_name));
+        self.send_server_event(ServerEvent::room_deleted(&old_name));
+        self.send_server_event(ServerEvent::room_created(new_name));
+        Ok(())
+    }
+
+    /// Closes the given room, making its users leave it
+    ///
+    /// The room is removed right away, so joining it again creates a new room.
+    pub fn close(&self, username: &Username, room_name: &RoomName) -> Result<(), &'static str> {
+        if room_name == &RoomName::lobby() {
+            return Err("The lobby cannot be closed");
+        }
+        let Some((_, room)) = self.rooms.remove(room_name) else {
+            return Err("Room does not exist");
+        };
+        tracing::debug!("Closing room {room_name}");
+        room.send_event(username, RoomEvent::Closed);
+        self.send_server_event(ServerEvent::room_deleted(room_name));
+        Ok(())
+    }
+
+    /// Deletes the rooms (except the lobby) that have been empty for at least the given duration
+    pub fn remove_idle(&self, ttl: Duration) {
+        let idle_rooms: Vec<RoomName> = self
+            .rooms
+            .iter()
+            .filter(|entry| !entry.is_lobby() && entry.idle_for().is_some_and(|idle| idle >= ttl))
+            .map(|entry| entry.key().clone())
+            .collect();
+        for room_name in idle_rooms {
+            if self
+                .rooms
+                .remove_if(&room_name, |_, room| room.is_empty())
+                .is_some()
+            {
+                tracing::debug!("Deleting idle room {room_name}");
+                self.send_server_event(ServerEvent::room_deleted(&room_name));
+            }
         }
-        self.leave(username, previous);
-        self.join(username, next)
+    }
+
+    /// Returns the current state of all rooms
+    pub fn snapshot(&self) -> StateSnapshot {
+        let mut rooms: Vec<RoomSnapshot> =
+            self.rooms.iter().map(|entry| entry.snapshot()).collect();
+        rooms.sort_by(|a, b| a.name.cmp(&b.name));
+        StateSnapshot { rooms }
+    }
+
+    /// Restores the rooms from the given state
+    ///
+    /// Missing rooms are created empty (with fresh channels and their password) and existing rooms
+    /// get their history replaced. Users can't be restored, so the rooms are empty, but they are
+    /// only deleted once the last user that joins them leaves.
+    pub fn restore(&self, snapshot: StateSnapshot) {
+        for room_snapshot in snapshot.rooms {
+            let password = room_snapshot.password.as_deref();
+            let room = self
+                .rooms
+                .entry(room_snapshot.name.clone())
+                .or_insert_with(|| self.create_room(&room_snapshot.name, password))
+                .clone();
+            room.restore(room_snapshot);
+        }
+        self.changes.notify_one();
+    }
+
+    /// Waits until the state of the rooms might have changed since the last call
+    ///
+    /// Every event that is sent to a room or to all users counts as a change, so this errs on the
+    /// side of waking up too often.
+    pub async fn changed(&self) {
+        self.changes.notified().await;
     }
 
     pub fn list(&self) -> Vec<(RoomName, usize)> {
@@ -80,7 +231,273 @@ impl Rooms {
         list
     }
 
+    /// Returns the names of the rooms that the given user is in
+    pub fn memberships(&self, username: &Username) -> Vec<RoomName> {
+        let mut memberships: Vec<RoomName> = self
+            .rooms
+            .iter()
+            .filter(|entry| entry.contains(username))
+            .map(|entry| entry.key().clone())
+            .collect();
+        memberships.sort();
+        memberships
+    }
+
+    /// Returns a new message id for a message outside of the rooms (e.g. a direct message)
+    pub fn next_message_id(&self) -> MessageId {
+        MessageId::new(self.next_message_id.fetch_add(1, atomic::Ordering::Relaxed))
+    }
+
+    /// Broadcasts the given event to all connected users
+    ///
+    /// Sending only fails when nobody is connected, in which case the event is dropped since new
+    /// connections receive the current room list anyway.
     pub fn send_server_event(&self, event: ServerEvent) {
-        let _ = self.events.send(event);
+        if let Err(err) = self.events.send(event) {
+            tracing::trace!("No users to receive {} server event", err.0.kind());
+        }
+        self.changes.notify_one();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use tokio::{sync::broadcast, time};
+
+    use super::*;
+
+    fn rooms() -> Rooms {
+        let (events, _) = broadcast::channel(16);
+        Rooms::new(events, 10)
+    }
+
+    fn has_lobby(rooms: &Rooms) -> bool {
+        rooms.get(&RoomName::lobby()).is_some()
+    }
+
+    #[test]
+    fn sending_without_subscribers() {
+        // the receiver is dropped right away
+        let rooms = rooms();
+        rooms.send_server_event(ServerEvent::Draining);
+        let (room, events) = rooms
+            .join(&Username::from("alice"), &RoomName::from("den"), None)
+            .unwrap();
+        drop(events);
+        room.send_event(&Username::from("alice"), RoomEvent::Back);
+    }
+
+    #[tokio::test]
+    async fn changes_are_signalled() {
+        let rooms = rooms();
+        let alice = Username::from("alice");
+        let (room, _events) = rooms.join(&alice, &RoomName::from("den"), None).unwrap();
+        time::timeout(Duration::from_secs(1), rooms.changed())
+            .await
+            .expect("joining a room is a change");
+        // nothing changed in the meantime
+        assert!(time::timeout(Duration::from_millis(10), rooms.changed())
+            .await
+            .is_err());
+        room.send_message(&alice, "hello").unwrap();
+        time::timeout(Duration::from_secs(1), rooms.changed())
+            .await
+            .expect("sending a message is a change");
+    }
+
+    #[test]
+    fn lobby_is_never_deleted() {
+        let rooms = rooms();
+        let lobby = RoomName::lobby();
+        let [alice, bob] = ["alice", "bob"].map(Username::from);
+        let (room, _events) = rooms.join(&alice, &lobby, None).unwrap();
+        rooms.join(&bob, &lobby, None).unwrap();
+        rooms.leave(&alice, &room, None);
+        rooms.leave(&bob, &room, None);
+        assert!(has_lobby(&rooms));
+
+        rooms.remove_idle(Duration::ZERO);
+        assert!(has_lobby(&rooms));
+
+        assert!(rooms
+            .rename(&alice, &room, &RoomName::from("hall"))
+            .is_err());
+        let (den, _events) = rooms.join(&alice, &RoomName::from("den"), None).unwrap();
+        assert!(rooms.rename(&alice, &den, &lobby).is_err());
+        assert!(rooms.close(&alice, &lobby).is_err());
+        assert!(has_lobby(&rooms));
+        assert_eq!(rooms.get(&lobby).unwrap().name(), lobby);
+    }
+
+    #[test]
+    fn rooms_are_deleted_once_empty() {
+        let rooms = rooms();
+        let room_name = RoomName::from("den");
+        let [alice, bob] = ["alice", "bob"].map(Username::from);
+        let (room, _events) = rooms.join(&alice, &room_name, None).unwrap();
+        rooms.join(&bob, &room_name, None).unwrap();
+        rooms.leave(&alice, &room, None);
+        assert!(rooms.get(&room_name).is_some());
+        rooms.leave(&bob, &room, None);
+        assert!(rooms.get(&room_name).is_none());
+        assert!(has_lobby(&rooms));
+    }
+
+    #[test]
+    fn renamed_rooms_keep_their_users() {
+        let rooms = rooms();
+        let [den, hall] = ["den", "hall"].map(RoomName::from);
+        let alice = Username::from("alice");
+        let (room, _events) = rooms.join(&alice, &den, None).unwrap();
+        rooms.rename(&alice, &room, &hall).unwrap();
+        assert!(rooms.get(&den).is_none());
+        assert_eq!(
+            rooms.get(&hall).unwrap().list_users(),
+            [alice.clone()].as_slice()
+        );
+        rooms.leave(&alice, &room, None);
+        assert!(rooms.get(&hall).is_none());
+        assert!(has_lobby(&rooms));
+    }
+
+    #[test]
+    fn renaming_to_a_taken_name_keeps_the_room() {
+        let rooms = rooms();
+        let [den, hall] = ["den", "hall"].map(RoomName::from);
+        let [alice, bob] = ["alice", "bob"].map(Username::from);
+        let (room, _events) = rooms.join(&alice, &den, None).unwrap();
+        rooms.join(&bob, &hall, None).unwrap();
+        assert_eq!(
+            rooms.rename(&alice, &room, &hall),
+            Err("A room with that name already exists")
+        );
+        assert!(rooms.get(&den).unwrap().is_same(&room));
+        assert_eq!(room.name(), den);
+        assert_eq!(rooms.get(&hall).unwrap().list_users(), [bob].as_slice());
+    }
+
+    #[test]
+    fn locked_room_needs_the_right_password() {
+        let rooms = rooms();
+        let room_name = RoomName::from("secret");
+        let [alice, bob] = ["alice", "bob"].map(Username::from);
+        rooms.join(&alice, &room_name, Some("hunter2")).unwrap();
+        assert_eq!(
+            rooms.join(&bob, &room_name, None).unwrap_err(),
+            "The room is locked, a password is required"
+        );
+        assert_eq!(
+            rooms.join(&bob, &room_name, Some("hunter3")).unwrap_err(),
+            "Wrong password"
+        );
+        let (room, _events) = rooms.join(&bob, &room_name, Some("hunter2")).unwrap();
+        assert_eq!(room.list_users(), [alice, bob]);
+    }
+
+    #[test]
+    fn message_ids_are_unique_across_rooms() {
+        let rooms = rooms();
+        let alice = Username::from("alice");
+        let (den, _den_events) = rooms.join(&alice, &RoomName::from("den"), None).unwrap();
+        let (hall, _hall_events) = rooms.join(&alice, &RoomName::from("hall"), None).unwrap();
+        for room in [&den, &hall, &den, &hall] {
+            room.send_message(&alice, "hello").unwrap();
+        }
+        let ids = |room: &Room| -> Vec<u64> {
+            room.snapshot()
+                .history
+                .iter()
+                .map(|entry| entry.id.as_u64())
+                .collect()
+        };
+        assert_eq!(ids(&den), [0, 2]);
+        assert_eq!(ids(&hall), [1, 3]);
+
+        // the restored ids are not handed out again
+        let restored = self::rooms();
+        restored.restore(rooms.snapshot());
+        let (lobby, _events) = restored.join(&alice, &RoomName::lobby(), None).unwrap();
+        lobby.send_message(&alice, "hello again").unwrap();
+        assert_eq!(ids(&lobby), [4]);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn empty_rooms_are_swept_after_the_ttl() {
+        let (events, mut server_events) = broadcast::channel(16);
+        let rooms = Rooms::new(events, 10);
+        let room_name = RoomName::from("den");
+        let ttl = Duration::from_secs(300);
+        // a room that was left behind without ever being joined
+        let room = rooms.create_room(&room_name, None);
+        rooms.rooms.insert(room_name.clone(), room);
+        assert!(matches!(
+            server_events.try_recv(),
+            Ok(ServerEvent::RoomCreated(_))
+        ));
+
+        time::advance(ttl - Duration::from_secs(1)).await;
+        rooms.remove_idle(ttl);
+        assert!(rooms.get(&room_name).is_some());
+
+        time::advance(Duration::from_secs(1)).await;
+        rooms.remove_idle(ttl);
+        assert!(rooms.get(&room_name).is_none());
+        assert!(matches!(
+            server_events.try_recv(),
+            Ok(ServerEvent::RoomDeleted(name)) if name == room_name
+        ));
+    }
+
+    #[test]
+    fn restored_rooms_are_not_swept() {
+        let rooms = rooms();
+        let room_name = RoomName::from("den");
+        let alice = Username::from("alice");
+        let (room, _events) = rooms.join(&alice, &room_name, None).unwrap();
+        room.send_message(&alice, "hello").unwrap();
+
+        let restored = self::rooms();
+        restored.restore(rooms.snapshot());
+        restored.remove_idle(Duration::ZERO);
+        let room = restored
+            .get(&room_name)
+            .expect("the restored room was swept");
+        restored.join(&alice, &room_name, None).unwrap();
+        restored.leave(&alice, &room, None);
+        assert!(restored.get(&room_name).is_none());
+    }
+
+    #[test]
+    fn restart_keeps_the_access_rules() {
+        let rooms = rooms();
+        let room_name = RoomName::from("secret");
+        let [alice, bob, carol, mallory] = ["alice", "bob", "carol", "mallory"].map(Username::from);
+        let (room, _events) = rooms.join(&alice, &room_name, Some("hunter2")).unwrap();
+        for username in [&bob, &carol] {
+            rooms.join(username, &room_name, Some("hunter2")).unwrap();
+        }
+        room.send_message(&alice, "hello").unwrap();
+        room.op(&alice, &carol).unwrap();
+        room.ban(&alice, &mallory).unwrap();
+        room.mute(&alice, &bob).unwrap();
+
+        let saved = serde_json::to_string(&rooms.snapshot()).unwrap();
+        let restored = self::rooms();
+        restored.restore(serde_json::from_str(&saved).unwrap());
+
+        assert_eq!(
+            restored.join(&mallory, &room_name, None).unwrap_err(),
+            "You are banned from this room"
+        );
+        let dave = Username::from("dave");
+        assert!(restored.join(&dave, &room_name, None).is_err());
+        assert!(restored.join(&dave, &room_name, Some("wrong")).is_err());
+        let (room, _events) = restored.join(&bob, &room_name, Some("hunter2")).unwrap();
+        assert!(room.send_message(&bob, "hi").is_err());
+        assert!(room.is_owner(&alice));
+        assert!(room.is_moderator(&carol));
+        let history = room.snapshot().history;
+        assert_eq!(history.len(), 1);
+        assert_eq!(history[0].text, "hello");
     }
 }
@@ -1,54 +1,561 @@
-use std::net::SocketAddr;
+use std::{
+    future::Future,
+    net::{Ipv4Addr, SocketAddr},
+    sync::Mutex,
+};
 
-use common::ServerEvent;
+use common::{Capabilities, Command, ServerEvent, Username};
+use futures::{future, SinkExt};
+use itertools::Itertools;
+use rand::{rngs::StdRng, RngCore, SeedableRng};
+use socket2::{SockRef, TcpKeepalive};
 use tokio::{
-    net::TcpListener,
+    net::{TcpListener, TcpStream},
+    signal,
     sync::broadcast::{self, Sender},
+    time::{self, Duration},
+};
+use tokio_rustls::TlsAcceptor;
+use tokio_util::task::TaskTracker;
+
+#[cfg(test)]
+use std::sync::Arc;
+
+#[cfg(unix)]
+use tokio::net::UnixListener;
+
+#[cfg(unix)]
+use crate::admin;
+#[cfg(test)]
+use crate::translator::Translator;
+use crate::{
+    config::Config,
+    connection::Connection,
+    control,
+    rooms::Rooms,
+    state::SharedState,
+    storage::Storage,
+    throttle::AcceptThrottle,
+    tls,
+    transport::{self, Frame, Protocol, Transport},
+    users::Users,
 };
 
-use crate::{connection::Connection, rooms::Rooms, users::Users};
+/// Returns the help text with only the commands allowed by the configuration
+pub fn commands_help(config: &Config) -> String {
+    Command::USAGE
+        .iter()
+        .filter(|(name, _)| config.is_command_allowed(name))
+        .map(|(name, args)| {
+            let prefix = &config.command_prefix;
+            if args.is_empty() {
+                format!("{prefix}{name}")
+            } else {
+                format!("{prefix}{name} {args}")
+            }
+        })
+        .join(" | ")
+}
 
-pub const COMMANDS: &str =
-    "/help | /name {name} | /rooms | /join {room} | /users | /nudge {name} | /quit";
+pub const CAPABILITIES: Capabilities = Capabilities {
+    binary_codec: true,
+    compression: false,
+    tls: false,
+    file_chunking: true,
+    reactions: true,
+};
 
 pub struct Server {
     listener: TcpListener,
-    users: Users,
-    rooms: Rooms,
+    control_listener: Option<TcpListener>,
+    #[cfg(unix)]
+    admin_listener: Option<UnixListener>,
+    websocket_listener: Option<TcpListener>,
+    tls: Option<TlsAcceptor>,
+    storage: Option<Storage>,
+    shared: SharedState,
     event_tx: Sender<ServerEvent>,
+    rng: Mutex<StdRng>,
 }
 
 impl Server {
-    pub async fn listen(addr: SocketAddr) -> anyhow::Result<Self> {
+    pub async fn listen(addr: SocketAddr, config: Config) -> anyhow::Result<Self> {
         let listener = TcpListener::bind(addr).await?;
         let local_addr = listener.local_addr()?;
         tracing::info!("Listening on {local_addr}");
+        let control_listener = match config.control_port {
+            Some(port) => {
+                let listener = TcpListener::bind((Ipv4Addr::LOCALHOST, port)).await?;
+                tracing::info!("Control socket listening on {}", listener.local_addr()?);
+                Some(listener)
+            }
+            None => None,
+        };
+        #[cfg(unix)]
+        let admin_listener = match &config.admin_socket {
+            Some(path) => {
+                let listener = admin::bind(path)?;
+                tracing::info!("Admin console listening on {}", path.display());
+                Some(listener)
+            }
+            None => None,
+        };
+        let websocket_listener = match config.websocket_port {
+            Some(port) => {
+                let listener = TcpListener::bind(SocketAddr::new(addr.ip(), port)).await?;
+                tracing::info!("WebSocket listening on {}", listener.local_addr()?);
+                Some(listener)
+            }
+            None => None,
+        };
+        let tls = match (&config.tls_cert, &config.tls_key) {
+            (Some(cert), Some(key)) => {
+                tracing::info!("TLS is enabled");
+                Some(tls::acceptor(cert, key)?)
+            }
+            _ => None,
+        };
         let (event_tx, _) = broadcast::channel(1024);
+        let rooms = Rooms::new(event_tx.clone(), config.history_size);
+        let storage = match &config.data_dir {
+            Some(data_dir) => {
+                let storage = Storage::open(data_dir)?;
+                if let Some(snapshot) = storage.load()? {
+                    tracing::info!("Restoring {} rooms", snapshot.rooms.len());
+                    rooms.restore(snapshot);
+                }
+                Some(storage)
+            }
+            None => None,
+        };
+        let rng = match config.seed {
+            Some(seed) => StdRng::seed_from_u64(seed),
+            None => StdRng::from_entropy(),
+        };
 
         Ok(Self {
             listener,
-            users: Users::default(),
-            rooms: Rooms::new(event_tx.clone()),
+            control_listener,
+            #[cfg(unix)]
+            admin_listener,
+            websocket_listener,
+            tls,
+            storage,
+            shared: SharedState::new(config, rooms),
             event_tx,
+            rng: Mutex::new(rng),
         })
     }
 
-    pub async fn run(&self) {
+    /// Replaces the service that translates text for `/translate`
+    #[cfg(test)]
+    pub fn with_translator(mut self, translator: Arc<dyn Translator>) -> Self {
+        self.shared.translator = translator;
+        self
+    }
+
+    /// Returns the address that the server is listening on
+    #[cfg(test)]
+    pub fn local_addr(&self) -> SocketAddr {
+        self.listener.local_addr().unwrap()
+    }
+
+    /// Runs the server until it receives a shutdown signal (SIGINT or SIGTERM)
+    pub async fn run(self) {
+        self.run_until(shutdown_signal()).await;
+    }
+
+    /// Runs the server until the given future completes with the reason of the shutdown
+    pub async fn run_until(mut self, shutdown: impl Future<Output = &'static str>) {
+        if let Some(listener) = self.control_listener.take() {
+            tokio::spawn(control::serve(listener, self.shared.clone()));
+        }
+        #[cfg(unix)]
+        if let Some(listener) = self.admin_listener.take() {
+            tokio::spawn(admin::serve(listener, self.shared.clone()));
+        }
+        if let Some(ttl) = self.shared.config.idle_room_ttl {
+            let rooms = self.shared.rooms.clone();
+            tokio::spawn(async move {
+                let mut interval = tokio::time::interval(ttl);
+                loop {
+                    interval.tick().await;
+                    rooms.remove_idle(ttl);
+                }
+            });
+        }
+        let saver = self.storage.clone().map(|storage| {
+            let rooms = self.shared.rooms.clone();
+            let save_interval = self.shared.config.save_interval;
+            tokio::spawn(async move {
+                loop {
+                    rooms.changed().await;
+                    if let Err(err) = storage.save(&rooms) {
+                        tracing::error!("Failed to save the rooms: {err:#}");
+                    }
+                    // the changes in the meantime are saved together afterwards
+                    time::sleep(save_interval).await;
+                }
+            })
+        });
+        let mut throttle = self
+            .shared
+            .config
+            .max_connections_per_minute
+            .map(|limit| AcceptThrottle::new(limit, Duration::from_secs(60)));
+        let connections = TaskTracker::new();
+        tokio::pin!(shutdown);
         loop {
-            let (stream, addr) = match self.listener.accept().await {
-                Ok(ok) => ok,
-                Err(err) => {
-                    tracing::error!("Failed to accept connection: {err}");
-                    continue;
+            let (stream, addr, protocol) = tokio::select! {
+                signal = &mut shutdown => {
+                    tracing::info!("Received {signal}, shutting down");
+                    break;
                 }
+                result = self.accept() => match result {
+                    Ok(ok) => ok,
+                    Err(err) => {
+                        tracing::error!("Failed to accept connection: {err}");
+                        continue;
+                    }
+                },
             };
-            let users = self.users.clone();
-            let rooms = self.rooms.clone();
+            if let Some(throttle) = &mut throttle {
+                if !throttle.allow(addr.ip()) {
+                    tracing::warn!("Refusing connection from {addr}, too many connections");
+                    continue;
+                }
+            }
+            if self.shared.is_banned(&addr.ip()) {
+                tracing::info!("Refusing connection from banned address {addr}");
+                let reason = ServerEvent::error("You are banned from this server");
+                tokio::spawn(self.refuse(stream, protocol, reason));
+                continue;
+            }
+            if self.shared.is_draining() {
+                tracing::info!("Refusing connection from {addr} while draining");
+                tokio::spawn(self.refuse(stream, protocol, ServerEvent::Draining));
+                continue;
+            }
+            if let Some(max_users) = self.shared.config.max_users {
+                if self.shared.users.len() >= max_users {
+                    tracing::info!("Refusing connection from {addr}, server is full");
+                    tokio::spawn(self.refuse(stream, protocol, ServerEvent::ServerFull));
+                    continue;
+                }
+            }
+            if let Err(err) = set_keepalive(&stream, &self.shared.config) {
+                tracing::warn!("Failed to set TCP keepalive for {addr}: {err}");
+            }
+            let shared = self.shared.clone();
             let events = self.event_tx.subscribe();
-            let mut connection = Connection::new(stream, events, users, rooms, addr);
-            tokio::spawn(async move {
-                connection.handle().await;
+            let username = claim_random_name(&self.shared.users, &mut *self.rng.lock().unwrap());
+            let tls = self.tls.clone();
+            connections.spawn(async move {
+                let max_line_length = shared.config.max_line_length;
+                match transport::open(stream, tls.as_ref(), protocol, max_line_length).await {
+                    Ok(transport) => {
+                        let connection = Connection::new(transport, events, shared, username, addr);
+                        connection.handle().await;
+                    }
+                    Err(err) => {
+                        tracing::warn!("Failed to accept connection from {addr}: {err}");
+                        shared.users.remove(&username);
+                    }
+                }
             });
         }
+        if let Some(saver) = saver {
+            saver.abort();
+        }
+        self.shutdown(connections).await;
+    }
+
+    /// Stops listening, saves the rooms, notifies the users and waits for their connections to
+    /// close
+    ///
+    /// The rooms are saved before the users leave them, since empty rooms are deleted. The
+    /// connections that are still open after the grace period are dropped.
+    async fn shutdown(self, connections: TaskTracker) {
+        drop(self.listener);
+        drop(self.websocket_listener);
+        #[cfg(unix)]
+        if let Some(path) = &self.shared.config.admin_socket {
+            if let Err(err) = std::fs::remove_file(path) {
+                tracing::warn!("Failed to remove the admin socket: {err}");
+            }
+        }
+        self.shared.start_draining();
+        if let Some(storage) = &self.storage {
+            if let Err(err) = storage.save(&self.shared.rooms) {
+                tracing::error!("Failed to save the rooms: {err:#}");
+            }
+        }
+        let event = ServerEvent::server_shutdown("The server is shutting down");
+        self.shared.rooms.send_server_event(event);
+        connections.close();
+        let grace_period = self.shared.config.shutdown_grace_period;
+        if time::timeout(grace_period, connections.wait())
+            .await
+            .is_err()
+        {
+            tracing::warn!(
+                "Dropping {} connections that did not close within {grace_period:?}",
+                connections.len()
+            );
+        }
+        tracing::info!("Shut down");
+    }
+
+    /// Accepts the next connection from any of the listeners
+    async fn accept(&self) -> std::io::Result<(TcpStream, SocketAddr, Protocol)> {
+        let with_protocol = |protocol| move |(stream, addr)| (stream, addr, protocol);
+        match &self.websocket_listener {
+            Some(websocket_listener) => tokio::select! {
+                result = self.listener.accept() => result.map(with_protocol(Protocol::Lines)),
+                result = websocket_listener.accept() => {
+                    result.map(with_protocol(Protocol::WebSocket))
+                }
+            },
+            None => self
+                .listener
+                .accept()
+                .await
+                .map(with_protocol(Protocol::Lines)),
+        }
+    }
+
+    /// Sends the reason of refusing a connection to the user and closes it
+    fn refuse(
+        &self,
+        stream: TcpStream,
+        protocol: Protocol,
+        reason: ServerEvent,
+    ) -> impl Future<Output = ()> {
+        let tls = self.tls.clone();
+        let max_line_length = self.shared.config.max_line_length;
+        async move {
+            match transport::open(stream, tls.as_ref(), protocol, max_line_length).await {
+                Ok(transport) => send_refusal(transport, reason).await,
+                Err(err) => tracing::debug!("Failed to send refusal: {err}"),
+            }
+        }
+    }
+}
+
+/// Draws random names until one that is not in use is found and registers it
+///
+/// The attempt number is appended to the names if the random ones keep colliding.
+fn claim_random_name(users: &Users, rng: &mut impl RngCore) -> Username {
+    const PLAIN_NAME_ATTEMPTS: u32 = 10;
+    for attempt in 0.. {
+        let username = Username::random_with(rng);
+        let username = if attempt < PLAIN_NAME_ATTEMPTS {
+            username
+        } else {
+            Username::new(format!("{username}{attempt}"))
+        };
+        if users.insert(&username) {
+            return username;
+        }
+    }
+    unreachable!("ran out of attempts to draw a random name")
+}
+
+/// Waits until the server is asked to shut down, returning the name of the signal
+async fn shutdown_signal() -> &'static str {
+    let interrupt = async {
+        if let Err(err) = signal::ctrl_c().await {
+            tracing::error!("Failed to listen for SIGINT: {err}");
+            future::pending::<()>().await;
+        }
+    };
+    #[cfg(unix)]
+    let terminate = async {
+        match signal::unix::signal(signal::unix::SignalKind::terminate()) {
+            Ok(mut terminate) => {
+                terminate.recv().await;
+            }
+            Err(err) => {
+                tracing::error!("Failed to listen for SIGTERM: {err}");
+                future::pending::<()>().await;
+            }
+        }
+    };
+    #[cfg(not(unix))]
+    let terminate = future::pending::<()>();
+    tokio::select! {
+        _ = interrupt => "SIGINT",
+        _ = terminate => "SIGTERM",
+    }
+}
+
+/// Enables TCP keepalive on the given stream if it is configured
+fn set_keepalive(stream: &TcpStream, config: &Config) -> std::io::Result<()> {
+    let Some(idle) = config.keepalive_idle else {
+        return Ok(());
+    };
+    let keepalive = TcpKeepalive::new().with_time(idle);
+    #[cfg(any(
+        target_os = "android",
+        target_os = "freebsd",
+        target_os = "ios",
+        target_os = "linux",
+        target_os = "macos",
+        target_os = "windows",
+    ))]
+    let keepalive = keepalive.with_interval(config.keepalive_interval);
+    SockRef::from(stream).set_tcp_keepalive(&keepalive)
+}
+
+async fn send_refusal(mut transport: impl Transport, reason: ServerEvent) {
+    if let Err(err) = transport.send(Frame::Text(reason.as_json_str())).await {
+        tracing::debug!("Failed to send refusal: {err}");
+    }
+    if let Err(err) = transport.close().await {
+        tracing::debug!("Failed to close the connection: {err}");
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::net::Ipv4Addr;
+
+    use common::RoomEvent;
+    use rand::rngs::mock::StepRng;
+    use tokio::time::Instant;
+
+    use super::*;
+    use crate::test_utils::{config, TestServer};
+
+    #[test]
+    fn random_names_are_redrawn_until_free() {
+        let users = Users::default();
+        let taken = Username::random_with(&mut StdRng::seed_from_u64(1));
+        users.insert(&taken);
+        let username = claim_random_name(&users, &mut StdRng::seed_from_u64(1));
+        assert_ne!(username, taken);
+        assert!(users.contains(&username));
+    }
+
+    #[test]
+    fn colliding_random_names_get_a_number() {
+        let users = Users::default();
+        // always draws the same name
+        let mut rng = StepRng::new(0, 0);
+        let first = claim_random_name(&users, &mut rng);
+        let second = claim_random_name(&users, &mut rng);
+        assert_eq!(second.as_str(), format!("{first}10"));
+        assert!(users.contains(&first) && users.contains(&second));
+    }
+
+    #[tokio::test]
+    async fn draining_refuses_new_connections_only() {
+        let server = TestServer::start(&["--oper-password", "hunter2"]).await;
+        let mut admin = server.connect_as("admin").await;
+        let mut bob = server.connect_as("bob").await;
+        admin.send("/oper hunter2").await;
+        admin.send("/drain").await;
+        bob.recv_until(|event| matches!(event, ServerEvent::Draining))
+            .await;
+
+        let mut refused = server.connect_raw().await;
+        let events = refused.recv_until_closed().await;
+        assert!(
+            matches!(events.as_slice(), [ServerEvent::Draining]),
+            "{events:?}"
+        );
+        // the existing sessions keep working
+        bob.send("still here").await;
+        admin
+            .recv_until(|event| {
+                let ServerEvent::RoomEvent { event, .. } = event else {
+                    return false;
+                };
+                matches!(event, RoomEvent::Message { text, .. } if text == "still here")
+            })
+            .await;
+        server.shutdown().await;
+    }
+
+    #[tokio::test]
+    async fn users_beyond_the_limit_are_refused() {
+        let server = TestServer::start(&["--max-users", "2"]).await;
+        let mut alice = server.connect_as("alice").await;
+        let mut bob = server.connect_as("bob").await;
+        let mut refused = server.connect_raw().await;
+        let events = refused.recv_until_closed().await;
+        assert!(
+            matches!(events.as_slice(), [ServerEvent::ServerFull]),
+            "{events:?}"
+        );
+        // the existing users are unaffected
+        bob.send("still here").await;
+        alice
+            .recv_until(|event| {
+                let ServerEvent::RoomEvent { event, .. } = event else {
+                    return false;
+                };
+                matches!(event, RoomEvent::Message { text, .. } if text == "still here")
+            })
+            .await;
+        server.shutdown().await;
+    }
+
+    #[tokio::test]
+    async fn keepalive_is_enabled_if_configured() {
+        let listener = TcpListener::bind((Ipv4Addr::LOCALHOST, 0)).await.unwrap();
+        let stream = TcpStream::connect(listener.local_addr().unwrap())
+            .await
+            .unwrap();
+        set_keepalive(&stream, &config(&[])).unwrap();
+        assert!(!SockRef::from(&stream).keepalive().unwrap());
+
+        let flags = ["--keepalive-idle", "30", "--keepalive-interval", "5"];
+        set_keepalive(&stream, &config(&flags)).unwrap();
+        let socket = SockRef::from(&stream);
+        assert!(socket.keepalive().unwrap());
+        #[cfg(target_os = "linux")]
+        {
+            assert_eq!(socket.keepalive_time().unwrap(), Duration::from_secs(30));
+            assert_eq!(socket.keepalive_interval().unwrap(), Duration::from_secs(5));
+        }
+    }
+
+    #[tokio::test]
+    async fn shutdown_notifies_the_users() {
+        let server = TestServer::start(&[]).await;
+        let mut client = server.connect().await;
+        let shutdown = tokio::spawn(server.shutdown());
+        let events = client.recv_until_closed().await;
+        assert!(
+            events
+                .iter()
+                .any(|event| matches!(event, ServerEvent::ServerShutdown { .. })),
+            "{events:?}"
+        );
+        shutdown.await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn shutdown_drops_connections_after_the_grace_period() {
+        let port = TcpListener::bind((Ipv4Addr::LOCALHOST, 0))
+            .await
+            .unwrap()
+            .local_addr()
+            .unwrap()
+            .port()
+            .to_string();
+        let flags = ["--shutdown-grace-period", "1", "--websocket-port", &port];
+        let server = TestServer::start(&flags).await;
+        // never completes the WebSocket handshake, so the connection never closes on its own
+        let _stalled = TcpStream::connect(format!("127.0.0.1:{port}"))
+            .await
+            .unwrap();
+        time::sleep(Duration::from_millis(100)).await;
+        let start = Instant::now();
+        time::timeout(Duration::from_secs(3), server.shutdown())
+            .await
+            .expect("the shutdown did not finish after the grace period");
+        assert!(start.elapsed() >= Duration::from_secs(1));
     }
 }
@@ -0,0 +1,147 @@
+use std::{net::SocketAddr, sync::Arc};
+
+use common::{MessageId, ServerEvent, Username};
+use dashmap::DashMap;
+use tokio::{
+    sync::mpsc,
+    time::{Duration, Instant},
+};
+use tokio_util::sync::CancellationToken;
+
+/// The outgoing queues of the connected users, for sending events to a single user
+#[derive(Clone, Debug, Default)]
+pub struct Sessions {
+    inner: Arc<DashMap<Username, Session>>,
+    /// The delivered direct messages that were not read yet, with their sender and recipient
+    unread: Arc<DashMap<MessageId, (Username, Username)>>,
+}
+
+#[derive(Clone, Debug)]
+struct Session {
+    outgoing: mpsc::Sender<ServerEvent>,
+    addr: SocketAddr,
+    connected_at: Instant,
+    signals: SessionSignals,
+}
+
+/// The signals between the connection of a user and the rest of the server
+#[derive(Clone, Debug, Default)]
+pub struct SessionSignals {
+    /// Cancelled to ask the connection to close
+    pub disconnect: CancellationToken,
+    /// Cancelled by the connection once the user is gone and their name is free again
+    pub released: CancellationToken,
+}
+
+impl Sessions {
+    /// Registers the connection of the given user, returning the signals that it has to handle
+    pub fn insert(
+        &self,
+        username: &Username,
+        addr: SocketAddr,
+        outgoing: mpsc::Sender<ServerEvent>,
+    ) -> SessionSignals {
+        let signals = SessionSignals::default();
+        let session = Session {
+            outgoing,
+            addr,
+            connected_at: Instant::now(),
+            signals: signals.clone(),
+        };
+        self.inner.insert(username.clone(), session);
+        signals
+    }
+
+    pub fn rename(&self, old_name: &Username, new_name: &Username) {
+        if let Some((_, session)) = self.inner.remove(old_name) {
+            self.inner.insert(new_name.clone(), session);
+        }
+        for mut entry in self.unread.iter_mut() {
+            let (from, to) = entry.value_mut();
+            for username in [from, to] {
+                if username == old_name {
+                    *username = new_name.clone();
+                }
+            }
+        }
+    }
+
+    pub fn remove(&self, username: &Username) {
+        self.inner.remove(username);
+        // the messages of a user that left can't be read anymore
+        self.unread.retain(|_, (_, to)| to != username);
+    }
+
+    /// Asks the connection of the given user to close
+    ///
+    /// Returns the token that is cancelled once the user is gone, if they are connected.
+    pub fn disconnect(&self, username: &Username) -> Option<CancellationToken> {
+        let session = self.inner.get(username)?;
+        session.signals.disconnect.cancel();
+        Some(session.signals.released.clone())
+    }
+
+    /// Queues the given event for the given user, returning whether it was queued
+    ///
+    /// The event is dropped if the user is not connected or can't keep up.
+    pub fn send(&self, username: &Username, event: &ServerEvent) -> bool {
+        let Some(outgoing) = self.inner.get(username).map(|entry| entry.outgoing.clone()) else {
+            return false;
+        };
+        outgoing.try_send(event.clone()).is_ok()
+    }
+
+    /// Sends a direct message to the given user, returning whether it was queued
+    ///
+    /// The message is remembered until the recipient marks it as read with
+    /// [`Sessions::mark_read`].
+    pub fn send_direct_message(
+        &self,
+        id: MessageId,
+        from: &Username,
+        to: &Username,
+        event: &ServerEvent,
+    ) -> bool {
+        let sent = self.send(to, event);
+        if sent {
+            self.unread.insert(id, (from.clone(), to.clone()));
+        }
+        sent
+    }
+
+    /// Marks a direct message to the given user as read and sends a receipt to its sender
+    ///
+    /// No receipt is sent if the sender is no longer connected.
+    pub fn mark_read(&self, id: MessageId, reader: &Username) -> Result<(), &'static str> {
+        let (_, (from, _)) = self
+            .unread
+            .remove_if(&id, |_, (_, to)| to == reader)
+            .ok_or("No unread direct message with that id")?;
+        let receipt = ServerEvent::ReadReceipt {
+            id,
+            reader: reader.clone(),
+        };
+        if !self.send(&from, &receipt) {
+            tracing::debug!("Dropping the read receipt of message {id}, {from} is offline");
+        }
+        Ok(())
+    }
+
+    /// Returns the connected users with their addresses and how long they have been connected
+    pub fn list(&self) -> Vec<(Username, SocketAddr, Duration)> {
+        let mut list: Vec<_> = self
+            .inner
+            .iter()
+            .map(|entry| {
+                let session = entry.value();
+                (
+                    entry.key().clone(),
+                    session.addr,
+                    session.connected_at.elapsed(),
+                )
+            })
+            .collect();
+        list.sort_by(|a, b| a.0.cmp(&b.0));
+        list
+    }
+}
@@ -0,0 +1,29 @@
+use common::{RoomName, Username};
+use serde::{Deserialize, Serialize};
+
+use crate::history::HistoryEntry;
+
+/// The state of all rooms at a point in time
+#[derive(Clone, Debug, Default, Serialize, Deserialize)]
+pub struct StateSnapshot {
+    pub rooms: Vec<RoomSnapshot>,
+}
+
+/// The state of a room at a point in time
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct RoomSnapshot {
+    pub name: RoomName,
+    pub owner: Option<Username>,
+    /// The users in the room, only informational since they can't be restored
+    pub users: Vec<Username>,
+    pub history: Vec<HistoryEntry>,
+    /// The password that is required for joining the room
+    #[serde(default)]
+    pub password: Option<String>,
+    #[serde(default)]
+    pub moderators: Vec<Username>,
+    #[serde(default)]
+    pub banned: Vec<Username>,
+    #[serde(default)]
+    pub muted: Vec<Username>,
+}
@@ -0,0 +1,109 @@
+use std::{
+    net::IpAddr,
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc,
+    },
+};
+
+use common::{ServerEvent, Username};
+use dashmap::DashSet;
+use serde::Serialize;
+
+use crate::{
+    config::Config,
+    hostnames::Hostnames,
+    presence::Presence,
+    rooms::Rooms,
+    sessions::Sessions,
+    translator::{EchoTranslator, Translator},
+    users::Users,
+};
+
+/// The numbers that the control socket and the admin console report
+#[derive(Clone, Copy, Debug, Serialize)]
+pub struct Stats {
+    pub users: usize,
+    pub rooms: usize,
+    pub connections: usize,
+    pub draining: bool,
+}
+
+/// SharedState that is shared between the server and all connections
+#[derive(Clone, Debug)]
+pub struct SharedState {
+    /// The users that are connected to the server
+    pub users: Users,
+    /// The rooms that are available on the server
+    pub rooms: Rooms,
+    /// The configuration of the server
+    pub config: Arc<Config>,
+    /// The statuses of the connected users
+    pub presence: Presence,
+    /// The outgoing queues of the connected users
+    pub sessions: Sessions,
+    /// The resolved hostnames of the connected users
+    pub hostnames: Hostnames,
+    /// Whether the server is draining (i.e. not accepting new connections)
+    pub draining: Arc<AtomicBool>,
+    /// The addresses that are not allowed to connect
+    pub banned: Arc<DashSet<IpAddr>>,
+    /// The service that translates text for `/translate`
+    pub translator: Arc<dyn Translator>,
+}
+
+impl SharedState {
+    /// Creates the state of a server that has the given rooms and nobody connected yet
+    pub fn new(config: Config, rooms: Rooms) -> Self {
+        Self {
+            users: Users::default(),
+            rooms,
+            config: Arc::new(config),
+            presence: Presence::default(),
+            sessions: Sessions::default(),
+            hostnames: Hostnames::default(),
+            draining: Arc::default(),
+            banned: Arc::default(),
+            translator: Arc::new(EchoTranslator),
+        }
+    }
+
+    pub fn is_draining(&self) -> bool {
+        self.draining.load(Ordering::Relaxed)
+    }
+
+    pub fn start_draining(&self) {
+        self.draining.store(true, Ordering::Relaxed);
+    }
+
+    pub fn is_banned(&self, ip: &IpAddr) -> bool {
+        self.banned.contains(ip)
+    }
+
+    /// Disconnects the given user, also banning their address if requested
+    ///
+    /// Returns whether the user was connected.
+    pub fn kick(&self, username: &Username, banned: bool) -> bool {
+        if !self.users.contains(username) {
+            return false;
+        }
+        self.rooms
+            .send_server_event(ServerEvent::kicked(username, banned));
+        true
+    }
+
+    /// Sends an announcement to every connected user
+    pub fn announce(&self, message: &str) {
+        self.rooms
+            .send_server_event(ServerEvent::announcement(message));
+    }
+
+    pub fn stats(&self) -> Stats {
+        Stats {
+            users: self.users.len(),
+            rooms: self.rooms.list().len(),
+            connections: self.sessions.list().len(),
+            draining: self.is_draining(),
+        }
+    }
+}
@@ -0,0 +1,130 @@
+use std::{
+    fs, io,
+    path::{Path, PathBuf},
+    sync::{Arc, Mutex},
+};
+
+use anyhow::Context;
+
+use crate::{rooms::Rooms, snapshot::StateSnapshot};
+
+/// Keeps the state of the rooms in a data directory so that it survives restarts
+#[derive(Clone, Debug)]
+pub struct Storage {
+    path: PathBuf,
+    /// The most recently saved state, also held while saving so that saves don't interleave
+    last_saved: Arc<Mutex<Option<String>>>,
+}
+
+impl Storage {
+    const FILE_NAME: &'static str = "rooms.json";
+
+    /// Opens the storage in the given directory, creating the directory if needed
+    pub fn open(data_dir: &Path) -> io::Result<Self> {
+        fs::create_dir_all(data_dir)?;
+        Ok(Self {
+            path: data_dir.join(Self::FILE_NAME),
+            last_saved: Arc::default(),
+        })
+    }
+
+    /// Loads the saved state, if there is one
+    pub fn load(&self) -> anyhow::Result<Option<StateSnapshot>> {
+        let contents = match fs::read_to_string(&self.path) {
+            Ok(contents) => contents,
+            Err(err) if err.kind() == io::ErrorKind::NotFound => return Ok(None),
+            Err(err) => {
+                return Err(err).with_context(|| format!("Failed to read {}", self.path.display()))
+            }
+        };
+        let snapshot = serde_json::from_str(&contents)
+            .with_context(|| format!("Failed to parse {}", self.path.display()))?;
+        *self.last_saved.lock().unwrap() = Some(contents);
+        Ok(Some(snapshot))
+    }
+
+    /// Saves the current state of the given rooms if it changed since the last save
+    ///
+    /// The state is written to a temporary file first, so a crash while saving never leaves a
+    /// partially written state behind.
+    pub fn save(&self, rooms: &Rooms) -> anyhow::Result<()> {
+        let contents = serde_json::to_string(&rooms.snapshot())?;
+        let mut last_saved = self.last_saved.lock().unwrap();
+        if last_saved.as_ref() == Some(&contents) {
+            return Ok(());
+        }
+        let temp_path = self.path.with_extension("json.tmp");
+        fs::write(&temp_path, &contents)
+            .with_context(|| format!("Failed to write {}", temp_path.display()))?;
+        fs::rename(&temp_path, &self.path)
+            .with_context(|| format!("Failed to replace {}", self.path.display()))?;
+        tracing::debug!("Saved the rooms to {}", self.path.display());
+        *last_saved = Some(contents);
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use common::{RoomName, Username};
+    use tokio::sync::broadcast;
+
+    use super::*;
+
+    /// Opens a storage in an empty directory that is unique to the given test
+    fn storage(test: &str) -> (Storage, PathBuf) {
+        let data_dir = std::env::temp_dir().join(format!("server-{}-{test}", std::process::id()));
+        let _ = fs::remove_dir_all(&data_dir);
+        (Storage::open(&data_dir).unwrap(), data_dir)
+    }
+
+    fn rooms() -> Rooms {
+        let (events, _) = broadcast::channel(16);
+        Rooms::new(events, 10)
+    }
+
+    #[test]
+    fn saved_rooms_are_loaded() {
+        let (storage, data_dir) = storage("saved_rooms_are_loaded");
+        assert!(storage.load().unwrap().is_none());
+
+        let rooms = rooms();
+        let alice = Username::from("alice");
+        let (room, _events) = rooms.join(&alice, &RoomName::from("den"), None).unwrap();
+        room.send_message(&alice, "hello").unwrap();
+        storage.save(&rooms).unwrap();
+        assert!(!data_dir.join("rooms.json.tmp").exists());
+
+        let reopened = Storage::open(&data_dir).unwrap();
+        let snapshot = reopened.load().unwrap().unwrap();
+        let names: Vec<_> = snapshot
+            .rooms
+            .iter()
+            .map(|room| room.name.as_str())
+            .collect();
+        assert_eq!(names, ["den", "lobby"]);
+        assert_eq!(snapshot.rooms[0].history[0].text, "hello");
+        fs::remove_dir_all(data_dir).unwrap();
+    }
+
+    #[test]
+    fn unchanged_rooms_are_not_saved_again() {
+        let (storage, data_dir) = storage("unchanged_rooms_are_not_saved_again");
+        let rooms = rooms();
+        storage.save(&rooms).unwrap();
+        let path = data_dir.join(Storage::FILE_NAME);
+        fs::remove_file(&path).unwrap();
+        storage.save(&rooms).unwrap();
+        assert!(!path.exists());
+        fs::remove_dir_all(data_dir).unwrap();
+    }
+
+    #[test]
+    fn invalid_files_are_reported() {
+        let (storage, data_dir) = storage("invalid_files_are_reported");
+        fs::write(data_dir.join(Storage::FILE_NAME), "{").unwrap();
+        let err = storage.load().unwrap_err();
+        assert!(err.to_string().starts_with("Failed to parse"), "{err}");
+        fs::remove_dir_all(data_dir).unwrap();
+    }
+}
@@ -0,0 +1,150 @@
+//! Helpers for testing the server over real connections
+
+use std::{
+    net::{Ipv4Addr, SocketAddr},
+    sync::Arc,
+};
+
+use clap::Parser;
+use common::{RoomEvent, ServerEvent, Username};
+use futures::{SinkExt, StreamExt};
+use tokio::{
+    net::TcpStream,
+    sync::oneshot,
+    task::JoinHandle,
+    time::{self, Duration},
+};
+use tokio_util::codec::{Framed, LinesCodec};
+
+use crate::{config::Config, server::Server, translator::Translator, Args};
+
+/// How long to wait for an event before failing the test
+const TIMEOUT: Duration = Duration::from_secs(5);
+
+/// Returns the configuration that the given command line flags result in
+pub fn config(flags: &[&str]) -> Config {
+    Args::parse_from(["server"].iter().chain(flags)).config()
+}
+
+/// A server that runs in the background until it is shut down
+pub struct TestServer {
+    pub addr: SocketAddr,
+    shutdown: oneshot::Sender<()>,
+    task: JoinHandle<()>,
+}
+
+impl TestServer {
+    /// Starts a server on a free port with the given command line flags
+    pub async fn start(flags: &[&str]) -> Self {
+        Self::run(Self::listen(flags).await)
+    }
+
+    /// Starts a server that translates `/translate` requests with the given translator
+    pub async fn start_with_translator(flags: &[&str], translator: Arc<dyn Translator>) -> Self {
+        Self::run(Self::listen(flags).await.with_translator(translator))
+    }
+
+    async fn listen(flags: &[&str]) -> Server {
+        Server::listen((Ipv4Addr::LOCALHOST, 0).into(), config(flags))
+            .await
+            .unwrap()
+    }
+
+    fn run(server: Server) -> Self {
+        let addr = server.local_addr();
+        let (shutdown, signal) = oneshot::channel();
+        let task = tokio::spawn(server.run_until(async {
+            let _ = signal.await;
+            "the end of the test"
+        }));
+        Self {
+            addr,
+            shutdown,
+            task,
+        }
+    }
+
+    /// Opens a connection without waiting for any events (e.g. to be refused)
+    pub async fn connect_raw(&self) -> TestClient {
+        let stream = TcpStream::connect(self.addr).await.unwrap();
+        TestClient {
+            lines: Framed::new(stream, LinesCodec::new()),
+        }
+    }
+
+    /// Connects a user and skips the events that are sent upon connecting
+    pub async fn connect(&self) -> TestClient {
+        let mut client = self.connect_raw().await;
+        client
+            .recv_until(|event| matches!(event, ServerEvent::Users(_)))
+            .await;
+        client
+    }
+
+    /// Connects a user and changes their name to the given one
+    pub async fn connect_as(&self, name: &str) -> TestClient {
+        let mut client = self.connect().await;
+        client.send(&format!("/name {name}")).await;
+        let name = Username::from(name);
+        client
+            .recv_until(|event| {
+                matches!(
+                    event,
+                    ServerEvent::RoomEvent { event: RoomEvent::NameChange(new_name), .. }
+                        if new_name == &name
+                )
+            })
+            .await;
+        client
+    }
+
+    /// Shuts the server down and waits until it is done
+    pub async fn shutdown(self) {
+        self.shutdown.send(()).unwrap();
+        self.task.await.unwrap();
+    }
+}
+
+/// A user that is connected to a [`TestServer`]
+pub struct TestClient {
+    lines: Framed<TcpStream, LinesCodec>,
+}
+
+impl TestClient {
+    pub async fn send(&mut self, line: &str) {
+        self.lines.send(line).await.unwrap();
+    }
+
+    /// Receives the next event, or `None` if the connection was closed
+    pub async fn try_recv(&mut self) -> Option<ServerEvent> {
+        let line = time::timeout(TIMEOUT, self.lines.next())
+            .await
+            .expect("timed out waiting for an event")?
+            .unwrap();
+        Some(ServerEvent::from_json_str(&line).unwrap())
+    }
+
+    /// Receives the next event, failing if the connection was closed
+    pub async fn recv(&mut self) -> ServerEvent {
+        self.try_recv().await.expect("the connection was closed")
+    }
+
+    /// Receives events until one matches the given predicate, returning it
+    pub async fn recv_until(&mut self, predicate: impl Fn(&ServerEvent) -> bool) -> ServerEvent {
+        loop {
+            let event = self.recv().await;
+            if predicate(&event) {
+                return event;
+            }
+        }
+    }
+
+    /// Receives events until the connection is closed, returning them
+    pub async fn recv_until_closed(&mut self) -> Vec<ServerEvent> {
+        let mut events = Vec::new();
+        while let Some(event) = self.try_recv().await {
+            events.push(event);
+        }
+        events
+    }
+}
@@ -0,0 +1,210 @@
+use std::{
+    collections::{HashMap, VecDeque},
+    net::IpAddr,
+};
+
+use tokio::time::{Duration, Instant};
+
+/// Limits how often connections are accepted from the same address
+#[derive(Debug)]
+pub struct AcceptThrottle {
+    /// The maximum number of connections from an address within the window
+    limit: usize,
+    window: Duration,
+    /// The times of the recently accepted connections per address
+    accepts: HashMap<IpAddr, VecDeque<Instant>>,
+}
+
+impl AcceptThrottle {
+    /// The number of tracked addresses after which stale ones are forgotten
+    const PRUNE_THRESHOLD: usize = 1024;
+
+    pub fn new(limit: usize, window: Duration) -> Self {
+        Self {
+            limit,
+            window,
+            accepts: HashMap::new(),
+        }
+    }
+
+    /// Records a connection from the given address, returning whether it should be accepted
+    pub fn allow(&mut self, ip: IpAddr) -> bool {
+        let now = Instant::now();
+        if self.accepts.len() > Self::PRUNE_THRESHOLD {
+            let window = self.window;
+            self.accepts
+                .retain(|_, times| times.back().is_some_and(|time| now - *time < window));
+        }
+        let times = self.accepts.entry(ip).or_default();
+        while times.front().is_some_and(|time| now - *time >= self.window) {
+            times.pop_front();
+        }
+        if times.len() >= self.limit {
+            return false;
+        }
+        times.push_back(now);
+        true
+    }
+}
+
+/// A bucket of tokens that refills at a constant rate, up to one second worth of tokens
+///
+/// Taking more tokens than available puts the bucket into debt, so that a single large message
+/// is let through but delays the following ones.
+#[derive(Debug)]
+struct TokenBucket {
+    rate: f64,
+    tokens: f64,
+    refilled_at: Instant,
+}
+
+impl TokenBucket {
+    fn new(rate: f64) -> Self {
+        Self {
+            rate,
+            tokens: rate,
+            refilled_at: Instant::now(),
+        }
+    }
+
+    /// Takes the given number of tokens, returning whether there were any available
+    fn take(&mut self, amount: f64, now: Instant) -> bool {
+        let elapsed = now.duration_since(self.refilled_at).as_secs_f64();
+        self.tokens = (self.tokens + elapsed * self.rate).min(self.rate);
+        self.refilled_at = now;
+        if self.tokens <= 0.0 {
+            return false;
+        }
+        self.tokens -= amount;
+        true
+    }
+}
+
+/// The outcome of checking a line against a [`MessageThrottle`]
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Verdict {
+    Allowed,
+    /// The limit was exceeded just now and the user is muted for the given duration
+    Muted(Duration),
+    /// The user is still muted from exceeding the limit earlier
+    StillMuted,
+}
+
+/// Limits how many messages and bytes a user can send per second
+///
+/// Users that exceed a limit are muted for a while, during which all their lines are dropped.
+#[derive(Debug)]
+pub struct MessageThrottle {
+    messages: Option<TokenBucket>,
+    bytes: Option<TokenBucket>,
+    mute: Duration,
+    muted_until: Option<Instant>,
+}
+
+impl MessageThrottle {
+    pub fn new(
+        messages_per_second: Option<u32>,
+        bytes_per_second: Option<usize>,
+        mute: Duration,
+    ) -> Self {
+        Self {
+            messages: messages_per_second.map(|rate| TokenBucket::new(rate as f64)),
+            bytes: bytes_per_second.map(|rate| TokenBucket::new(rate as f64)),
+            mute,
+            muted_until: None,
+        }
+    }
+
+    /// Records a line of the given length, returning whether it should be handled
+    pub fn check(&mut self, len: usize) -> Verdict {
+        let now = Instant::now();
+        if let Some(until) = self.muted_until {
+            if now < until {
+                return Verdict::StillMuted;
+            }
+            self.muted_until = None;
+        }
+        let within_limits = [(&mut self.messages, 1.0), (&mut self.bytes, len as f64)]
+            .into_iter()
+            .all(|(bucket, amount)| {
+                bucket
+                    .as_mut()
+                    .is_none_or(|bucket| bucket.take(amount, now))
+            });
+        if within_limits {
+            return Verdict::Allowed;
+        }
+        self.muted_until = Some(now + self.mute);
+        Verdict::Muted(self.mute)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::net::Ipv4Addr;
+
+    use tokio::time;
+
+    use super::*;
+
+    #[tokio::test(start_paused = true)]
+    async fn accepts_are_limited_per_address() {
+        let mut throttle = AcceptThrottle::new(2, Duration::from_secs(60));
+        let [first, second] = [1, 2].map(|n| IpAddr::from(Ipv4Addr::new(10, 0, 0, n)));
+        assert!(throttle.allow(first));
+        assert!(throttle.allow(first));
+        assert!(!throttle.allow(first));
+        assert!(throttle.allow(second));
+        time::advance(Duration::from_secs(60)).await;
+        assert!(throttle.allow(first));
+    }
+
+    #[test]
+    fn buckets_refill_up_to_their_rate() {
+        let start = Instant::now();
+        let mut bucket = TokenBucket::new(2.0);
+        assert!(bucket.take(1.0, start));
+        assert!(bucket.take(1.0, start));
+        assert!(!bucket.take(1.0, start));
+        assert!(bucket.take(1.0, start + Duration::from_millis(500)));
+        // a long pause doesn't save up more than one second worth of tokens
+        let later = start + Duration::from_secs(60);
+        assert!(bucket.take(1.0, later));
+        assert!(bucket.take(1.0, later));
+        assert!(!bucket.take(1.0, later));
+    }
+
+    #[test]
+    fn large_amounts_put_buckets_into_debt() {
+        let start = Instant::now();
+        let mut bucket = TokenBucket::new(10.0);
+        assert!(bucket.take(30.0, start));
+        assert!(!bucket.take(1.0, start + Duration::from_secs(1)));
+        assert!(bucket.take(1.0, start + Duration::from_millis(2100)));
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn exceeding_a_limit_mutes() {
+        let mute = Duration::from_secs(10);
+        let mut throttle = MessageThrottle::new(Some(2), Some(100), mute);
+        assert_eq!(throttle.check(10), Verdict::Allowed);
+        assert_eq!(throttle.check(10), Verdict::Allowed);
+        assert_eq!(throttle.check(10), Verdict::Muted(mute));
+        time::advance(Duration::from_secs(5)).await;
+        assert_eq!(throttle.check(10), Verdict::StillMuted);
+        time::advance(Duration::from_secs(5)).await;
+        assert_eq!(throttle.check(10), Verdict::Allowed);
+
+        time::advance(Duration::from_secs(1)).await;
+        assert_eq!(throttle.check(200), Verdict::Allowed);
+        assert_eq!(throttle.check(1), Verdict::Muted(mute));
+    }
+
+    #[test]
+    fn no_limits_allow_everything() {
+        let mut throttle = MessageThrottle::new(None, None, Duration::from_secs(10));
+        for _ in 0..1000 {
+            assert_eq!(throttle.check(usize::MAX), Verdict::Allowed);
+        }
+    }
+}
@@ -0,0 +1,50 @@
+use std::{path::Path, sync::Arc};
+
+use anyhow::Context;
+use tokio::{
+    io::{AsyncRead, AsyncWrite},
+    net::TcpStream,
+    time::Duration,
+};
+use tokio_rustls::{
+    rustls::{
+        pki_types::{pem::PemObject, CertificateDer, PrivateKeyDer},
+        ServerConfig,
+    },
+    TlsAcceptor,
+};
+
+/// How long a client has for completing the TLS handshake
+const HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// A connection to a user, either plain or encrypted
+pub trait UserStream: AsyncRead + AsyncWrite + Send + Unpin {}
+
+impl<T: AsyncRead + AsyncWrite + Send + Unpin> UserStream for T {}
+
+/// Creates a TLS acceptor from the given PEM encoded certificate chain and private key
+pub fn acceptor(cert: &Path, key: &Path) -> anyhow::Result<TlsAcceptor> {
+    let certs = CertificateDer::pem_file_iter(cert)
+        .and_then(|certs| certs.collect::<Result<Vec<_>, _>>())
+        .with_context(|| format!("Failed to read the certificates from {}", cert.display()))?;
+    let key = PrivateKeyDer::from_pem_file(key)
+        .with_context(|| format!("Failed to read the private key from {}", key.display()))?;
+    let config = ServerConfig::builder()
+        .with_no_client_auth()
+        .with_single_cert(certs, key)?;
+    Ok(TlsAcceptor::from(Arc::new(config)))
+}
+
+/// Performs the TLS handshake on the given stream if TLS is enabled
+pub async fn accept(
+    stream: TcpStream,
+    acceptor: Option<&TlsAcceptor>,
+) -> anyhow::Result<Box<dyn UserStream>> {
+    let Some(acceptor) = acceptor else {
+        return Ok(Box::new(stream));
+    };
+    let stream = tokio::time::timeout(HANDSHAKE_TIMEOUT, acceptor.accept(stream))
+        .await
+        .context("TLS handshake timed out")??;
+    Ok(Box::new(stream))
+}
@@ -0,0 +1,339 @@
+use std::collections::{BTreeSet, HashMap};
+
+use common::Username;
+use tokio::time::{Duration, Instant};
+
+use crate::room::Room;
+
+/// A file that is being sent to a room in chunks
+#[derive(Debug)]
+struct Transfer {
+    room: Room,
+    filename: String,
+    /// The announced size of the file in bytes (decoded)
+    size: usize,
+    /// The number of bytes received so far (decoded)
+    received: usize,
+    /// The number of chunks that the file is split into, known from the first chunk
+    total: Option<u32>,
+    /// The indices of the chunks received so far
+    chunks: BTreeSet<u32>,
+    /// When the transfer started or last received a chunk
+    last_activity: Instant,
+}
+
+/// The chunked file transfers of a user, by the id that the user picked for them
+#[derive(Debug, Default)]
+pub struct Transfers {
+    inner: HashMap<u64, Transfer>,
+}
+
+impl Transfers {
+    /// The maximum number of files that a user can send at the same time
+    const MAX_CONCURRENT: usize = 4;
+
+    /// Starts a transfer of a file with the given size to the given room
+    pub fn start(
+        &mut self,
+        id: u64,
+        room: Room,
+        filename: &str,
+        size: usize,
+    ) -> Result<(), &'static str> {
+        if self.inner.contains_key(&id) {
+            return Err("A file transfer with that id is already in progress");
+        }
+        if self.inner.len() >= Self::MAX_CONCURRENT {
+            return Err("Too many file transfers in progress");
+        }
+        let transfer = Transfer {
+            room,
+            filename: filename.to_string(),
+            size,
+            received: 0,
+            total: None,
+            chunks: BTreeSet::new(),
+            last_activity: Instant::now(),
+        };
+        self.inner.insert(id, transfer);
+        Ok(())
+    }
+
+    /// Records the chunk with the given index and size, returning the room to send it to
+    ///
+    /// Chunks can arrive in any order. A chunk that was already received is ignored and no room is
+    /// returned for it, so a retransmitted chunk is neither relayed nor counted twice.
+    ///
+    /// The chunk is refused if it exceeds the announced size, its index doesn't fit the number of
+    /// chunks of the first chunk, or the user left the room or was muted in it.
+    pub fn chunk(
+        &mut self,
+        id: u64,
+        index: u32,
+        total: u32,
+        len: usize,
+        username: &Username,
+    ) -> Result<Option<Room>, &'static str> {
+        let transfer = self
+            .inner
+            .get_mut(&id)
+            .ok_or("No file transfer with that id")?;
+        if !transfer.room.contains(username) {
+            return Err("You are no longer in the room of the file transfer");
+        }
+        transfer.room.check_can_post(username)?;
+        if index >= total || *transfer.total.get_or_insert(total) != total {
+            return Err("The chunk does not fit the number of chunks");
+        }
+        transfer.last_activity = Instant::now();
+        if transfer.chunks.contains(&index) {
+            tracing::debug!(
+                "Ignoring duplicated chunk {index} of file {}",
+                transfer.filename
+            );
+            return Ok(None);
+        }
+        if transfer.received + len > transfer.size {
+            return Err("The file is larger than announced");
+        }
+        transfer.received += len;
+        transfer.chunks.insert(index);
+        Ok(Some(transfer.room.clone()))
+    }
+
+    /// Finishes a transfer, returning the room that the file was sent to
+    ///
+    /// Every chunk must have arrived and add up to the announced size.
+    pub fn finish(&mut self, id: u64, username: &Username) -> Result<Room, &'static str> {
+        let transfer = self.inner.get(&id).ok_or("No file transfer with that id")?;
+        transfer.room.check_can_post(username)?;
+        if transfer.received < transfer.size {
+            return Err("The file is smaller than announced");
+        }
+        if transfer
+            .total
+            .is_some_and(|total| transfer.chunks.len() != total as usize)
+        {
+            return Err("The file is missing chunks");
+        }
+        let transfer = self
+            .inner
+            .remove(&id)
+            .ok_or("No file transfer with that id")?;
+        tracing::debug!("Finished sending file {}", transfer.filename);
+        Ok(transfer.room)
+    }
+
+    /// Cancels a transfer, returning the room that the file was sent to
+    pub fn abort(&mut self, id: u64) -> Option<Room> {
+        let transfer = self.inner.remove(&id)?;
+        tracing::debug!(
+            "Aborted sending file {} after {} of {} bytes",
+            transfer.filename,
+            transfer.received,
+            transfer.size
+        );
+        Some(transfer.room)
+    }
+
+    /// Returns whether the transfer with the given id is in progress
+    pub fn contains(&self, id: u64) -> bool {
+        self.inner.contains_key(&id)
+    }
+
+    /// Returns the ids of the transfers in progress
+    pub fn ids(&self) -> Vec<u64> {
+        self.inner.keys().copied().collect()
+    }
+
+    /// Returns when the next transfer times out if it receives no chunks until then
+    pub fn deadline(&self, timeout: Duration) -> Option<Instant> {
+        self.inner
+            .values()
+            .map(|transfer| transfer.last_activity + timeout)
+            .min()
+    }
+
+    /// Returns the ids of the transfers that received no chunks within the given timeout
+    pub fn expired(&self, timeout: Duration) -> Vec<u64> {
+        let now = Instant::now();
+        self.inner
+            .iter()
+            .filter(|(_, transfer)| now >= transfer.last_activity + timeout)
+            .map(|(id, _)| *id)
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+
+    use common::RoomName;
+    use tokio::time;
+
+    use super::*;
+
+    fn room_with(username: &Username) -> Room {
+        let room = Room::new(
+            RoomName::from("den"),
+            Arc::default(),
+            Arc::default(),
+            10,
+            None,
+        );
+        room.join(username);
+        room
+    }
+
+    #[test]
+    fn chunks_cannot_exceed_the_announced_size() {
+        let alice = Username::from("alice");
+        let mut transfers = Transfers::default();
+        transfers
+            .start(1, room_with(&alice), "notes.txt", 10)
+            .unwrap();
+        transfers.chunk(1, 0, 3, 6, &alice).unwrap();
+        assert_eq!(
+            transfers.chunk(1, 1, 3, 5, &alice).unwrap_err(),
+            "The file is larger than announced"
+        );
+        transfers.chunk(1, 1, 3, 2, &alice).unwrap();
+        transfers.chunk(1, 2, 3, 2, &alice).unwrap();
+        transfers.finish(1, &alice).unwrap();
+        assert!(transfers.ids().is_empty());
+    }
+
+    #[test]
+    fn unfinished_files_cannot_end() {
+        let alice = Username::from("alice");
+        let mut transfers = Transfers::default();
+        transfers
+            .start(1, room_with(&alice), "notes.txt", 10)
+            .unwrap();
+        transfers.chunk(1, 0, 3, 4, &alice).unwrap();
+        assert_eq!(
+            transfers.finish(1, &alice).unwrap_err(),
+            "The file is smaller than announced"
+        );
+        // the transfer is kept until it is aborted
+        assert_eq!(transfers.ids(), [1]);
+        assert!(transfers.abort(1).is_some());
+        assert!(transfers.abort(1).is_none());
+        assert_eq!(
+            transfers.chunk(1, 1, 3, 4, &alice).unwrap_err(),
+            "No file transfer with that id"
+        );
+    }
+
+    #[test]
+    fn transfers_are_limited() {
+        let alice = Username::from("alice");
+        let room = room_with(&alice);
+        let mut transfers = Transfers::default();
+        transfers.start(1, room.clone(), "notes.txt", 0).unwrap();
+        assert_eq!(
+            transfers
+                .start(1, room.clone(), "notes.txt", 0)
+                .unwrap_err(),
+            "A file transfer with that id is already in progress"
+        );
+        for id in 2..=Transfers::MAX_CONCURRENT as u64 {
+            transfers.start(id, room.clone(), "notes.txt", 0).unwrap();
+        }
+        assert_eq!(
+            transfers.start(0, room, "notes.txt", 0).unwrap_err(),
+            "Too many file transfers in progress"
+        );
+    }
+
+    #[test]
+    fn chunks_need_a_consistent_count() {
+        let alice = Username::from("alice");
+        let mut transfers = Transfers::default();
+        transfers
+            .start(1, room_with(&alice), "notes.txt", 10)
+            .unwrap();
+        assert_eq!(
+            transfers.chunk(1, 2, 2, 4, &alice).unwrap_err(),
+            "The chunk does not fit the number of chunks"
+        );
+        transfers.chunk(1, 0, 2, 4, &alice).unwrap();
+        assert_eq!(
+            transfers.chunk(1, 1, 3, 4, &alice).unwrap_err(),
+            "The chunk does not fit the number of chunks"
+        );
+    }
+
+    #[test]
+    fn duplicated_chunks_are_ignored() {
+        let alice = Username::from("alice");
+        let mut transfers = Transfers::default();
+        transfers
+            .start(1, room_with(&alice), "notes.txt", 8)
+            .unwrap();
+        assert!(transfers.chunk(1, 1, 2, 4, &alice).unwrap().is_some());
+        assert!(transfers.chunk(1, 1, 2, 4, &alice).unwrap().is_none());
+        assert_eq!(
+            transfers.finish(1, &alice).unwrap_err(),
+            "The file is smaller than announced"
+        );
+        // the first chunk arrives last
+        assert!(transfers.chunk(1, 0, 2, 4, &alice).unwrap().is_some());
+        transfers.finish(1, &alice).unwrap();
+    }
+
+    #[test]
+    fn every_chunk_is_needed() {
+        let alice = Username::from("alice");
+        let mut transfers = Transfers::default();
+        transfers
+            .start(1, room_with(&alice), "notes.txt", 8)
+            .unwrap();
+        transfers.chunk(1, 0, 3, 4, &alice).unwrap();
+        transfers.chunk(1, 2, 3, 4, &alice).unwrap();
+        assert_eq!(
+            transfers.finish(1, &alice).unwrap_err(),
+            "The file is missing chunks"
+        );
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn stalled_transfers_expire() {
+        let alice = Username::from("alice");
+        let room = room_with(&alice);
+        let timeout = Duration::from_secs(30);
+        let mut transfers = Transfers::default();
+        assert_eq!(transfers.deadline(timeout), None);
+        transfers.start(1, room.clone(), "notes.txt", 8).unwrap();
+        time::advance(Duration::from_secs(20)).await;
+        transfers.start(2, room, "notes.txt", 8).unwrap();
+        transfers.chunk(2, 0, 2, 4, &alice).unwrap();
+        assert!(transfers.expired(timeout).is_empty());
+        assert_eq!(
+            transfers.deadline(timeout),
+            Some(Instant::now() + Duration::from_secs(10))
+        );
+        time::advance(Duration::from_secs(10)).await;
+        assert_eq!(transfers.expired(timeout), [1]);
+        transfers.abort(1);
+        // the chunk keeps the other transfer alive
+        time::advance(Duration::from_secs(19)).await;
+        assert!(transfers.expired(timeout).is_empty());
+        time::advance(Duration::from_secs(1)).await;
+        assert_eq!(transfers.expired(timeout), [2]);
+    }
+
+    #[test]
+    fn chunks_need_the_sender_in_the_room() {
+        let alice = Username::from("alice");
+        let room = room_with(&alice);
+        let mut transfers = Transfers::default();
+        transfers.start(1, room.clone(), "notes.txt", 10).unwrap();
+        room.leave(&alice, None);
+        assert_eq!(
+            transfers.chunk(1, 1, 3, 4, &alice).unwrap_err(),
+            "You are no longer in the room of the file transfer"
+        );
+    }
+}
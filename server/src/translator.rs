@@ -0,0 +1,21 @@
+use std::fmt::Debug;
+
+use async_trait::async_trait;
+
+/// A service that translates text for `/translate`
+#[async_trait]
+pub trait Translator: Debug + Send + Sync {
+    /// Translates the given text to the given language
+    async fn translate(&self, language: &str, text: &str) -> anyhow::Result<String>;
+}
+
+/// Translator that returns the text as is, used when no translation service is configured
+#[derive(Clone, Copy, Debug, Default)]
+pub struct EchoTranslator;
+
+#[async_trait]
+impl Translator for EchoTranslator {
+    async fn translate(&self, _language: &str, text: &str) -> anyhow::Result<String> {
+        Ok(text.to_string())
+    }
+}
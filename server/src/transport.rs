@@ -0,0 +1,226 @@
+use std::io;
+
+use common::{Codec, ServerEvent};
+use futures::{future, Sink, SinkExt, Stream, StreamExt};
+use tokio::net::TcpStream;
+use tokio_rustls::TlsAcceptor;
+use tokio_tungstenite::tungstenite::{protocol::WebSocketConfig, Error as WsError, Message};
+use tokio_util::{
+    bytes::BytesMut,
+    codec::{Decoder, Encoder, Framed, LengthDelimitedCodec, LinesCodec, LinesCodecError},
+};
+
+use crate::tls;
+
+/// The protocol that is spoken on a listener
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Protocol {
+    /// Lines of JSON over raw TCP
+    Lines,
+    /// JSON text messages over WebSocket
+    WebSocket,
+}
+
+/// An encoded event that is written to the user
+#[derive(Clone, Debug)]
+pub enum Frame {
+    Text(String),
+    Binary(Vec<u8>),
+}
+
+impl Frame {
+    /// Encodes the given event with the given codec
+    pub fn encode(event: &ServerEvent, codec: Codec) -> Self {
+        if codec.is_text() {
+            Self::Text(event.as_json_str())
+        } else {
+            Self::Binary(codec.encode(event))
+        }
+    }
+}
+
+/// A connection to a user that reads one command (a line) and writes one event at a time
+pub trait Transport:
+    Stream<Item = Result<String, LinesCodecError>>
+    + Sink<Frame, Error = LinesCodecError>
+    + Send
+    + Unpin
+    + 'static
+{
+}
+
+impl<T> Transport for T where
+    T: Stream<Item = Result<String, LinesCodecError>>
+        + Sink<Frame, Error = LinesCodecError>
+        + Send
+        + Unpin
+        + 'static
+{
+}
+
+/// How many times longer than the line length a WebSocket message can be before the connection is
+/// closed
+///
+/// Unlike an overlong line, a message is read in full before it can be discarded, so only the
+/// messages up to this limit are discarded while keeping the connection open.
+const WEBSOCKET_MESSAGE_FACTOR: usize = 4;
+
+/// Sets up the given protocol (and TLS, if enabled) on a newly accepted stream
+pub async fn open(
+    stream: TcpStream,
+    tls: Option<&TlsAcceptor>,
+    protocol: Protocol,
+    max_line_length: usize,
+) -> anyhow::Result<Box<dyn Transport>> {
+    let stream = tls::accept(stream, tls).await?;
+    match protocol {
+        Protocol::Lines => {
+            let codec = FrameCodec::new(max_line_length);
+            Ok(Box::new(Framed::new(stream, codec)))
+        }
+        Protocol::WebSocket => {
+            let max_message_size = max_line_length.saturating_mul(WEBSOCKET_MESSAGE_FACTOR);
+            let config = WebSocketConfig {
+                max_message_size: Some(max_message_size),
+                max_frame_size: Some(max_message_size),
+                ..Default::default()
+            };
+            let websocket =
+                tokio_tungstenite::accept_async_with_config(stream, Some(config)).await?;
+            let transport = websocket
+                .with(|frame| {
+                    future::ok::<_, WsError>(match frame {
+                        Frame::Text(text) => Message::Text(text),
+                        Frame::Binary(bytes) => Message::Binary(bytes),
+                    })
+                })
+                .sink_map_err(io_error)
+                .filter_map(move |message| {
+                    future::ready(match message {
+                        // discarded like the overlong lines over raw TCP
+                        Ok(Message::Text(line)) if line.len() > max_line_length => {
+                            Some(Err(LinesCodecError::MaxLineLengthExceeded))
+                        }
+                        Ok(Message::Text(line)) => Some(Ok(line)),
+                        Ok(Message::Binary(_)) => {
+                            Some(Err(io_error("binary messages are not supported")))
+                        }
+                        // pings are answered by tungstenite and the stream ends after closing
+                        Ok(
+                            Message::Ping(_)
+                            | Message::Pong(_)
+                            | Message::Close(_)
+                            | Message::Frame(_),
+                        ) => None,
+                        Err(err) => Some(Err(io_error(err))),
+                    })
+                });
+            Ok(Box::new(transport))
+        }
+    }
+}
+
+fn io_error(err: impl Into<Box<dyn std::error::Error + Send + Sync>>) -> LinesCodecError {
+    LinesCodecError::Io(io::Error::other(err))
+}
+
+/// Reads lines and writes frames over raw TCP
+///
+/// Text frames are written as lines and binary frames are prefixed with their length.
+pub(crate) struct FrameCodec {
+    lines: LinesCodec,
+    length_delimited: LengthDelimitedCodec,
+}
+
+impl FrameCodec {
+    pub(crate) fn new(max_line_length: usize) -> Self {
+        Self {
+            lines: LinesCodec::new_with_max_length(max_line_length),
+            length_delimited: LengthDelimitedCodec::builder()
+                .max_frame_length(u32::MAX as usize)
+                .new_codec(),
+        }
+    }
+}
+
+impl Decoder for FrameCodec {
+    type Item = String;
+    type Error = LinesCodecError;
+
+    fn decode(&mut self, src: &mut BytesMut) -> Result<Option<String>, LinesCodecError> {
+        self.lines.decode(src)
+    }
+
+    fn decode_eof(&mut self, src: &mut BytesMut) -> Result<Option<String>, LinesCodecError> {
+        self.lines.decode_eof(src)
+    }
+}
+
+impl Encoder<Frame> for FrameCodec {
+    type Error = LinesCodecError;
+
+    fn encode(&mut self, frame: Frame, dst: &mut BytesMut) -> Result<(), LinesCodecError> {
+        match frame {
+            Frame::Text(line) => self.lines.encode(line, dst),
+            Frame::Binary(bytes) => Ok(self.length_delimited.encode(bytes.into(), dst)?),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::net::Ipv4Addr;
+
+    use tokio::net::TcpListener;
+
+    use super::*;
+
+    /// Accepts a connection with the given protocol and a line length of 8 bytes
+    async fn accept(listener: TcpListener, protocol: Protocol) -> Box<dyn Transport> {
+        let (stream, _) = listener.accept().await.unwrap();
+        open(stream, None, protocol, 8).await.unwrap()
+    }
+
+    #[tokio::test]
+    async fn overlong_lines_are_skipped() {
+        let listener = TcpListener::bind((Ipv4Addr::LOCALHOST, 0)).await.unwrap();
+        let stream = TcpStream::connect(listener.local_addr().unwrap())
+            .await
+            .unwrap();
+        let mut transport = accept(listener, Protocol::Lines).await;
+        let mut client = Framed::new(stream, LinesCodec::new());
+        client.send("a line that is too long").await.unwrap();
+        assert!(matches!(
+            transport.next().await,
+            Some(Err(LinesCodecError::MaxLineLengthExceeded))
+        ));
+        // the codec yields `None` once to resynchronize
+        assert!(transport.next().await.is_none());
+        client.send("short").await.unwrap();
+        assert_eq!(transport.next().await.unwrap().unwrap(), "short");
+    }
+
+    #[tokio::test]
+    async fn overlong_messages_are_skipped() {
+        let listener = TcpListener::bind((Ipv4Addr::LOCALHOST, 0)).await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        // the handshake needs both sides at the same time
+        let server = tokio::spawn(accept(listener, Protocol::WebSocket));
+        let stream = TcpStream::connect(addr).await.unwrap();
+        let (mut client, _) = tokio_tungstenite::client_async(format!("ws://{addr}"), stream)
+            .await
+            .unwrap();
+        let mut transport = server.await.unwrap();
+        let message = Message::Text("a message that is too long".to_string());
+        client.send(message).await.unwrap();
+        client
+            .send(Message::Text("short".to_string()))
+            .await
+            .unwrap();
+        assert!(matches!(
+            transport.next().await,
+            Some(Err(LinesCodecError::MaxLineLengthExceeded))
+        ));
+        assert_eq!(transport.next().await.unwrap().unwrap(), "short");
+    }
+}
@@ -13,6 +13,10 @@ impl Users {
         self.inner.insert(username.clone())
     }
 
+    pub fn contains(&self, username: &Username) -> bool {
+        self.inner.contains(username)
+    }
+
     pub fn remove(&self, username: &Username) -> bool {
         self.inner.remove(username).is_some()
     }